@@ -1,7 +1,14 @@
 //! Base types for page frame allocators.
-use core::alloc::AllocErr;
+use core::{alloc::AllocErr, fmt, iter::FusedIterator, marker::PhantomData};
 use hal9000::mem::Page;
 
+/// The highest physical address reachable by legacy ISA DMA controllers.
+pub const ISA_DMA_MAX_ADDRESS: usize = 0xff_ffff;
+
+/// The highest physical address reachable by devices which can only
+/// address 32 bits.
+pub const DMA32_MAX_ADDRESS: usize = 0xffff_ffff;
+
 /// An allocator that provides page frames.
 pub unsafe trait Allocator {
     /// Architecture-dependent size of a physical page.
@@ -23,6 +30,530 @@ pub unsafe trait Allocator {
     /// given `frame` was not originally allocated by this `Allocator`.
     unsafe fn dealloc(&mut self, frame: Self::Frame) -> Result<(), AllocErr>;
 
-    // TODO: alloc_range/dealloc_range; requires an architecture-independent
-    //       way of representing frame ranges.
+    /// Returns a range of `count` physically contiguous frames.
+    ///
+    /// The default implementation allocates frames one at a time, and
+    /// succeeds only if each frame immediately follows the previous one.
+    /// This is sufficient for allocators which hand out frames in address
+    /// order, but allocators which can find contiguous frames more reliably
+    /// should override it.
+    ///
+    /// # Returns
+    /// - `Ok(FrameRange)` if `count` contiguous frames were allocated.
+    /// - `Err(AllocErr)` if they could not be, or if `count` is zero. Any
+    ///   frames allocated along the way are returned to the allocator.
+    unsafe fn alloc_range(
+        &mut self,
+        count: usize,
+    ) -> Result<FrameRange<Self::Frame>, AllocErr> {
+        if count == 0 {
+            return Err(AllocErr);
+        }
+        let mut range = FrameRange::new(self.alloc()?, 1);
+        while range.len() < count {
+            match self.alloc() {
+                Ok(ref frame) if range.end == frame.base().into() => {
+                    range.end += Self::Frame::SIZE;
+                },
+                Ok(frame) => {
+                    let _ = self.dealloc(frame);
+                    let _ = self.dealloc_range(range);
+                    return Err(AllocErr);
+                },
+                Err(err) => {
+                    let _ = self.dealloc_range(range);
+                    return Err(err);
+                },
+            }
+        }
+        Ok(range)
+    }
+
+    /// Deallocate a range of frames.
+    ///
+    /// The default implementation deallocates each frame in the range in
+    /// turn.
+    ///
+    /// # Returns
+    /// - `Ok(())` if every frame in the range was deallocated.
+    /// - `Err(AllocErr)` if the allocator rejected any frame in the range. The
+    ///   remaining frames are still deallocated.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because undefined behaviour may result if the
+    /// given `range` was not originally allocated by this `Allocator`.
+    unsafe fn dealloc_range(
+        &mut self,
+        range: FrameRange<Self::Frame>,
+    ) -> Result<(), AllocErr> {
+        let mut result = Ok(());
+        for frame in range {
+            if let Err(err) = self.dealloc(frame) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Returns a range of physically contiguous frames satisfying the given
+    /// `constraints`, such as a buffer for a device which performs DMA.
+    ///
+    /// The range may be freed with `dealloc_range`.
+    ///
+    /// The default implementation allocates a range with `alloc_range`, and
+    /// fails if that range does not happen to satisfy the constraints.
+    /// Allocators which can search for a suitable range should override it.
+    ///
+    /// # Returns
+    /// - `Ok(FrameRange)` if a suitable range was allocated.
+    /// - `Err(AllocErr)` if no suitable range could be found.
+    unsafe fn alloc_constrained(
+        &mut self,
+        constraints: Constraints,
+    ) -> Result<FrameRange<Self::Frame>, AllocErr> {
+        let range = self.alloc_range(constraints.count())?;
+        if constraints.is_satisfied_by(&range) {
+            Ok(range)
+        } else {
+            let _ = self.dealloc_range(range);
+            Err(AllocErr)
+        }
+    }
+}
+
+/// A contiguous range of frames.
+///
+/// A `FrameRange` is represented by the base address of its first frame
+/// and the end address of its last frame, so it may describe ranges of any
+/// type of `Page`. Like `core::ops::Range`, a `FrameRange` is an iterator
+/// over the frames it contains.
+///
+/// # Type Parameters
+/// - `F`: the type of frames in the range.
+pub struct FrameRange<F> {
+    /// The base address of the first frame in the range.
+    start: usize,
+
+    /// The end address of the last frame in the range (exclusive).
+    end: usize,
+
+    _frame: PhantomData<fn() -> F>,
+}
+
+/// An allocator that can also provide pages of a larger size, `P`, from the
+/// same pool of memory as its frames.
+///
+/// This allows page tables to map huge pages (such as 2 MiB or 1 GiB pages)
+/// with memory from the kernel's frame allocator. A huge page is a range of
+/// `P::SIZE / Self::FRAME_SIZE` frames, aligned to `P::SIZE`, so the default
+/// implementations allocate and free huge pages as constrained ranges of
+/// frames. Allocators which keep track of larger blocks of memory may
+/// override them.
+///
+/// # Type Parameters
+/// - `P`: the type of huge pages provided by this allocator. `P::SIZE` must be
+///   a multiple of the allocator's `FRAME_SIZE`.
+pub unsafe trait HugeAllocator<P>: Allocator
+where
+    P: Page,
+{
+    /// Returns a new huge page.
+    ///
+    /// # Returns
+    /// - `Ok(P)` if a huge page was allocated.
+    /// - `Err(AllocErr)` if no huge page could be allocated, or if `P` is not
+    ///   made up of whole frames.
+    unsafe fn alloc_huge(&mut self) -> Result<P, AllocErr> {
+        let count = huge_frames::<P, Self::Frame>().ok_or(AllocErr)?;
+        let constraints = Constraints::new(count, P::SIZE, usize::max_value());
+        let range = self.alloc_constrained(constraints)?;
+        let base: usize = range.start_address().into();
+        Ok(P::containing(base.into()))
+    }
+
+    /// Deallocate a huge page.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because undefined behaviour may result if the
+    /// given `page` was not originally allocated by this allocator's
+    /// `alloc_huge`.
+    unsafe fn dealloc_huge(&mut self, page: P) -> Result<(), AllocErr> {
+        let count = huge_frames::<P, Self::Frame>().ok_or(AllocErr)?;
+        let base: usize = page.base().into();
+        let start = Self::Frame::containing(base.into());
+        self.dealloc_range(FrameRange::new(start, count))
+    }
+}
+
+/// Returns the number of frames of type `F` which make up a page of type
+/// `P`, if `P` is made up of whole frames.
+#[inline]
+fn huge_frames<P: Page, F: Page>() -> Option<usize> {
+    if P::SIZE >= F::SIZE && P::SIZE % F::SIZE == 0 {
+        Some(P::SIZE / F::SIZE)
+    } else {
+        None
+    }
+}
+
+/// Constraints on the placement of a range of physically contiguous frames.
+///
+/// Devices which perform direct memory access (DMA) often require buffers
+/// which are aligned to some power of two, and which lie below some physical
+/// address, such as [`ISA_DMA_MAX_ADDRESS`] or [`DMA32_MAX_ADDRESS`].
+///
+/// [`ISA_DMA_MAX_ADDRESS`]: constant.ISA_DMA_MAX_ADDRESS.html
+/// [`DMA32_MAX_ADDRESS`]: constant.DMA32_MAX_ADDRESS.html
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Constraints {
+    /// The number of frames in the range.
+    count: usize,
+
+    /// The alignment of the start of the range, in bytes.
+    align: usize,
+
+    /// The highest physical address which may be in the range (inclusive).
+    max_address: usize,
+}
+
+// ===== impl Constraints =====
+
+impl Constraints {
+    /// Returns new `Constraints` requiring `count` frames, starting at an
+    /// address aligned to `align` bytes, none of which lie above
+    /// `max_address`.
+    ///
+    /// Frames are always aligned to their own size, so any `align` less than
+    /// the size of a frame has no effect. A `max_address` of
+    /// `usize::max_value()` places no limit on the range.
+    ///
+    /// # Panics
+    /// If `align` is not a power of two.
+    pub fn new(count: usize, align: usize, max_address: usize) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Constraints {
+            count,
+            align,
+            max_address,
+        }
+    }
+
+    /// Returns the number of frames in the range.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the alignment of the start of the range, in bytes.
+    #[inline]
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    /// Returns the highest physical address which may be in the range.
+    #[inline]
+    pub fn max_address(&self) -> usize {
+        self.max_address
+    }
+
+    /// Returns the number of contiguous frames of type `F`, starting with
+    /// `start`, which lie entirely at or below the maximum address.
+    pub fn frames_below<F: Page>(&self, start: &F) -> usize {
+        let start: usize = start.base().into();
+        match self.max_address.checked_sub(start) {
+            Some(len) if len >= F::SIZE - 1 => {
+                (len - (F::SIZE - 1)) / F::SIZE + 1
+            },
+            _ => 0,
+        }
+    }
+
+    /// Returns `true` if `range` satisfies these constraints.
+    pub fn is_satisfied_by<F: Page>(&self, range: &FrameRange<F>) -> bool {
+        range.len() == self.count
+            && range.start % self.align == 0
+            && self.frames_below(&range.start()) >= self.count
+    }
+}
+
+// ===== impl FrameRange =====
+
+impl<F> FrameRange<F>
+where
+    F: Page,
+{
+    /// Returns a new `FrameRange` of `count` frames, beginning with `start`.
+    ///
+    /// # Panics
+    /// If the range would extend past the end of the address space.
+    pub fn new(start: F, count: usize) -> Self {
+        let start: usize = start.base().into();
+        let end = count
+            .checked_mul(F::SIZE)
+            .and_then(|len| start.checked_add(len))
+            .expect("frame range must fit in the address space");
+        FrameRange {
+            start,
+            end,
+            _frame: PhantomData,
+        }
+    }
+
+    /// Returns the first frame in the range.
+    ///
+    /// If the range is empty, this is the frame which _would_ have been
+    /// first.
+    #[inline]
+    pub fn start(&self) -> F {
+        F::containing(self.start.into())
+    }
+
+    /// Returns the base address of the first frame in the range.
+    #[inline]
+    pub fn start_address(&self) -> F::Address {
+        self.start.into()
+    }
+
+    /// Returns the end address of the last frame in the range (exclusive).
+    #[inline]
+    pub fn end_address(&self) -> F::Address {
+        self.end.into()
+    }
+
+    /// Returns the number of frames in the range.
+    #[inline]
+    pub fn len(&self) -> usize {
+        (self.end - self.start) / F::SIZE
+    }
+
+    /// Returns `true` if the range contains no frames.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Returns `true` if `frame` is in the range.
+    #[inline]
+    pub fn contains(&self, frame: &F) -> bool {
+        let base: usize = frame.base().into();
+        base >= self.start && base < self.end
+    }
+
+    /// Returns `true` if this range and `other` have any frames in common.
+    #[inline]
+    pub fn overlaps(&self, other: &Self) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.start < other.end
+            && other.start < self.end
+    }
+
+    /// Split the range in two, so that the first range holds the first
+    /// `n` frames, and the second range holds the rest.
+    ///
+    /// # Panics
+    /// If `n` is greater than the length of the range.
+    pub fn split_at(self, n: usize) -> (Self, Self) {
+        assert!(n <= self.len(), "split index {} out of range", n);
+        let mid = self.start + n * F::SIZE;
+        let head = FrameRange {
+            start: self.start,
+            end: mid,
+            _frame: PhantomData,
+        };
+        let tail = FrameRange {
+            start: mid,
+            end: self.end,
+            _frame: PhantomData,
+        };
+        (head, tail)
+    }
+}
+
+impl<F> Iterator for FrameRange<F>
+where
+    F: Page,
+{
+    type Item = F;
+
+    fn next(&mut self) -> Option<F> {
+        if self.is_empty() {
+            return None;
+        }
+        let frame = self.start();
+        self.start += F::SIZE;
+        Some(frame)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.len();
+        (len, Some(len))
+    }
+}
+
+impl<F> DoubleEndedIterator for FrameRange<F>
+where
+    F: Page,
+{
+    fn next_back(&mut self) -> Option<F> {
+        if self.is_empty() {
+            return None;
+        }
+        self.end -= F::SIZE;
+        Some(F::containing(self.end.into()))
+    }
+}
+
+impl<F> FusedIterator for FrameRange<F> where F: Page {}
+
+// `FrameRange` is deliberately not `Copy`: since it is an iterator, a copy
+// would let `for frame in range` silently consume a copy of the range.
+impl<F> Clone for FrameRange<F> {
+    #[inline]
+    fn clone(&self) -> Self {
+        FrameRange {
+            start: self.start,
+            end: self.end,
+            _frame: PhantomData,
+        }
+    }
+}
+
+impl<F> PartialEq for FrameRange<F> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start && self.end == other.end
+    }
+}
+
+impl<F> Eq for FrameRange<F> {}
+
+impl<F> fmt::Debug for FrameRange<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FrameRange")
+            .field("start", &(self.start as *const u8))
+            .field("end", &(self.end as *const u8))
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::sim::{Frame, HugeFrame, PhysicalMemory, FRAME_SIZE};
+    use std::vec::Vec;
+
+    #[test]
+    fn ranges_iterate_over_frames() {
+        let memory = PhysicalMemory::new(4);
+        let range = FrameRange::new(memory.frame(1), 3);
+        assert_eq!(range.len(), 3);
+        assert_eq!(range.start(), memory.frame(1));
+        assert_eq!(range.end_address(), memory.end_addr());
+        let frames: Vec<Frame> = range.clone().collect();
+        assert_eq!(
+            frames,
+            vec![memory.frame(1), memory.frame(2), memory.frame(3)]
+        );
+        assert_eq!(range.clone().next_back(), Some(memory.frame(3)));
+        assert!(range.contains(&memory.frame(3)));
+        assert!(!range.contains(&memory.frame(0)));
+    }
+
+    #[test]
+    fn ranges_split() {
+        let memory = PhysicalMemory::new(4);
+        let (head, tail) = FrameRange::new(memory.frame(0), 4).split_at(1);
+        assert_eq!(head, FrameRange::new(memory.frame(0), 1));
+        assert_eq!(tail, FrameRange::new(memory.frame(1), 3));
+        assert!(!head.overlaps(&tail));
+
+        let (empty, all) = tail.clone().split_at(0);
+        assert!(empty.is_empty());
+        assert_eq!(all, tail);
+    }
+
+    #[test]
+    fn ranges_overlap() {
+        let memory = PhysicalMemory::new(8);
+        let a = FrameRange::new(memory.frame(0), 4);
+        let b = FrameRange::new(memory.frame(3), 4);
+        let c = FrameRange::new(memory.frame(4), 4);
+        assert!(a.overlaps(&b) && b.overlaps(&a));
+        assert!(b.overlaps(&c));
+        assert!(!a.overlaps(&c));
+        assert!(!a.overlaps(&FrameRange::new(memory.frame(2), 0)));
+    }
+
+    #[test]
+    fn constraints_limit_ranges() {
+        let memory = PhysicalMemory::with_base(0x10_0000, 8);
+        let below = 0x10_0000 + 4 * FRAME_SIZE - 1;
+        let constraints = Constraints::new(2, 2 * FRAME_SIZE, below);
+        assert_eq!(constraints.frames_below(&memory.frame(0)), 4);
+        assert_eq!(constraints.frames_below(&memory.frame(3)), 1);
+        assert_eq!(constraints.frames_below(&memory.frame(4)), 0);
+
+        let ok = FrameRange::new(memory.frame(2), 2);
+        assert!(constraints.is_satisfied_by(&ok));
+        let unaligned = FrameRange::new(memory.frame(1), 2);
+        assert!(!constraints.is_satisfied_by(&unaligned));
+        let too_high = FrameRange::new(memory.frame(4), 2);
+        assert!(!constraints.is_satisfied_by(&too_high));
+        let too_short = FrameRange::new(memory.frame(0), 1);
+        assert!(!constraints.is_satisfied_by(&too_short));
+    }
+
+    #[test]
+    fn default_huge_page_methods() {
+        struct Frames<'mem>(crate::sim::Frames<'mem>);
+
+        unsafe impl<'mem> Allocator for Frames<'mem> {
+            type Frame = Frame;
+
+            unsafe fn alloc(&mut self) -> Result<Frame, AllocErr> {
+                self.0.alloc()
+            }
+
+            unsafe fn dealloc(&mut self, frame: Frame) -> Result<(), AllocErr> {
+                self.0.dealloc(frame)
+            }
+        }
+
+        unsafe impl<'mem> HugeAllocator<HugeFrame> for Frames<'mem> {}
+
+        let count = HugeFrame::SIZE / FRAME_SIZE;
+        let memory = PhysicalMemory::with_base(HugeFrame::SIZE, count + 1);
+        let mut frames = Frames(memory.allocator());
+        let page: HugeFrame = unsafe { frames.alloc_huge() }.unwrap();
+        assert_eq!(page.base(), memory.start_addr());
+        assert_eq!(frames.0.allocated(), count);
+
+        // The only frame left is not aligned to a huge page.
+        assert!(unsafe { frames.alloc_huge() }.is_err());
+        unsafe { frames.dealloc_huge(page).unwrap() };
+        assert_eq!(frames.0.allocated(), 0);
+    }
+
+    #[test]
+    fn default_range_methods() {
+        let memory = PhysicalMemory::new(4);
+        let mut frames = memory.allocator();
+        let range = unsafe { frames.alloc_range(3) }.unwrap();
+        assert_eq!(range, FrameRange::new(memory.frame(0), 3));
+        assert!(unsafe { frames.alloc_range(2) }.is_err());
+        assert_eq!(frames.allocated(), 3, "failed ranges must be freed");
+
+        unsafe { frames.dealloc_range(range).unwrap() };
+        assert_eq!(frames.allocated(), 0);
+        assert!(unsafe { frames.alloc_range(0) }.is_err());
+
+        let high = Constraints::new(1, FRAME_SIZE, usize::max_value());
+        let low = Constraints::new(1, FRAME_SIZE, 0xfff);
+        assert!(unsafe { frames.alloc_constrained(low) }.is_err());
+        assert_eq!(frames.allocated(), 0, "failed ranges must be freed");
+        let range = unsafe { frames.alloc_constrained(high) }.unwrap();
+        assert_eq!(range.len(), 1);
+    }
 }
@@ -20,12 +20,21 @@ extern crate spin;
 pub mod frame;
 #[cfg(feature = "lend")]
 pub mod lend;
+pub mod memory_map;
+#[cfg(feature = "std")]
+pub mod sim;
 
-pub use self::frame::Allocator as FrameAllocator;
+pub use self::frame::{
+    Allocator as FrameAllocator,
+    Constraints,
+    FrameRange,
+    HugeAllocator,
+};
 use core::{
     alloc::{Alloc, AllocErr, Layout},
     ptr,
 };
+use hal9000::mem::Page;
 
 /// An allocator behind a mutex.
 #[derive(Debug)]
@@ -33,6 +42,13 @@ pub struct LockedAlloc<A>(spin::Mutex<A>);
 
 // ===== impl LockedAlloc =====
 
+impl<A> LockedAlloc<A> {
+    /// Returns a new `LockedAlloc` wrapping the given allocator.
+    pub const fn new(alloc: A) -> Self {
+        LockedAlloc(spin::Mutex::new(alloc))
+    }
+}
+
 impl<A> core::ops::Deref for LockedAlloc<A> {
     type Target = spin::Mutex<A>;
 
@@ -72,4 +88,39 @@ where
     unsafe fn dealloc(&mut self, frame: Self::Frame) -> Result<(), AllocErr> {
         self.lock().dealloc(frame)
     }
+
+    unsafe fn alloc_range(
+        &mut self,
+        count: usize,
+    ) -> Result<FrameRange<Self::Frame>, AllocErr> {
+        self.lock().alloc_range(count)
+    }
+
+    unsafe fn dealloc_range(
+        &mut self,
+        range: FrameRange<Self::Frame>,
+    ) -> Result<(), AllocErr> {
+        self.lock().dealloc_range(range)
+    }
+
+    unsafe fn alloc_constrained(
+        &mut self,
+        constraints: Constraints,
+    ) -> Result<FrameRange<Self::Frame>, AllocErr> {
+        self.lock().alloc_constrained(constraints)
+    }
+}
+
+unsafe impl<'a, A, P> HugeAllocator<P> for &'a LockedAlloc<A>
+where
+    A: HugeAllocator<P>,
+    P: Page,
+{
+    unsafe fn alloc_huge(&mut self) -> Result<P, AllocErr> {
+        self.lock().alloc_huge()
+    }
+
+    unsafe fn dealloc_huge(&mut self, page: P) -> Result<(), AllocErr> {
+        self.lock().dealloc_huge(page)
+    }
 }
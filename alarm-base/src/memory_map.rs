@@ -0,0 +1,709 @@
+//! Descriptions of physical memory provided by firmware.
+//!
+//! At boot, the firmware or bootloader describes the machine's physical
+//! memory as a list of [`Region`]s, each of which is usable RAM, or is
+//! reserved for some purpose. This module provides parsers for the two
+//! common formats of such lists — the BIOS [`E820`] map and the
+//! [`Multiboot2`] memory map tag — and an iterator over the frames which
+//! are [`Available`] for a frame allocator to manage.
+//!
+//! [`Region`]: struct.Region.html
+//! [`E820`]: struct.E820.html
+//! [`Multiboot2`]: struct.Multiboot2.html
+//! [`Available`]: struct.Available.html
+use crate::frame::FrameRange;
+use core::{cmp, fmt, marker::PhantomData};
+use hal9000::mem::Page;
+
+/// The kind of memory in a [`Region`].
+///
+/// [`Region`]: struct.Region.html
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegionKind {
+    /// RAM which is free for the kernel to use.
+    Usable,
+    /// Memory which must not be used.
+    Reserved,
+    /// Memory holding ACPI tables, which may be used once they have been
+    /// read.
+    AcpiReclaimable,
+    /// Memory which must be preserved across ACPI sleep states.
+    AcpiNvs,
+    /// Memory used by the bootloader, such as the boot information
+    /// structure itself.
+    Bootloader,
+    /// Memory holding the kernel's own code and data.
+    KernelImage,
+    /// RAM which has been found to be faulty.
+    Defective,
+}
+
+/// A region of physical memory.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Region {
+    /// The physical address of the start of the region.
+    base: usize,
+
+    /// The length of the region, in bytes.
+    len: usize,
+
+    /// The kind of memory in the region.
+    kind: RegionKind,
+}
+
+/// An iterator over the regions in an E820 memory map.
+///
+/// An E820 map is the list of address range descriptors returned by the
+/// BIOS `INT 15h, EAX=E820h` call. Each descriptor holds a 64-bit base
+/// address, a 64-bit length, and a 32-bit type, followed by a 32-bit field
+/// of extended attributes if the BIOS supports ACPI 3.0.
+#[derive(Clone, Debug)]
+pub struct E820<'a> {
+    entries: Entries<'a>,
+}
+
+/// An iterator over the regions in a Multiboot2 memory map tag.
+#[derive(Clone, Debug)]
+pub struct Multiboot2<'a> {
+    entries: Entries<'a>,
+}
+
+/// An iterator over the frames described by a memory map which are
+/// available for a frame allocator to manage.
+///
+/// A frame is available if it lies entirely within a `Usable` region, and
+/// does not overlap any region of another kind. Memory maps may report the
+/// kernel image or the bootloader's data as part of a usable region, so
+/// those regions are subtracted from the usable regions which contain them.
+/// Frames which are in more than one usable region are only yielded once.
+///
+/// Since this iterator must not require a heap, it makes one pass over the
+/// memory map for each range of frames it yields, and so requires the
+/// iterator of regions to be `Clone`.
+///
+/// # Type Parameters
+/// - `F`: the type of frames yielded by this iterator.
+/// - `I`: the type of the iterator over the memory map's regions.
+pub struct Available<F, I> {
+    /// All the regions in the memory map.
+    regions: I,
+
+    /// The index of the usable region which is currently being searched.
+    current: usize,
+
+    /// The base address of the next frame in the current region which may
+    /// be available.
+    next: usize,
+
+    /// The end address of the last whole frame in the current region.
+    end: usize,
+
+    _frame: PhantomData<fn() -> F>,
+}
+
+/// An iterator over the fixed-size entries in an E820 or Multiboot2 memory
+/// map.
+#[derive(Clone, Debug)]
+struct Entries<'a> {
+    bytes: &'a [u8],
+    entry_size: usize,
+}
+
+/// The size of an E820 address range descriptor, without extended
+/// attributes.
+const E820_ENTRY_SIZE: usize = 20;
+
+/// The type of a Multiboot2 memory map tag.
+const MULTIBOOT2_MMAP_TAG: u32 = 6;
+
+/// The type of the Multiboot2 tag which ends the boot information.
+const MULTIBOOT2_END_TAG: u32 = 0;
+
+/// The size of the header of a Multiboot2 memory map tag.
+const MULTIBOOT2_MMAP_HEADER_SIZE: usize = 16;
+
+// ===== impl Region =====
+
+impl Region {
+    /// Returns a new `Region` of `len` bytes of the given `kind`, starting at
+    /// the physical address `base`.
+    ///
+    /// If the region would extend past the end of the address space, it is
+    /// truncated.
+    pub fn new(base: usize, len: usize, kind: RegionKind) -> Self {
+        Region {
+            base,
+            len: cmp::min(len, usize::max_value() - base),
+            kind,
+        }
+    }
+
+    /// Returns the physical address of the start of the region.
+    #[inline]
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns the length of the region, in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the region contains no memory.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the physical address of the end of the region (exclusive).
+    #[inline]
+    pub fn end(&self) -> usize {
+        self.base + self.len
+    }
+
+    /// Returns the kind of memory in the region.
+    #[inline]
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    /// Returns `true` if the region is free for the kernel to use.
+    #[inline]
+    pub fn is_usable(&self) -> bool {
+        self.kind == RegionKind::Usable
+    }
+
+    /// Returns the range of whole frames of type `F` in the region.
+    pub fn frames<F: Page>(&self) -> FrameRange<F> {
+        let start = align_up(self.base, F::SIZE);
+        let end = self.end() & !(F::SIZE - 1);
+        let count = end.saturating_sub(start) / F::SIZE;
+        FrameRange::new(F::containing(start.into()), count)
+    }
+
+    /// Returns a `Region` from a 64-bit memory map entry, if any of it is
+    /// in the address space.
+    fn from_entry(base: u64, len: u64, kind: RegionKind) -> Option<Self> {
+        if base > usize::max_value() as u64 {
+            return None;
+        }
+        let len = cmp::min(len, usize::max_value() as u64) as usize;
+        Some(Region::new(base as usize, len, kind))
+    }
+}
+
+// ===== impl E820 =====
+
+impl<'a> E820<'a> {
+    /// Returns an iterator over the regions in an E820 memory map made up of
+    /// 20-byte address range descriptors.
+    ///
+    /// Any bytes after the last whole descriptor are ignored.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        E820 {
+            entries: Entries {
+                bytes,
+                entry_size: E820_ENTRY_SIZE,
+            },
+        }
+    }
+
+    /// Returns an iterator over the regions in an E820 memory map made up of
+    /// address range descriptors of `entry_size` bytes.
+    ///
+    /// This should be used if the BIOS returned 24-byte descriptors, with
+    /// ACPI 3.0 extended attributes.
+    ///
+    /// # Returns
+    /// - `Some(E820)` if the descriptors are large enough to be valid.
+    /// - `None` if `entry_size` is less than 20 bytes.
+    pub fn with_entry_size(bytes: &'a [u8], entry_size: usize) -> Option<Self> {
+        if entry_size < E820_ENTRY_SIZE {
+            return None;
+        }
+        Some(E820 {
+            entries: Entries { bytes, entry_size },
+        })
+    }
+}
+
+impl<'a> Iterator for E820<'a> {
+    type Item = Region;
+
+    #[inline]
+    fn next(&mut self) -> Option<Region> {
+        self.entries.next()
+    }
+}
+
+// ===== impl Multiboot2 =====
+
+impl<'a> Multiboot2<'a> {
+    /// Returns an iterator over the regions in a Multiboot2 memory map tag.
+    ///
+    /// `tag` must begin with the tag's `type` field.
+    ///
+    /// # Returns
+    /// - `Some(Multiboot2)` if `tag` is a well-formed memory map tag.
+    /// - `None` if `tag` is not a memory map tag, or is malformed.
+    pub fn from_tag(tag: &'a [u8]) -> Option<Self> {
+        if tag.len() < MULTIBOOT2_MMAP_HEADER_SIZE
+            || read_u32(tag, 0) != MULTIBOOT2_MMAP_TAG
+        {
+            return None;
+        }
+        let size = read_u32(tag, 4) as usize;
+        let entry_size = read_u32(tag, 8) as usize;
+        if size < MULTIBOOT2_MMAP_HEADER_SIZE
+            || size > tag.len()
+            || entry_size < E820_ENTRY_SIZE
+        {
+            return None;
+        }
+        Some(Multiboot2 {
+            entries: Entries {
+                bytes: &tag[MULTIBOOT2_MMAP_HEADER_SIZE..size],
+                entry_size,
+            },
+        })
+    }
+
+    /// Returns an iterator over the regions in the memory map tag of a
+    /// Multiboot2 boot information structure.
+    ///
+    /// `info` must begin with the structure's `total_size` field.
+    ///
+    /// # Returns
+    /// - `Some(Multiboot2)` if the boot information has a well-formed memory
+    ///   map tag.
+    /// - `None` if it has no memory map tag, or is malformed.
+    pub fn from_boot_info(info: &'a [u8]) -> Option<Self> {
+        if info.len() < 8 {
+            return None;
+        }
+        let total_size = read_u32(info, 0) as usize;
+        let info = info.get(..total_size)?;
+        let mut offset = 8;
+        while offset + 8 <= info.len() {
+            let ty = read_u32(info, offset);
+            let size = read_u32(info, offset + 4) as usize;
+            if ty == MULTIBOOT2_END_TAG || size < 8 {
+                return None;
+            }
+            if ty == MULTIBOOT2_MMAP_TAG {
+                return Self::from_tag(info.get(offset..offset + size)?);
+            }
+            // Tags are padded to 8-byte alignment.
+            offset = align_up(offset.checked_add(size)?, 8);
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for Multiboot2<'a> {
+    type Item = Region;
+
+    #[inline]
+    fn next(&mut self) -> Option<Region> {
+        self.entries.next()
+    }
+}
+
+// ===== impl Available =====
+
+impl<F, I> Available<F, I>
+where
+    F: Page,
+    I: Iterator<Item = Region> + Clone,
+{
+    /// Returns an iterator over the frames in `regions` which are available
+    /// for a frame allocator to manage.
+    pub fn new<R>(regions: R) -> Self
+    where
+        R: IntoIterator<Item = Region, IntoIter = I>,
+    {
+        Available {
+            regions: regions.into_iter(),
+            current: 0,
+            next: 0,
+            end: 0,
+            _frame: PhantomData,
+        }
+    }
+
+    /// Returns the smallest range of frames which contains every available
+    /// frame, or `None` if no frames are available.
+    ///
+    /// The range may include frames which are not available, in the gaps
+    /// between usable regions.
+    pub fn span(&self) -> Option<FrameRange<F>> {
+        let (start, end) = self.clone().fold(None, |span, range| {
+            let start: usize = range.start_address().into();
+            let end: usize = range.end_address().into();
+            match span {
+                None => Some((start, end)),
+                Some((s, e)) => Some((cmp::min(s, start), cmp::max(e, end))),
+            }
+        })?;
+        let count = (end - start) / F::SIZE;
+        Some(FrameRange::new(F::containing(start.into()), count))
+    }
+
+    /// Returns `true` if the frame at `addr` overlaps a region which excludes
+    /// it from the current usable region, and if so, advances `addr` past
+    /// that region.
+    fn skip_excluded(&self, addr: &mut usize) -> bool {
+        let frame_end = addr.saturating_add(F::SIZE);
+        let excluded = self
+            .excluding()
+            .find(|region| region.base() < frame_end && *addr < region.end());
+        match excluded {
+            Some(region) => {
+                *addr = align_up(region.end(), F::SIZE);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns an iterator over the regions which exclude frames from the
+    /// current usable region: every region which is not usable, and every
+    /// usable region which came before the current one.
+    fn excluding<'b>(&'b self) -> impl Iterator<Item = Region> + 'b {
+        let current = self.current;
+        self.regions
+            .clone()
+            .enumerate()
+            .filter(move |(i, region)| {
+                !region.is_empty() && (!region.is_usable() || *i + 1 < current)
+            })
+            .map(|(_, region)| region)
+    }
+}
+
+impl<F, I> Iterator for Available<F, I>
+where
+    F: Page,
+    I: Iterator<Item = Region> + Clone,
+{
+    type Item = FrameRange<F>;
+
+    fn next(&mut self) -> Option<FrameRange<F>> {
+        loop {
+            // Skip past the frames at the start of the remaining part of the
+            // current region which are excluded by other regions.
+            let mut next = self.next;
+            while next < self.end && self.skip_excluded(&mut next) {}
+            self.next = cmp::min(next, self.end);
+
+            if self.next < self.end {
+                // The available frames end where the next excluded region
+                // begins, or at the end of the current region.
+                let start = self.next;
+                let end = self
+                    .excluding()
+                    .filter(|region| region.end() > start)
+                    .map(|region| region.base() & !(F::SIZE - 1))
+                    .filter(|&base| base > start)
+                    .fold(self.end, cmp::min);
+                self.next = end;
+                let count = (end - start) / F::SIZE;
+                let start = F::containing(start.into());
+                return Some(FrameRange::new(start, count));
+            }
+
+            // Move on to the next usable region.
+            let region = self
+                .regions
+                .clone()
+                .enumerate()
+                .skip(self.current)
+                .find(|(_, region)| region.is_usable());
+            let (i, region) = region?;
+            let frames = region.frames::<F>();
+            self.current = i + 1;
+            self.next = frames.start_address().into();
+            self.end = frames.end_address().into();
+        }
+    }
+}
+
+impl<F, I> Clone for Available<F, I>
+where
+    I: Clone,
+{
+    fn clone(&self) -> Self {
+        Available {
+            regions: self.regions.clone(),
+            current: self.current,
+            next: self.next,
+            end: self.end,
+            _frame: PhantomData,
+        }
+    }
+}
+
+impl<F, I> fmt::Debug for Available<F, I>
+where
+    I: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Available")
+            .field("regions", &self.regions)
+            .field("current", &self.current)
+            .field("next", &(self.next as *const u8))
+            .field("end", &(self.end as *const u8))
+            .finish()
+    }
+}
+
+// ===== impl Entries =====
+
+impl<'a> Entries<'a> {
+    /// Returns the kind of memory described by a memory map entry's type.
+    ///
+    /// E820 and Multiboot2 memory maps use the same values for each type.
+    fn kind(ty: u32) -> RegionKind {
+        match ty {
+            1 => RegionKind::Usable,
+            3 => RegionKind::AcpiReclaimable,
+            4 => RegionKind::AcpiNvs,
+            5 => RegionKind::Defective,
+            _ => RegionKind::Reserved,
+        }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Region;
+
+    fn next(&mut self) -> Option<Region> {
+        // Each entry begins with its base address, length, and type. Entries
+        // which begin past the end of the address space are skipped.
+        while self.bytes.len() >= self.entry_size {
+            let (entry, rest) = self.bytes.split_at(self.entry_size);
+            self.bytes = rest;
+            let kind = Self::kind(read_u32(entry, 16));
+            let (base, len) = (read_u64(entry, 0), read_u64(entry, 8));
+            let region = Region::from_entry(base, len, kind);
+            if region.is_some() {
+                return region;
+            }
+        }
+        None
+    }
+}
+
+/// Round `addr` up to a multiple of `align`, which must be a power of two,
+/// saturating at the last multiple of `align` in the address space.
+#[inline]
+fn align_up(addr: usize, align: usize) -> usize {
+    addr.saturating_add(align - 1) & !(align - 1)
+}
+
+/// Read a little-endian `u32` from `bytes` at `offset`.
+#[inline]
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset..offset + 4]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | u32::from(byte))
+}
+
+/// Read a little-endian `u64` from `bytes` at `offset`.
+#[inline]
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    bytes[offset..offset + 8]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::sim::{Frame, FRAME_SIZE};
+    use hal9000::mem::PAddr;
+    use std::vec::Vec;
+
+    fn entry(bytes: &mut Vec<u8>, base: u64, len: u64, ty: u32, pad: usize) {
+        bytes.extend_from_slice(&base.to_le_bytes());
+        bytes.extend_from_slice(&len.to_le_bytes());
+        bytes.extend_from_slice(&ty.to_le_bytes());
+        bytes.resize(bytes.len() + pad, 0);
+    }
+
+    fn addr(addr: PAddr) -> usize {
+        addr.into()
+    }
+
+    fn available(regions: &[Region]) -> Vec<(usize, usize)> {
+        Available::<Frame, _>::new(regions.iter().cloned())
+            .map(|range| (addr(range.start_address()), range.len()))
+            .collect()
+    }
+
+    #[test]
+    fn e820_entries_parsed() {
+        let mut bytes = Vec::new();
+        entry(&mut bytes, 0, 0x9_fc00, 1, 0);
+        entry(&mut bytes, 0x9_fc00, 0x400, 2, 0);
+        entry(&mut bytes, 0x10_0000, 0x10_0000, 3, 0);
+        entry(&mut bytes, 0x20_0000, 0x1000, 4, 0);
+        entry(&mut bytes, 0x20_1000, 0x1000, 5, 0);
+        // A truncated entry at the end is ignored.
+        bytes.extend_from_slice(&[0xff; 12]);
+
+        let regions: Vec<_> = E820::new(&bytes).collect();
+        assert_eq!(
+            regions,
+            vec![
+                Region::new(0, 0x9_fc00, RegionKind::Usable),
+                Region::new(0x9_fc00, 0x400, RegionKind::Reserved),
+                Region::new(0x10_0000, 0x10_0000, RegionKind::AcpiReclaimable),
+                Region::new(0x20_0000, 0x1000, RegionKind::AcpiNvs),
+                Region::new(0x20_1000, 0x1000, RegionKind::Defective),
+            ]
+        );
+    }
+
+    #[test]
+    fn e820_extended_entries_parsed() {
+        let mut bytes = Vec::new();
+        entry(&mut bytes, 0x1000, 0x2000, 1, 4);
+        entry(&mut bytes, 0x3000, 0x1000, 7, 4);
+
+        let regions: Vec<_> =
+            E820::with_entry_size(&bytes, 24).unwrap().collect();
+        assert_eq!(
+            regions,
+            vec![
+                Region::new(0x1000, 0x2000, RegionKind::Usable),
+                Region::new(0x3000, 0x1000, RegionKind::Reserved),
+            ]
+        );
+        assert!(E820::with_entry_size(&bytes, 16).is_none());
+    }
+
+    fn mmap_tag(regions: &[(u64, u64, u32)]) -> Vec<u8> {
+        let mut tag = Vec::new();
+        let size = 16 + 24 * regions.len() as u32;
+        tag.extend_from_slice(&MULTIBOOT2_MMAP_TAG.to_le_bytes());
+        tag.extend_from_slice(&size.to_le_bytes());
+        tag.extend_from_slice(&24u32.to_le_bytes());
+        tag.extend_from_slice(&0u32.to_le_bytes());
+        for &(base, len, ty) in regions {
+            entry(&mut tag, base, len, ty, 4);
+        }
+        tag
+    }
+
+    #[test]
+    fn multiboot2_tag_parsed() {
+        let tag = mmap_tag(&[(0, 0x9_f000, 1), (0xf_0000, 0x1_0000, 2)]);
+        let regions: Vec<_> = Multiboot2::from_tag(&tag).unwrap().collect();
+        assert_eq!(
+            regions,
+            vec![
+                Region::new(0, 0x9_f000, RegionKind::Usable),
+                Region::new(0xf_0000, 0x1_0000, RegionKind::Reserved),
+            ]
+        );
+    }
+
+    #[test]
+    fn multiboot2_malformed_tags_rejected() {
+        let mut tag = mmap_tag(&[(0, 0x9_f000, 1)]);
+        assert!(Multiboot2::from_tag(&tag[..20]).is_none());
+
+        tag[8..12].copy_from_slice(&8u32.to_le_bytes());
+        assert!(Multiboot2::from_tag(&tag).is_none());
+
+        tag[0..4].copy_from_slice(&1u32.to_le_bytes());
+        assert!(Multiboot2::from_tag(&tag).is_none());
+    }
+
+    #[test]
+    fn multiboot2_boot_info_searched_for_tag() {
+        let mut info = vec![0; 8];
+        // A command line tag, whose size is not a multiple of 8.
+        info.extend_from_slice(&1u32.to_le_bytes());
+        info.extend_from_slice(&13u32.to_le_bytes());
+        info.extend_from_slice(b"quiet");
+        info.extend_from_slice(&[0; 3]);
+        info.extend(mmap_tag(&[(0x10_0000, 0x70_0000, 1)]));
+        info.extend_from_slice(&MULTIBOOT2_END_TAG.to_le_bytes());
+        info.extend_from_slice(&8u32.to_le_bytes());
+        let total = info.len() as u32;
+        info[0..4].copy_from_slice(&total.to_le_bytes());
+
+        let regions: Vec<_> =
+            Multiboot2::from_boot_info(&info).unwrap().collect();
+        assert_eq!(
+            regions,
+            vec![Region::new(0x10_0000, 0x70_0000, RegionKind::Usable)]
+        );
+
+        // Without a memory map tag, there is nothing to parse.
+        let mut info = vec![16, 0, 0, 0, 0, 0, 0, 0];
+        info.extend_from_slice(&MULTIBOOT2_END_TAG.to_le_bytes());
+        info.extend_from_slice(&8u32.to_le_bytes());
+        assert!(Multiboot2::from_boot_info(&info).is_none());
+    }
+
+    #[test]
+    fn region_frames_are_whole() {
+        let region = Region::new(0x1800, 0x3000, RegionKind::Usable);
+        let frames = region.frames::<Frame>();
+        assert_eq!(addr(frames.start_address()), 0x2000);
+        assert_eq!(frames.len(), 2);
+
+        let region = Region::new(0x1800, 0x400, RegionKind::Usable);
+        assert!(region.frames::<Frame>().is_empty());
+    }
+
+    #[test]
+    fn available_excludes_other_regions() {
+        let regions = [
+            Region::new(0, 0x9_fc00, RegionKind::Usable),
+            Region::new(0x9_fc00, 0x400, RegionKind::Reserved),
+            Region::new(0x10_0000, 0x10_0000, RegionKind::Usable),
+            // The kernel image is reported within usable memory, and does
+            // not end on a frame boundary.
+            Region::new(0x10_0000, 0x2_0800, RegionKind::KernelImage),
+            Region::new(0x18_0000, FRAME_SIZE, RegionKind::Bootloader),
+            Region::new(0x1f_f000, 0x2000, RegionKind::AcpiReclaimable),
+        ];
+        assert_eq!(
+            available(&regions),
+            vec![(0, 0x9f), (0x12_1000, 0x5f), (0x18_1000, 0x7e)]
+        );
+    }
+
+    #[test]
+    fn available_frames_yielded_once() {
+        let regions = [
+            Region::new(0x1_0000, 0x4000, RegionKind::Usable),
+            Region::new(0x1_2000, 0x4000, RegionKind::Usable),
+            Region::new(0x1_0000, 0x4000, RegionKind::Usable),
+            Region::new(0x2_0000, 0, RegionKind::Reserved),
+        ];
+        assert_eq!(available(&regions), vec![(0x1_0000, 4), (0x1_4000, 2)]);
+
+        let span = Available::<Frame, _>::new(regions.iter().cloned())
+            .span()
+            .unwrap();
+        assert_eq!(addr(span.start_address()), 0x1_0000);
+        assert_eq!(span.len(), 6);
+    }
+
+    #[test]
+    fn nothing_available_without_usable_memory() {
+        let regions = [Region::new(0, 0x1000_0000, RegionKind::Reserved)];
+        assert!(available(&regions).is_empty());
+        assert!(Available::<Frame, _>::new(regions.iter().cloned())
+            .span()
+            .is_none());
+    }
+}
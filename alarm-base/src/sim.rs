@@ -0,0 +1,474 @@
+//! Simulated physical memory, for testing frame allocators on the host.
+//!
+//! A [`PhysicalMemory`] reserves a buffer from the host's heap and pretends
+//! that it is a region of physical memory starting at some fake physical
+//! address. Frames in the region are represented by [`Frame`]s, which
+//! implement `hal9000`'s `Page` trait, and physical addresses in the region
+//! may be translated to pointers into the buffer, so that allocators which
+//! store data in the frames they manage may be tested with `cargo test`.
+//!
+//! [`PhysicalMemory`]: struct.PhysicalMemory.html
+//! [`Frame`]: struct.Frame.html
+use crate::{
+    frame,
+    memory_map::{Region, RegionKind},
+};
+use core::{alloc::AllocErr, fmt, ops::Range, ptr::NonNull};
+use hal9000::mem::{PAddr, Page};
+use std::{
+    alloc::{alloc_zeroed, dealloc, Layout},
+    vec::Vec,
+};
+
+/// The base-2 logarithm of the size of a simulated frame.
+pub const FRAME_SHIFT: usize = 12;
+
+/// The size of a simulated frame, in bytes.
+pub const FRAME_SIZE: usize = 1 << FRAME_SHIFT;
+
+/// The base-2 logarithm of the size of a simulated huge frame.
+pub const HUGE_FRAME_SHIFT: usize = 21;
+
+/// The physical address at which simulated memory starts, by default.
+///
+/// This is deliberately not zero, so that code which confuses physical
+/// frame addresses with frame numbers or offsets is caught by tests.
+pub const DEFAULT_BASE: usize = 0x10_0000;
+
+/// The number of frames described by [`sample_memory_map`].
+///
+/// [`sample_memory_map`]: fn.sample_memory_map.html
+pub const SAMPLE_MAP_FRAMES: usize = 64;
+
+/// The first frame after the kernel image in [`sample_memory_map`].
+///
+/// This is the first frame of the first range of more than one available
+/// frame, so allocators which keep their state in the first range that can
+/// hold it will keep it here.
+///
+/// [`sample_memory_map`]: fn.sample_memory_map.html
+pub const SAMPLE_MAP_AFTER_KERNEL: usize = 4;
+
+/// The frames which [`sample_memory_map`] reports as two adjacent usable
+/// regions.
+///
+/// Together, they form a naturally aligned block of 32 frames.
+///
+/// [`sample_memory_map`]: fn.sample_memory_map.html
+pub const SAMPLE_MAP_SPLIT: Range<usize> = 32..64;
+
+/// A 4 KiB frame of simulated physical memory.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Frame(usize);
+
+/// A 2 MiB huge frame of simulated physical memory.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct HugeFrame(usize);
+
+/// A region of simulated physical memory.
+///
+/// The region is backed by a zeroed, frame-aligned buffer on the host's
+/// heap, which is freed when the `PhysicalMemory` is dropped.
+pub struct PhysicalMemory {
+    /// The start of the host buffer backing the simulated memory.
+    buf: NonNull<u8>,
+
+    /// The fake physical address of the first frame.
+    base: usize,
+
+    /// The number of frames in the region.
+    frames: usize,
+}
+
+/// A simple frame allocator over a region of simulated physical memory.
+///
+/// Frames are handed out in address order, and frames which are returned
+/// are reused first. The allocator keeps track of which frames are in use
+/// on the host's heap, so that freeing a frame twice, or freeing a frame
+/// from outside the region, returns an error rather than corrupting state.
+#[derive(Debug)]
+pub struct Frames<'mem> {
+    /// The memory frames are allocated from.
+    memory: &'mem PhysicalMemory,
+
+    /// Whether each frame in the region is allocated.
+    allocated: Vec<bool>,
+
+    /// Indices of frames which have been returned to the allocator.
+    free: Vec<usize>,
+
+    /// The index of the first frame which has never been allocated.
+    next: usize,
+}
+
+// ===== impl Frame =====
+
+impl Page for Frame {
+    type Address = PAddr;
+    const SHIFT: usize = FRAME_SHIFT;
+    const SIZE: usize = FRAME_SIZE;
+
+    #[inline]
+    fn containing(addr: PAddr) -> Self {
+        let addr: usize = addr.into();
+        Frame(addr & !(FRAME_SIZE - 1))
+    }
+
+    #[inline]
+    fn base(&self) -> PAddr {
+        PAddr::from(self.0)
+    }
+
+    #[inline]
+    fn end_address(&self) -> PAddr {
+        PAddr::from(self.0 + FRAME_SIZE)
+    }
+
+    #[inline]
+    fn number(&self) -> usize {
+        self.0 >> FRAME_SHIFT
+    }
+}
+
+// ===== impl HugeFrame =====
+
+impl Page for HugeFrame {
+    type Address = PAddr;
+    const SHIFT: usize = HUGE_FRAME_SHIFT;
+
+    #[inline]
+    fn containing(addr: PAddr) -> Self {
+        let addr: usize = addr.into();
+        HugeFrame(addr & !(Self::SIZE - 1))
+    }
+
+    #[inline]
+    fn base(&self) -> PAddr {
+        PAddr::from(self.0)
+    }
+
+    #[inline]
+    fn end_address(&self) -> PAddr {
+        PAddr::from(self.0 + Self::SIZE)
+    }
+
+    #[inline]
+    fn number(&self) -> usize {
+        self.0 >> HUGE_FRAME_SHIFT
+    }
+}
+
+// ===== impl PhysicalMemory =====
+
+impl PhysicalMemory {
+    /// Returns `frames` frames of simulated physical memory, starting at
+    /// [`DEFAULT_BASE`].
+    ///
+    /// [`DEFAULT_BASE`]: constant.DEFAULT_BASE.html
+    pub fn new(frames: usize) -> Self {
+        Self::with_base(DEFAULT_BASE, frames)
+    }
+
+    /// Returns `frames` frames of simulated physical memory, starting at
+    /// the physical address `base`.
+    ///
+    /// # Panics
+    /// - If `base` is not aligned to `FRAME_SIZE`.
+    /// - If `frames` is zero, or the region would extend past the end of the
+    ///   address space.
+    /// - If the host could not allocate a buffer for the region.
+    pub fn with_base(base: usize, frames: usize) -> Self {
+        assert_eq!(base % FRAME_SIZE, 0, "base must be frame-aligned");
+        assert!(frames > 0, "simulated memory must have at least one frame");
+        let len = frames
+            .checked_mul(FRAME_SIZE)
+            .and_then(|len| base.checked_add(len).map(|_| len))
+            .expect("simulated memory must fit in the address space");
+        let layout = Layout::from_size_align(len, FRAME_SIZE)
+            .expect("simulated memory must fit in the address space");
+        let buf = unsafe { alloc_zeroed(layout) };
+        PhysicalMemory {
+            buf: NonNull::new(buf).expect("could not allocate host buffer"),
+            base,
+            frames,
+        }
+    }
+
+    /// Returns the physical address of the start of the region.
+    #[inline]
+    pub fn start_addr(&self) -> PAddr {
+        PAddr::from(self.base)
+    }
+
+    /// Returns the physical address of the end of the region (exclusive).
+    #[inline]
+    pub fn end_addr(&self) -> PAddr {
+        PAddr::from(self.base + self.size())
+    }
+
+    /// Returns the size of the region, in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.frames * FRAME_SIZE
+    }
+
+    /// Returns the number of frames in the region.
+    #[inline]
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Returns the `n`th frame in the region.
+    ///
+    /// # Panics
+    /// If the region has `n` or fewer frames.
+    pub fn frame(&self, n: usize) -> Frame {
+        assert!(n < self.frames, "frame {} out of range", n);
+        Frame(self.base + n * FRAME_SIZE)
+    }
+
+    /// Returns `true` if the physical address `addr` is in the region.
+    #[inline]
+    pub fn contains(&self, addr: PAddr) -> bool {
+        let addr: usize = addr.into();
+        addr >= self.base && addr - self.base < self.size()
+    }
+
+    /// Returns the offset which, added (with wrapping) to a physical
+    /// address in the region, gives the address of the corresponding byte
+    /// in the host buffer.
+    ///
+    /// This is the simulated equivalent of a kernel's direct mapping of
+    /// physical memory, and may be passed to allocators which need to write
+    /// to the frames they manage.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        (self.buf.as_ptr() as usize).wrapping_sub(self.base)
+    }
+
+    /// Returns a pointer to the byte in the host buffer corresponding to
+    /// the physical address `addr`.
+    ///
+    /// # Panics
+    /// If `addr` is not in the region.
+    pub fn to_virt(&self, addr: PAddr) -> *mut u8 {
+        assert!(self.contains(addr), "{:?} is not in simulated memory", addr);
+        let addr: usize = addr.into();
+        addr.wrapping_add(self.offset()) as *mut u8
+    }
+
+    /// Returns the physical address corresponding to the pointer `ptr`
+    /// into the host buffer.
+    ///
+    /// # Panics
+    /// If `ptr` does not point into the host buffer.
+    pub fn to_phys(&self, ptr: *const u8) -> PAddr {
+        let addr = PAddr::from((ptr as usize).wrapping_sub(self.offset()));
+        assert!(self.contains(addr), "{:?} is not in simulated memory", ptr);
+        addr
+    }
+
+    /// Returns a simple frame allocator over this region.
+    pub fn allocator(&self) -> Frames<'_> {
+        Frames {
+            memory: self,
+            allocated: vec![false; self.frames],
+            free: Vec::new(),
+            next: 0,
+        }
+    }
+
+    #[inline]
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.size(), FRAME_SIZE).unwrap()
+    }
+}
+
+impl Drop for PhysicalMemory {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.buf.as_ptr(), self.layout()) }
+    }
+}
+
+impl fmt::Debug for PhysicalMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PhysicalMemory")
+            .field("start_addr", &self.start_addr())
+            .field("end_addr", &self.end_addr())
+            .field("frames", &self.frames)
+            .field("buf", &self.buf)
+            .finish()
+    }
+}
+
+// ===== impl Frames =====
+
+impl<'mem> Frames<'mem> {
+    /// Returns the memory this allocator allocates frames from.
+    #[inline]
+    pub fn memory(&self) -> &'mem PhysicalMemory {
+        self.memory
+    }
+
+    /// Returns the number of frames currently allocated.
+    #[inline]
+    pub fn allocated(&self) -> usize {
+        self.next - self.free.len()
+    }
+
+    /// Returns the index of `frame` in the region, if it is in the region.
+    fn index_of(&self, frame: &Frame) -> Option<usize> {
+        if self.memory.contains(frame.base()) {
+            Some((frame.0 - self.memory.base) / FRAME_SIZE)
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl<'mem> frame::Allocator for Frames<'mem> {
+    type Frame = Frame;
+
+    unsafe fn alloc(&mut self) -> Result<Frame, AllocErr> {
+        let idx = match self.free.pop() {
+            Some(idx) => idx,
+            None if self.next < self.memory.frames => {
+                self.next += 1;
+                self.next - 1
+            },
+            None => return Err(AllocErr),
+        };
+        self.allocated[idx] = true;
+        Ok(self.memory.frame(idx))
+    }
+
+    unsafe fn dealloc(&mut self, frame: Frame) -> Result<(), AllocErr> {
+        let idx = self.index_of(&frame).ok_or(AllocErr)?;
+        if !self.allocated[idx] {
+            return Err(AllocErr);
+        }
+        self.allocated[idx] = false;
+        self.free.push(idx);
+        Ok(())
+    }
+}
+
+/// Returns a sample memory map of `memory`, for testing allocators which are
+/// initialized from one.
+///
+/// The map describes [`SAMPLE_MAP_FRAMES`] frames, numbered from the start
+/// of `memory`:
+///
+/// - Frames 0 to 29 are usable.
+/// - A kernel image is loaded from the start of frame 1 to halfway through
+///   frame 3, so frames 1, 2 and 3 are unavailable. The first frame after
+///   it is [`SAMPLE_MAP_AFTER_KERNEL`].
+/// - Frames 30 and 31 are a hole, missing from the map.
+/// - Frames 32 to 63 ([`SAMPLE_MAP_SPLIT`]) are usable, but are reported as
+///   two adjacent regions of 16 frames, as some firmware does.
+///
+/// # Panics
+/// If `memory` does not have exactly `SAMPLE_MAP_FRAMES` frames.
+///
+/// [`SAMPLE_MAP_FRAMES`]: constant.SAMPLE_MAP_FRAMES.html
+/// [`SAMPLE_MAP_AFTER_KERNEL`]: constant.SAMPLE_MAP_AFTER_KERNEL.html
+/// [`SAMPLE_MAP_SPLIT`]: constant.SAMPLE_MAP_SPLIT.html
+pub fn sample_memory_map(memory: &PhysicalMemory) -> Vec<Region> {
+    assert_eq!(
+        memory.frames(),
+        SAMPLE_MAP_FRAMES,
+        "the sample memory map describes {} frames",
+        SAMPLE_MAP_FRAMES
+    );
+    let frame = |n: usize| memory.base + n * FRAME_SIZE;
+    let split = SAMPLE_MAP_SPLIT;
+    let half = (split.end - split.start) / 2;
+    vec![
+        Region::new(frame(0), 30 * FRAME_SIZE, RegionKind::Usable),
+        Region::new(
+            frame(1),
+            2 * FRAME_SIZE + FRAME_SIZE / 2,
+            RegionKind::KernelImage,
+        ),
+        Region::new(frame(split.start), half * FRAME_SIZE, RegionKind::Usable),
+        Region::new(
+            frame(split.start + half),
+            half * FRAME_SIZE,
+            RegionKind::Usable,
+        ),
+    ]
+}
+
+/// Allocate frames from `frames` until it runs out.
+///
+/// # Returns
+/// Every frame that was allocated, in address order.
+pub fn drain<A>(frames: &mut A) -> Vec<Frame>
+where
+    A: frame::Allocator<Frame = Frame>,
+{
+    let mut drained = Vec::new();
+    while let Ok(frame) = unsafe { frames.alloc() } {
+        drained.push(frame);
+    }
+    drained.sort();
+    drained
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FrameAllocator;
+
+    #[test]
+    fn frames_have_physical_addresses() {
+        let memory = PhysicalMemory::with_base(0x20_0000, 4);
+        assert_eq!(memory.frame(0).base(), PAddr::from(0x20_0000));
+        assert_eq!(memory.frame(3).number(), (0x20_0000 >> FRAME_SHIFT) + 3);
+        assert_eq!(memory.end_addr(), PAddr::from(0x20_0000 + 4 * FRAME_SIZE));
+        assert!(memory.contains(memory.frame(3).base()));
+        assert!(!memory.contains(memory.end_addr()));
+    }
+
+    #[test]
+    fn physical_addresses_map_to_host_memory() {
+        let memory = PhysicalMemory::new(2);
+        let frame = memory.frame(1);
+        let ptr = memory.to_virt(frame.base()) as *mut u64;
+        unsafe {
+            assert_eq!(*ptr, 0, "simulated memory must start zeroed");
+            *ptr = 0xdead_beef;
+        }
+        assert_eq!(memory.to_phys(ptr as *const u8), frame.base());
+        let addr: usize = frame.base().into();
+        let offset_ptr = addr.wrapping_add(memory.offset()) as *const u64;
+        assert_eq!(unsafe { *offset_ptr }, 0xdead_beef);
+    }
+
+    #[test]
+    fn allocator_hands_out_every_frame() {
+        let memory = PhysicalMemory::new(4);
+        let mut frames = memory.allocator();
+        let all: Vec<_> =
+            (0..4).map(|_| unsafe { frames.alloc().unwrap() }).collect();
+        assert!(unsafe { frames.alloc() }.is_err());
+        assert_eq!(frames.allocated(), 4);
+        for (n, frame) in all.iter().enumerate() {
+            assert_eq!(*frame, memory.frame(n));
+        }
+
+        unsafe {
+            frames.dealloc(all[2]).unwrap();
+            assert!(frames.dealloc(all[2]).is_err(), "double free");
+            assert_eq!(frames.alloc().unwrap(), all[2]);
+        }
+    }
+
+    #[test]
+    fn allocator_rejects_foreign_frames() {
+        let memory = PhysicalMemory::new(1);
+        let mut frames = memory.allocator();
+        let foreign = Frame::containing(memory.end_addr());
+        assert!(unsafe { frames.dealloc(foreign) }.is_err());
+    }
+}
@@ -0,0 +1,579 @@
+//! A frame allocator which tracks frames with one bit per frame.
+//!
+//! A [`BitmapAllocator`] is the simplest useful frame allocator: it keeps a
+//! bitmap with one bit for each frame in the region of physical memory it
+//! manages, where a set bit marks an allocated frame. Frames are found with
+//! a _next-fit_ search, which resumes where the previous search left off,
+//! so that repeated allocations don't rescan the start of the region.
+//!
+//! The bitmap is stored in memory supplied by the caller, so the allocator
+//! may be used before any heap exists. It may even be stored in the first
+//! frames of the region it manages; see [`BitmapAllocator::from_region`].
+//!
+//! [`BitmapAllocator`]: struct.BitmapAllocator.html
+//! [`BitmapAllocator::from_region`]:
+//! struct.BitmapAllocator.html#method.from_region
+use alarm_base::{
+    memory_map::{Available, Region},
+    Constraints,
+    FrameAllocator,
+    FrameRange,
+    HugeAllocator,
+};
+use core::{alloc::AllocErr, cmp, fmt, marker::PhantomData, mem, slice};
+use hal9000::mem::Page;
+
+/// The number of frames tracked by each word of a bitmap.
+const BITS: usize = mem::size_of::<usize>() * 8;
+
+/// A frame allocator which tracks a contiguous region of frames with a
+/// bitmap.
+///
+/// # Type Parameters
+/// - `'map`: the lifetime of the memory holding the bitmap.
+/// - `F`: the type of frames handed out by this allocator.
+pub struct BitmapAllocator<'map, F> {
+    /// One bit per frame; set bits mark allocated frames.
+    ///
+    /// Bits past the end of the region are always set, so they are never
+    /// allocated.
+    map: &'map mut [usize],
+
+    /// The base address of the first frame in the region.
+    base: usize,
+
+    /// The number of frames in the region.
+    frames: usize,
+
+    /// The number of frames which are currently free.
+    free: usize,
+
+    /// The index of the frame at which the next search will start.
+    next: usize,
+
+    _frame: PhantomData<fn() -> F>,
+}
+
+// ===== impl BitmapAllocator =====
+
+impl<'map, F> BitmapAllocator<'map, F>
+where
+    F: Page,
+{
+    /// Returns the number of words of bitmap needed to track `frames`
+    /// frames.
+    #[inline]
+    pub const fn map_words(frames: usize) -> usize {
+        (frames + BITS - 1) / BITS
+    }
+
+    /// Returns a new `BitmapAllocator` managing `frames` frames starting at
+    /// `start`, all of which are initially free.
+    ///
+    /// The bitmap is stored in `map`, which must be at least
+    /// [`map_words(frames)`] words long. Any previous contents of `map` are
+    /// overwritten.
+    ///
+    /// # Panics
+    /// If `map` is too short to track `frames` frames.
+    ///
+    /// [`map_words(frames)`]: #method.map_words
+    pub fn new(start: F, frames: usize, map: &'map mut [usize]) -> Self {
+        let words = Self::map_words(frames);
+        assert!(
+            map.len() >= words,
+            "a bitmap for {} frames needs {} words, but only {} were given",
+            frames,
+            words,
+            map.len(),
+        );
+        let map = &mut map[..words];
+        for word in map.iter_mut() {
+            *word = 0;
+        }
+        if frames % BITS != 0 {
+            map[words - 1] = !0 << (frames % BITS);
+        }
+        BitmapAllocator {
+            map,
+            base: start.base().into(),
+            frames,
+            free: frames,
+            next: 0,
+            _frame: PhantomData,
+        }
+    }
+
+    /// Returns a new `BitmapAllocator` managing `frames` frames starting at
+    /// `start`, which stores its bitmap in the first frames of that region.
+    ///
+    /// The frames holding the bitmap are marked as allocated, and are never
+    /// handed out.
+    ///
+    /// # Arguments
+    /// - `start`: the first frame of the region.
+    /// - `frames`: the number of frames in the region.
+    /// - `phys_offset`: the offset which, added to a physical address in the
+    ///   region, gives the virtual address at which that memory is mapped. For
+    ///   identity-mapped memory, this is zero.
+    ///
+    /// # Returns
+    /// - `Ok(BitmapAllocator)` if the region was large enough to hold the
+    ///   bitmap.
+    /// - `Err(AllocErr)` if the bitmap would fill the entire region.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// region is mapped at `phys_offset`, that it is not in use, and that
+    /// nothing else will access it for the lifetime `'map`.
+    pub unsafe fn from_region(
+        start: F,
+        frames: usize,
+        phys_offset: usize,
+    ) -> Result<Self, AllocErr> {
+        let words = Self::map_words(frames);
+        let map_frames =
+            (words * mem::size_of::<usize>() + F::SIZE - 1) / F::SIZE;
+        if map_frames >= frames {
+            return Err(AllocErr);
+        }
+        let base: usize = start.base().into();
+        let ptr = base.wrapping_add(phys_offset) as *mut usize;
+        let map = slice::from_raw_parts_mut(ptr, words);
+        let mut alloc = Self::new(start, frames, map);
+        for idx in 0..map_frames {
+            alloc.set(idx);
+        }
+        alloc.free -= map_frames;
+        alloc.next = map_frames;
+        Ok(alloc)
+    }
+
+    /// Returns a new `BitmapAllocator` managing the frames which a firmware
+    /// memory map describes as available, which stores its bitmap in the
+    /// first available range of frames large enough to hold it.
+    ///
+    /// The allocator manages every frame from the first available frame to
+    /// the last, but only the [`Available`] frames are ever handed out, so
+    /// reserved regions and the kernel image are never allocated. Frames in
+    /// the gaps between usable regions are marked as allocated.
+    ///
+    /// # Arguments
+    /// - `regions`: the regions in the memory map. The kernel image, and any
+    ///   other memory in use, should be included as regions which are not
+    ///   `Usable`.
+    /// - `phys_offset`: the offset which, added to a physical address in the
+    ///   region, gives the virtual address at which that memory is mapped. For
+    ///   identity-mapped memory, this is zero.
+    ///
+    /// # Returns
+    /// - `Ok(BitmapAllocator)` if the memory map has enough available frames
+    ///   to hold the bitmap.
+    /// - `Err(AllocErr)` if it does not.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// memory map is accurate, that the available frames are mapped at
+    /// `phys_offset`, and that nothing else will access them for the
+    /// lifetime `'map`.
+    ///
+    /// [`Available`]: ../../alarm_base/memory_map/struct.Available.html
+    pub unsafe fn from_memory_map<I>(
+        regions: I,
+        phys_offset: usize,
+    ) -> Result<Self, AllocErr>
+    where
+        I: IntoIterator<Item = Region>,
+        I::IntoIter: Clone,
+    {
+        let available = Available::<F, _>::new(regions);
+        let span = available.span().ok_or(AllocErr)?;
+        let frames = span.len();
+        let words = Self::map_words(frames);
+        let map_frames =
+            (words * mem::size_of::<usize>() + F::SIZE - 1) / F::SIZE;
+        let home = available
+            .clone()
+            .find(|range| range.len() > map_frames)
+            .ok_or(AllocErr)?;
+        let base: usize = home.start_address().into();
+        let ptr = base.wrapping_add(phys_offset) as *mut usize;
+        let map = slice::from_raw_parts_mut(ptr, words);
+        let mut alloc = Self::new(span.start(), frames, map);
+        for word in alloc.map.iter_mut() {
+            *word = !0;
+        }
+        alloc.free = 0;
+        for range in available {
+            let range = if range == home {
+                range.split_at(map_frames).1
+            } else {
+                range
+            };
+            let idx = alloc.index_of(&range.start()).ok_or(AllocErr)?;
+            for i in idx..idx + range.len() {
+                alloc.clear(i);
+            }
+            alloc.free += range.len();
+        }
+        Ok(alloc)
+    }
+
+    /// Returns the total number of frames managed by this allocator.
+    #[inline]
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Returns the number of frames which are currently free.
+    #[inline]
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Returns `true` if `frame` is currently allocated.
+    ///
+    /// Frames outside the region managed by this allocator are never
+    /// allocated.
+    pub fn is_allocated(&self, frame: &F) -> bool {
+        self.index_of(frame)
+            .map(|idx| self.get(idx))
+            .unwrap_or(false)
+    }
+
+    /// Returns the index of the first free frame at or after `self.next`,
+    /// wrapping around to the start of the region if necessary.
+    fn find_free(&self) -> Option<usize> {
+        if self.free == 0 {
+            return None;
+        }
+        let words = self.map.len();
+        let start = self.next / BITS;
+        let first = !self.map[start] & (!0 << (self.next % BITS));
+        if first != 0 {
+            return Some(start * BITS + first.trailing_zeros() as usize);
+        }
+        // Check every other word, and finally the bits of the first word
+        // which came before `self.next`.
+        (1..=words)
+            .map(|i| (start + i) % words)
+            .find(|&word| self.map[word] != !0)
+            .map(|word| {
+                word * BITS + (!self.map[word]).trailing_zeros() as usize
+            })
+    }
+
+    /// Returns the index of the first run of `count` free frames which
+    /// starts at a frame number aligned to `align` frames, and ends at or
+    /// before index `limit`.
+    fn find_run(
+        &self,
+        count: usize,
+        align: usize,
+        limit: usize,
+    ) -> Option<usize> {
+        let first = self.base / F::SIZE;
+        let align_up =
+            |idx: usize| ((first + idx + align - 1) & !(align - 1)) - first;
+        let mut idx = align_up(0);
+        while idx.checked_add(count)? <= limit {
+            // Check the run from the end, so that if it is interrupted, the
+            // search can skip past the last allocated frame in the run.
+            match (idx..idx + count).rev().find(|&i| self.get(i)) {
+                None => return Some(idx),
+                Some(used) => idx = align_up(used + 1),
+            }
+        }
+        None
+    }
+
+    /// Returns the index of `frame` in the region, if it is in the region.
+    fn index_of(&self, frame: &F) -> Option<usize> {
+        let base: usize = frame.base().into();
+        let offset = base.checked_sub(self.base)?;
+        if offset % F::SIZE != 0 || offset / F::SIZE >= self.frames {
+            return None;
+        }
+        Some(offset / F::SIZE)
+    }
+
+    /// Returns the frame at index `idx` in the region.
+    #[inline]
+    fn frame(&self, idx: usize) -> F {
+        F::containing((self.base + idx * F::SIZE).into())
+    }
+
+    #[inline]
+    fn get(&self, idx: usize) -> bool {
+        self.map[idx / BITS] & (1 << (idx % BITS)) != 0
+    }
+
+    #[inline]
+    fn set(&mut self, idx: usize) {
+        self.map[idx / BITS] |= 1 << (idx % BITS);
+    }
+
+    #[inline]
+    fn clear(&mut self, idx: usize) {
+        self.map[idx / BITS] &= !(1 << (idx % BITS));
+    }
+}
+
+unsafe impl<'map, F> FrameAllocator for BitmapAllocator<'map, F>
+where
+    F: Page,
+{
+    type Frame = F;
+
+    unsafe fn alloc(&mut self) -> Result<F, AllocErr> {
+        let idx = self.find_free().ok_or(AllocErr)?;
+        self.set(idx);
+        self.free -= 1;
+        self.next = (idx + 1) % self.frames;
+        Ok(self.frame(idx))
+    }
+
+    unsafe fn dealloc(&mut self, frame: F) -> Result<(), AllocErr> {
+        let idx = self.index_of(&frame).ok_or(AllocErr)?;
+        if !self.get(idx) {
+            return Err(AllocErr);
+        }
+        self.clear(idx);
+        self.free += 1;
+        Ok(())
+    }
+
+    /// Returns a range of `count` contiguous frames.
+    ///
+    /// Unlike single frames, ranges are found with a first-fit search, so
+    /// that they are carved from the lowest addresses possible.
+    #[inline]
+    unsafe fn alloc_range(
+        &mut self,
+        count: usize,
+    ) -> Result<FrameRange<F>, AllocErr> {
+        self.alloc_constrained(Constraints::new(count, 1, usize::max_value()))
+    }
+
+    /// Returns a range of contiguous frames satisfying `constraints`, found
+    /// with a first-fit search.
+    unsafe fn alloc_constrained(
+        &mut self,
+        constraints: Constraints,
+    ) -> Result<FrameRange<F>, AllocErr> {
+        let count = constraints.count();
+        if count == 0 || count > self.free {
+            return Err(AllocErr);
+        }
+        let align = cmp::max(constraints.align() / F::SIZE, 1);
+        let limit =
+            cmp::min(self.frames, constraints.frames_below(&self.frame(0)));
+        let idx = self.find_run(count, align, limit).ok_or(AllocErr)?;
+        for i in idx..idx + count {
+            self.set(i);
+        }
+        self.free -= count;
+        Ok(FrameRange::new(self.frame(idx), count))
+    }
+}
+
+/// Huge pages are allocated as aligned ranges of frames.
+unsafe impl<'map, F, P> HugeAllocator<P> for BitmapAllocator<'map, F>
+where
+    F: Page,
+    P: Page,
+{
+}
+
+impl<'map, F> fmt::Debug for BitmapAllocator<'map, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BitmapAllocator")
+            .field("base", &(self.base as *const u8))
+            .field("frames", &self.frames)
+            .field("free", &self.free)
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alarm_base::{
+        memory_map::RegionKind,
+        sim::{self, Frame, HugeFrame, PhysicalMemory, FRAME_SIZE},
+    };
+    use std::{vec, vec::Vec};
+
+    type Bitmap<'map> = BitmapAllocator<'map, Frame>;
+
+    #[test]
+    fn allocates_every_frame_once() {
+        let memory = PhysicalMemory::new(70);
+        let mut map = [0; 2];
+        let mut frames = Bitmap::new(memory.frame(0), 70, &mut map);
+        let mut all: Vec<_> = (0..70)
+            .map(|_| unsafe { frames.alloc().unwrap() })
+            .collect();
+        assert!(unsafe { frames.alloc() }.is_err());
+        assert_eq!(frames.free_frames(), 0);
+
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 70);
+        assert!(all.iter().all(|frame| memory.contains(frame.base())));
+    }
+
+    #[test]
+    fn search_is_next_fit() {
+        let memory = PhysicalMemory::new(8);
+        let mut map = [0; 1];
+        let mut frames = Bitmap::new(memory.frame(0), 8, &mut map);
+        let a = unsafe { frames.alloc().unwrap() };
+        let b = unsafe { frames.alloc().unwrap() };
+        unsafe { frames.dealloc(a).unwrap() };
+
+        // The next search starts after `b`, rather than reusing `a`...
+        let c = unsafe { frames.alloc().unwrap() };
+        assert_eq!(c, memory.frame(2));
+        for _ in 3..8 {
+            unsafe { frames.alloc().unwrap() };
+        }
+        // ...until it wraps around to the start of the region.
+        assert_eq!(unsafe { frames.alloc().unwrap() }, a);
+        assert!(frames.is_allocated(&b));
+    }
+
+    #[test]
+    fn bad_frees_rejected() {
+        let memory = PhysicalMemory::new(4);
+        let mut map = [0; 1];
+        let mut frames = Bitmap::new(memory.frame(1), 2, &mut map);
+        let frame = unsafe { frames.alloc().unwrap() };
+        unsafe {
+            assert!(frames.dealloc(memory.frame(0)).is_err());
+            assert!(frames.dealloc(memory.frame(3)).is_err());
+            assert!(frames.dealloc(memory.frame(2)).is_err());
+            frames.dealloc(frame).unwrap();
+            assert!(frames.dealloc(frame).is_err(), "double free");
+        }
+        assert_eq!(frames.free_frames(), 2);
+    }
+
+    #[test]
+    fn ranges_found_first_fit() {
+        let memory = PhysicalMemory::new(8);
+        let mut map = [0; 1];
+        let mut frames = Bitmap::new(memory.frame(0), 8, &mut map);
+        let single = unsafe { frames.alloc().unwrap() };
+        let a = unsafe { frames.alloc_range(3).unwrap() };
+        assert_eq!(a, FrameRange::new(memory.frame(1), 3));
+        unsafe { frames.dealloc(single).unwrap() };
+
+        // The freed frame at the start of the region is too small for the
+        // range, so the search continues past `a`.
+        let b = unsafe { frames.alloc_range(2).unwrap() };
+        assert_eq!(b, FrameRange::new(memory.frame(4), 2));
+        assert!(unsafe { frames.alloc_range(3) }.is_err());
+        assert_eq!(frames.free_frames(), 3);
+
+        unsafe { frames.dealloc_range(a).unwrap() };
+        assert_eq!(frames.free_frames(), 6);
+    }
+
+    #[test]
+    fn constrained_ranges_aligned_and_bounded() {
+        // 16 frames starting at frame number 258.
+        let memory = PhysicalMemory::with_base(0x10_2000, 16);
+        let mut map = [0; 1];
+        let mut frames = Bitmap::new(memory.frame(0), 16, &mut map);
+        let aligned = Constraints::new(3, 8 * FRAME_SIZE, usize::max_value());
+        let range = unsafe { frames.alloc_constrained(aligned).unwrap() };
+        assert_eq!(range.start(), memory.frame(6), "frame number 264");
+        assert!(aligned.is_satisfied_by(&range));
+
+        // Only the first 6 frames lie below the limit.
+        let limit = 0x10_7fff;
+        let low = Constraints::new(6, FRAME_SIZE, limit);
+        let range = unsafe { frames.alloc_constrained(low).unwrap() };
+        assert_eq!(range, FrameRange::new(memory.frame(0), 6));
+        let low = Constraints::new(1, FRAME_SIZE, limit);
+        assert!(unsafe { frames.alloc_constrained(low) }.is_err());
+        assert_eq!(frames.free_frames(), 7);
+    }
+
+    #[test]
+    fn huge_pages_from_aligned_frames() {
+        let count = HugeFrame::SIZE / FRAME_SIZE;
+        let base = HugeFrame::SIZE - FRAME_SIZE;
+        let memory = PhysicalMemory::with_base(base, count + 1);
+        let mut map = [0; 9];
+        let mut frames = Bitmap::new(memory.frame(0), count + 1, &mut map);
+        let page: HugeFrame = unsafe { frames.alloc_huge().unwrap() };
+        assert_eq!(page.base(), memory.frame(1).base());
+        assert_eq!(frames.free_frames(), 1);
+
+        unsafe { frames.dealloc_huge(page).unwrap() };
+        assert_eq!(frames.free_frames(), count + 1);
+    }
+
+    #[test]
+    fn bitmap_in_managed_region() {
+        // 40000 frames need 5000 bytes of bitmap, which spans two frames.
+        let memory = PhysicalMemory::new(40_000);
+        let mut frames = unsafe {
+            Bitmap::from_region(memory.frame(0), 40_000, memory.offset())
+        }
+        .unwrap();
+        assert_eq!(frames.free_frames(), 40_000 - 2);
+        assert!(frames.is_allocated(&memory.frame(0)));
+        assert!(frames.is_allocated(&memory.frame(1)));
+        assert_eq!(unsafe { frames.alloc().unwrap() }, memory.frame(2));
+    }
+
+    #[test]
+    fn region_too_small_for_bitmap() {
+        let memory = PhysicalMemory::new(1);
+        let frames =
+            unsafe { Bitmap::from_region(memory.frame(0), 1, memory.offset()) };
+        assert!(frames.is_err());
+    }
+
+    #[test]
+    fn initialized_from_memory_map() {
+        let memory = PhysicalMemory::new(sim::SAMPLE_MAP_FRAMES);
+        let regions = sim::sample_memory_map(&memory);
+        let mut frames = unsafe {
+            Bitmap::from_memory_map(regions.clone(), memory.offset())
+        }
+        .unwrap();
+        // Every available frame is free, except the one holding the bitmap,
+        // which is the first frame after the kernel image.
+        let home = memory.frame(sim::SAMPLE_MAP_AFTER_KERNEL);
+        let expected: Vec<Frame> = Available::new(regions)
+            .flatten()
+            .filter(|frame| *frame != home)
+            .collect();
+        assert_eq!(frames.frames(), sim::SAMPLE_MAP_FRAMES);
+        assert_eq!(frames.free_frames(), expected.len());
+        for n in 0..sim::SAMPLE_MAP_FRAMES {
+            let frame = memory.frame(n);
+            assert_eq!(frames.is_allocated(&frame), !expected.contains(&frame));
+        }
+        assert_eq!(sim::drain(&mut frames), expected);
+        assert!(unsafe { frames.alloc() }.is_err());
+    }
+
+    #[test]
+    fn memory_map_without_usable_memory() {
+        let memory = PhysicalMemory::new(4);
+        let regions = vec![Region::new(
+            memory.start_addr().into(),
+            memory.size(),
+            RegionKind::Reserved,
+        )];
+        let frames =
+            unsafe { Bitmap::from_memory_map(regions, memory.offset()) };
+        assert!(frames.is_err());
+    }
+}
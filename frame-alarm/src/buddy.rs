@@ -0,0 +1,834 @@
+//! A binary buddy frame allocator.
+//!
+//! A [`BuddyAllocator`] manages physical memory in blocks of `2^order`
+//! contiguous frames, for orders up to [`MAX_ORDER`]. Every block is aligned
+//! to its own size, so each block has exactly one _buddy_: the block of the
+//! same order with which it may be merged into a block of the next order.
+//!
+//! Free blocks are kept on one free list per order. The lists are intrusive
+//! `List`s whose nodes are stored in the first bytes of the free blocks
+//! themselves, so the allocator needs no memory for its free lists. When a
+//! block is allocated from a larger free block, the larger block is split in
+//! half repeatedly, and the unused halves are placed on the free lists for
+//! their orders. When a block is freed, it is merged with its buddy for as
+//! long as the buddy is also free.
+//!
+//! The allocator does keep one byte of state for each frame it manages,
+//! recording whether that frame begins a free or allocated block and the
+//! block's order, so that a block's buddy may be found in constant time and
+//! bad frees may be detected. Like the bitmap of a
+//! [`BitmapAllocator`](../bitmap/struct.BitmapAllocator.html), this state is
+//! stored in memory supplied by the caller.
+//!
+//! [`BuddyAllocator`]: struct.BuddyAllocator.html
+//! [`MAX_ORDER`]: constant.MAX_ORDER.html
+use alarm_base::{
+    memory_map::{Available, Region},
+    Constraints,
+    FrameAllocator,
+    FrameRange,
+    HugeAllocator,
+};
+use core::{
+    alloc::AllocErr,
+    cmp,
+    fmt,
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    ptr::NonNull,
+    slice,
+};
+use hal9000::mem::Page;
+use intruder_alarm::{
+    list::{Linked, Links, List},
+    UnsafeRef,
+};
+
+/// The largest order of block managed by a [`BuddyAllocator`].
+///
+/// With 4 KiB frames, a block of this order is 1 GiB.
+///
+/// [`BuddyAllocator`]: struct.BuddyAllocator.html
+pub const MAX_ORDER: usize = 18;
+
+/// The number of bits in a `usize`.
+const BITS: usize = mem::size_of::<usize>() * 8;
+
+/// State bit marking the first frame of a free block.
+const FREE: u8 = 0x80;
+
+/// State bit marking the first frame of an allocated block.
+const ALLOCATED: u8 = 0x40;
+
+/// A list of free blocks of the same order.
+type FreeList = List<FreeBlock, FreeBlock, UnsafeRef<FreeBlock>>;
+
+/// A binary buddy allocator over a contiguous region of frames.
+///
+/// Frames in the region which are not currently available (such as holes
+/// in physical memory) are treated as permanently allocated; they are never
+/// handed out, and blocks are never merged across them.
+///
+/// # Type Parameters
+/// - `'map`: the lifetime of the memory holding the per-frame state.
+/// - `F`: the type of frames handed out by this allocator.
+pub struct BuddyAllocator<'map, F> {
+    /// Free blocks of each order.
+    free: [FreeList; MAX_ORDER + 1],
+
+    /// One byte of state per frame in the region.
+    ///
+    /// The first frame of each free block is marked `FREE`, and the first
+    /// frame of each allocated block is marked `ALLOCATED`, in both cases
+    /// along with the block's order. All other frames are zero.
+    state: &'map mut [u8],
+
+    /// The base address of the first frame in the region.
+    base: usize,
+
+    /// The offset which, added to a physical address in the region, gives
+    /// the virtual address at which that memory is mapped.
+    phys_offset: usize,
+
+    /// The number of frames which are currently free.
+    free_frames: usize,
+
+    _frame: PhantomData<fn() -> F>,
+}
+
+/// The first bytes of a free block, linking it into a free list.
+#[derive(Debug)]
+struct FreeBlock {
+    links: Links<FreeBlock>,
+}
+
+// ===== impl BuddyAllocator =====
+
+impl<'map, F> BuddyAllocator<'map, F>
+where
+    F: Page,
+{
+    /// Returns a new `BuddyAllocator` managing the `state.len()` frames
+    /// starting at `start`, all of which are initially free.
+    ///
+    /// The region is divided into the largest blocks which are aligned to
+    /// their own size, so if `start` is not aligned to a block of
+    /// `MAX_ORDER`, the first and last blocks will be smaller.
+    ///
+    /// # Arguments
+    /// - `start`: the first frame of the region.
+    /// - `phys_offset`: the offset which, added to a physical address in the
+    ///   region, gives the virtual address at which that memory is mapped. For
+    ///   identity-mapped memory, this is zero.
+    /// - `state`: one byte for each frame in the region. Any previous contents
+    ///   are overwritten.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// region is mapped at `phys_offset`, that it is not in use, and that
+    /// nothing else will access it while this allocator exists.
+    pub unsafe fn new(
+        start: F,
+        phys_offset: usize,
+        state: &'map mut [u8],
+    ) -> Self {
+        let frames = state.len();
+        let mut alloc = Self::empty(start, phys_offset, state);
+        alloc.insert(0, frames);
+        alloc
+    }
+
+    /// Returns a new `BuddyAllocator` managing `frames` frames starting at
+    /// `start`, which stores its per-frame state in the first frames of that
+    /// region.
+    ///
+    /// The frames holding the state are marked as allocated, and are never
+    /// handed out.
+    ///
+    /// # Returns
+    /// - `Ok(BuddyAllocator)` if the region was large enough to hold the
+    ///   allocator's state.
+    /// - `Err(AllocErr)` if the state would fill the entire region.
+    ///
+    /// # Unsafety
+    /// This function is unsafe for the same reasons as [`new`], and because
+    /// the caller must guarantee that nothing else will access the region
+    /// for the lifetime `'map`.
+    ///
+    /// [`new`]: #method.new
+    pub unsafe fn from_region(
+        start: F,
+        frames: usize,
+        phys_offset: usize,
+    ) -> Result<Self, AllocErr> {
+        let state_frames = (frames + F::SIZE - 1) / F::SIZE;
+        if state_frames >= frames {
+            return Err(AllocErr);
+        }
+        let base: usize = start.base().into();
+        let ptr = base.wrapping_add(phys_offset) as *mut u8;
+        let state = slice::from_raw_parts_mut(ptr, frames);
+        let mut alloc = Self::empty(start, phys_offset, state);
+        alloc.insert(state_frames, frames - state_frames);
+        Ok(alloc)
+    }
+
+    /// Returns a new `BuddyAllocator` managing the frames which a firmware
+    /// memory map describes as available, which stores its per-frame state
+    /// in the first available range of frames large enough to hold it.
+    ///
+    /// The allocator manages every frame from the first available frame to
+    /// the last, but only the [`Available`] frames are ever handed out, so
+    /// reserved regions and the kernel image are never allocated. The state
+    /// holds one byte for each frame in that span, including any gaps
+    /// between usable regions.
+    ///
+    /// # Arguments
+    /// - `regions`: the regions in the memory map. The kernel image, and any
+    ///   other memory in use, should be included as regions which are not
+    ///   `Usable`.
+    /// - `phys_offset`: the offset which, added to a physical address in the
+    ///   region, gives the virtual address at which that memory is mapped. For
+    ///   identity-mapped memory, this is zero.
+    ///
+    /// # Returns
+    /// - `Ok(BuddyAllocator)` if the memory map has enough available frames
+    ///   to hold the allocator's state.
+    /// - `Err(AllocErr)` if it does not.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// memory map is accurate, that the available frames are mapped at
+    /// `phys_offset`, and that nothing else will access them for the
+    /// lifetime `'map`.
+    ///
+    /// [`Available`]: ../../alarm_base/memory_map/struct.Available.html
+    pub unsafe fn from_memory_map<I>(
+        regions: I,
+        phys_offset: usize,
+    ) -> Result<Self, AllocErr>
+    where
+        I: IntoIterator<Item = Region>,
+        I::IntoIter: Clone,
+    {
+        let available = Available::<F, _>::new(regions);
+        let span = available.span().ok_or(AllocErr)?;
+        let frames = span.len();
+        let state_frames = (frames + F::SIZE - 1) / F::SIZE;
+        let home = available
+            .clone()
+            .find(|range| range.len() > state_frames)
+            .ok_or(AllocErr)?;
+        let base: usize = home.start_address().into();
+        let ptr = base.wrapping_add(phys_offset) as *mut u8;
+        let state = slice::from_raw_parts_mut(ptr, frames);
+        let mut alloc = Self::empty(span.start(), phys_offset, state);
+        for range in available {
+            let range = if range == home {
+                range.split_at(state_frames).1
+            } else {
+                range
+            };
+            let idx = alloc.index_of(&range.start()).ok_or(AllocErr)?;
+            alloc.insert(idx, range.len());
+        }
+        Ok(alloc)
+    }
+
+    /// Returns a new `BuddyAllocator` in which every frame is unavailable.
+    unsafe fn empty(
+        start: F,
+        phys_offset: usize,
+        state: &'map mut [u8],
+    ) -> Self {
+        for byte in state.iter_mut() {
+            *byte = 0;
+        }
+        BuddyAllocator {
+            free: Self::empty_lists(),
+            state,
+            base: start.base().into(),
+            phys_offset,
+            free_frames: 0,
+            _frame: PhantomData,
+        }
+    }
+
+    /// Returns an empty free list for each order from 0 to `MAX_ORDER`.
+    fn empty_lists() -> [FreeList; MAX_ORDER + 1] {
+        // `FreeList` is not `Copy`, so the lists can't be written as an
+        // array repeat expression; instead, fill in each one in turn.
+        unsafe {
+            let mut lists: [MaybeUninit<FreeList>; MAX_ORDER + 1] =
+                MaybeUninit::uninit().assume_init();
+            for list in &mut lists[..] {
+                *list = MaybeUninit::new(List::new());
+            }
+            mem::transmute(lists)
+        }
+    }
+
+    /// Returns the total number of frames in the region managed by this
+    /// allocator.
+    #[inline]
+    pub fn frames(&self) -> usize {
+        self.state.len()
+    }
+
+    /// Returns the number of frames which are currently free.
+    #[inline]
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of free blocks of the given `order`.
+    ///
+    /// # Panics
+    /// If `order` is greater than `MAX_ORDER`.
+    #[inline]
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free[order].len()
+    }
+
+    /// Allocate a block of `2^order` contiguous frames, aligned to the size
+    /// of the block.
+    ///
+    /// # Returns
+    /// - `Ok(F)`, the first frame of the block.
+    /// - `Err(AllocErr)` if no block of the requested order is free, or if
+    ///   `order` is greater than `MAX_ORDER`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe for the same reasons as
+    /// `FrameAllocator::alloc`.
+    pub unsafe fn alloc_order(&mut self, order: usize) -> Result<F, AllocErr> {
+        if order > MAX_ORDER {
+            return Err(AllocErr);
+        }
+        let idx = self.alloc_block(order, |_| true)?;
+        self.state[idx] = ALLOCATED | order as u8;
+        Ok(self.frame(idx))
+    }
+
+    /// Deallocate a block of `2^order` frames starting at `frame`, merging
+    /// it with its buddies where possible.
+    ///
+    /// # Returns
+    /// - `Ok(())` if the block was freed.
+    /// - `Err(AllocErr)` if `frame` does not begin an allocated block of the
+    ///   given `order`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that no
+    /// references to memory in the block remain.
+    pub unsafe fn dealloc_order(
+        &mut self,
+        frame: F,
+        order: usize,
+    ) -> Result<(), AllocErr> {
+        let idx = self.index_of(&frame).ok_or(AllocErr)?;
+        if order > MAX_ORDER || self.state[idx] != ALLOCATED | order as u8 {
+            return Err(AllocErr);
+        }
+        self.state[idx] = 0;
+        self.free_frames += 1 << order;
+        self.merge(idx, order);
+        Ok(())
+    }
+
+    /// Add the `count` frames starting at index `idx` to the free lists, as
+    /// the largest blocks which are aligned to their size, merging them with
+    /// any free buddies.
+    ///
+    /// The frames must not already be free.
+    unsafe fn insert(&mut self, mut idx: usize, mut count: usize) {
+        self.free_frames += count;
+        while count > 0 {
+            // The largest block which is aligned to its size, and which
+            // does not extend past the end of the range.
+            let align = self.number(idx).trailing_zeros() as usize;
+            let fits = BITS - 1 - count.leading_zeros() as usize;
+            let order = cmp::min(cmp::min(align, fits), MAX_ORDER);
+            self.merge(idx, order);
+            idx += 1 << order;
+            count -= 1 << order;
+        }
+    }
+
+    /// Free the block of `2^order` frames at index `idx`, merging it with
+    /// its buddy for as long as the buddy is also free.
+    unsafe fn merge(&mut self, mut idx: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = match self.buddy_of(idx, order) {
+                Some(buddy) if self.state[buddy] == FREE | order as u8 => buddy,
+                _ => break,
+            };
+            self.free[order].remove_node(self.block(buddy));
+            self.state[buddy] = 0;
+            idx = cmp::min(idx, buddy);
+            order += 1;
+        }
+        self.push(idx, order);
+    }
+
+    /// Returns the index of the buddy of the block of the given `order` at
+    /// index `idx`, if the buddy is in the region.
+    fn buddy_of(&self, idx: usize, order: usize) -> Option<usize> {
+        let buddy =
+            (self.number(idx) ^ (1 << order)).checked_sub(self.number(0))?;
+        if buddy + (1 << order) <= self.frames() {
+            Some(buddy)
+        } else {
+            None
+        }
+    }
+
+    /// Place the block of the given `order` at index `idx` on its free list.
+    unsafe fn push(&mut self, idx: usize, order: usize) {
+        let block = self.block(idx);
+        block.as_ptr().write(FreeBlock {
+            links: Links::new(),
+        });
+        self.free[order].push_front_node(UnsafeRef::from(block));
+        self.state[idx] = FREE | order as u8;
+    }
+
+    /// Remove the first free block of at least the given `order` whose index
+    /// satisfies `fits`, and split it until it is of the given `order`.
+    ///
+    /// The lower half of each split block is kept, so the returned block
+    /// starts at the same index as the free block which was found.
+    ///
+    /// # Returns
+    /// - `Ok(usize)`, the index of the allocated block. Its state is left for
+    ///   the caller to set.
+    /// - `Err(AllocErr)` if no suitable block is free.
+    unsafe fn alloc_block<P>(
+        &mut self,
+        order: usize,
+        fits: P,
+    ) -> Result<usize, AllocErr>
+    where
+        P: Fn(usize) -> bool,
+    {
+        for mut split in order..=MAX_ORDER {
+            let found = self.free[split]
+                .cursor()
+                .map(|block| self.index_of_block(block))
+                .find(|&idx| fits(idx));
+            if let Some(idx) = found {
+                self.free[split].remove_node(self.block(idx));
+                self.state[idx] = 0;
+                while split > order {
+                    split -= 1;
+                    self.push(idx + (1 << split), split);
+                }
+                self.free_frames -= 1 << order;
+                return Ok(idx);
+            }
+        }
+        Err(AllocErr)
+    }
+
+    /// Returns a pointer to the free-list node in the block at index `idx`.
+    #[inline]
+    fn block(&self, idx: usize) -> NonNull<FreeBlock> {
+        let addr = (self.base + idx * F::SIZE).wrapping_add(self.phys_offset);
+        unsafe { NonNull::new_unchecked(addr as *mut FreeBlock) }
+    }
+
+    /// Returns the order of the blocks which make up pages of type `P`, if
+    /// `P` is made up of whole frames.
+    #[inline]
+    fn huge_order<P: Page>() -> Option<usize> {
+        P::SHIFT.checked_sub(F::SHIFT)
+    }
+
+    /// Returns the index of the free block beginning with `block`.
+    #[inline]
+    fn index_of_block(&self, block: &FreeBlock) -> usize {
+        let addr =
+            (block as *const FreeBlock as usize).wrapping_sub(self.phys_offset);
+        (addr - self.base) / F::SIZE
+    }
+
+    /// Returns the index of `frame` in the region, if it is in the region.
+    fn index_of(&self, frame: &F) -> Option<usize> {
+        let base: usize = frame.base().into();
+        let offset = base.checked_sub(self.base)?;
+        if offset % F::SIZE != 0 || offset / F::SIZE >= self.frames() {
+            return None;
+        }
+        Some(offset / F::SIZE)
+    }
+
+    /// Returns the frame number of the frame at index `idx`.
+    #[inline]
+    fn number(&self, idx: usize) -> usize {
+        self.base / F::SIZE + idx
+    }
+
+    /// Returns the frame at index `idx` in the region.
+    #[inline]
+    fn frame(&self, idx: usize) -> F {
+        F::containing((self.base + idx * F::SIZE).into())
+    }
+}
+
+unsafe impl<'map, F> FrameAllocator for BuddyAllocator<'map, F>
+where
+    F: Page,
+{
+    type Frame = F;
+
+    #[inline]
+    unsafe fn alloc(&mut self) -> Result<F, AllocErr> {
+        self.alloc_order(0)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&mut self, frame: F) -> Result<(), AllocErr> {
+        self.dealloc_order(frame, 0)
+    }
+
+    /// Returns a range of `count` contiguous frames.
+    ///
+    /// The range is carved from the smallest block which can hold it, and
+    /// the rest of the block is returned to the free lists. Each frame in
+    /// the range is allocated individually, so the range may be freed with
+    /// `dealloc_range`, or a frame at a time.
+    #[inline]
+    unsafe fn alloc_range(
+        &mut self,
+        count: usize,
+    ) -> Result<FrameRange<F>, AllocErr> {
+        self.alloc_constrained(Constraints::new(count, 1, usize::max_value()))
+    }
+
+    /// Returns a range of contiguous frames satisfying `constraints`.
+    ///
+    /// The range is carved from the first suitable free block found on the
+    /// free lists, starting with the smallest order which can hold it and is
+    /// aligned as required, as in `alloc_range`. Since free blocks are kept
+    /// in LIFO order, this is not necessarily the lowest suitable block.
+    unsafe fn alloc_constrained(
+        &mut self,
+        constraints: Constraints,
+    ) -> Result<FrameRange<F>, AllocErr> {
+        let count = constraints.count();
+        if count == 0 || count > 1 << MAX_ORDER {
+            return Err(AllocErr);
+        }
+        let align = cmp::max(constraints.align() / F::SIZE, 1);
+        let order = cmp::max(
+            BITS - (count - 1).leading_zeros() as usize,
+            align.trailing_zeros() as usize,
+        );
+        if order > MAX_ORDER {
+            return Err(AllocErr);
+        }
+        let limit = constraints.frames_below(&self.frame(0));
+        let idx = self.alloc_block(order, |idx| idx + count <= limit)?;
+        for state in &mut self.state[idx..idx + count] {
+            *state = ALLOCATED;
+        }
+        self.insert(idx + count, (1 << order) - count);
+        Ok(FrameRange::new(self.frame(idx), count))
+    }
+}
+
+/// Huge pages are served directly from the free lists for high orders, so
+/// a `BuddyAllocator` may provide pages of up to `2^MAX_ORDER` frames.
+unsafe impl<'map, F, P> HugeAllocator<P> for BuddyAllocator<'map, F>
+where
+    F: Page,
+    P: Page,
+{
+    unsafe fn alloc_huge(&mut self) -> Result<P, AllocErr> {
+        let order = Self::huge_order::<P>().ok_or(AllocErr)?;
+        let frame = self.alloc_order(order)?;
+        let base: usize = frame.base().into();
+        Ok(P::containing(base.into()))
+    }
+
+    unsafe fn dealloc_huge(&mut self, page: P) -> Result<(), AllocErr> {
+        let order = Self::huge_order::<P>().ok_or(AllocErr)?;
+        let base: usize = page.base().into();
+        self.dealloc_order(F::containing(base.into()), order)
+    }
+}
+
+// A `BuddyAllocator` has exclusive access to its region, including the free
+// blocks its free lists point into.
+unsafe impl<'map, F> Send for BuddyAllocator<'map, F> {}
+
+impl<'map, F> fmt::Debug for BuddyAllocator<'map, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut free_blocks = [0; MAX_ORDER + 1];
+        for (count, list) in free_blocks.iter_mut().zip(self.free.iter()) {
+            *count = list.len();
+        }
+        f.debug_struct("BuddyAllocator")
+            .field("base", &(self.base as *const u8))
+            .field("frames", &self.state.len())
+            .field("free_frames", &self.free_frames)
+            .field("free_blocks", &free_blocks)
+            .finish()
+    }
+}
+
+// ===== impl FreeBlock =====
+
+impl AsRef<FreeBlock> for FreeBlock {
+    #[inline]
+    fn as_ref(&self) -> &Self {
+        self
+    }
+}
+
+impl Linked for FreeBlock {
+    #[inline]
+    fn links(&self) -> &Links<Self> {
+        &self.links
+    }
+
+    #[inline]
+    fn links_mut(&mut self) -> &mut Links<Self> {
+        &mut self.links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alarm_base::sim::{
+        self,
+        Frame,
+        HugeFrame,
+        PhysicalMemory,
+        FRAME_SHIFT,
+        FRAME_SIZE,
+        HUGE_FRAME_SHIFT,
+    };
+    use std::{vec, vec::Vec};
+
+    type Buddy<'map> = BuddyAllocator<'map, Frame>;
+
+    /// Returns a buddy allocator over all of `memory`, with its state on
+    /// the host heap.
+    fn buddy<'map>(
+        memory: &PhysicalMemory,
+        state: &'map mut Vec<u8>,
+    ) -> Buddy<'map> {
+        *state = vec![0; memory.frames()];
+        unsafe { Buddy::new(memory.frame(0), memory.offset(), state) }
+    }
+
+    #[test]
+    fn region_divided_into_aligned_blocks() {
+        // 16 frames starting at frame 3: blocks of 1, 4, 8, 2 and 1 frames.
+        let memory = PhysicalMemory::with_base(3 * FRAME_SIZE, 16);
+        let mut state = Vec::new();
+        let frames = buddy(&memory, &mut state);
+        assert_eq!(frames.free_frames(), 16);
+        assert_eq!(frames.free_blocks(0), 2);
+        assert_eq!(frames.free_blocks(1), 1);
+        assert_eq!(frames.free_blocks(2), 1);
+        assert_eq!(frames.free_blocks(3), 1);
+    }
+
+    #[test]
+    fn blocks_split_on_alloc() {
+        let memory = PhysicalMemory::new(8);
+        let mut state = Vec::new();
+        let mut frames = buddy(&memory, &mut state);
+        assert_eq!(frames.free_blocks(3), 1);
+
+        let a = unsafe { frames.alloc().unwrap() };
+        assert_eq!(a, memory.frame(0));
+        assert_eq!(frames.free_blocks(3), 0);
+        assert_eq!(frames.free_blocks(2), 1);
+        assert_eq!(frames.free_blocks(1), 1);
+        assert_eq!(frames.free_blocks(0), 1);
+
+        let b = unsafe { frames.alloc_order(1).unwrap() };
+        assert_eq!(b, memory.frame(2));
+        assert_eq!(frames.free_frames(), 5);
+    }
+
+    #[test]
+    fn buddies_coalesce_on_free() {
+        let memory = PhysicalMemory::new(8);
+        let mut state = Vec::new();
+        let mut frames = buddy(&memory, &mut state);
+        let all: Vec<_> =
+            (0..8).map(|_| unsafe { frames.alloc().unwrap() }).collect();
+        assert!(unsafe { frames.alloc() }.is_err());
+
+        for frame in all {
+            unsafe { frames.dealloc(frame).unwrap() };
+        }
+        assert_eq!(frames.free_frames(), 8);
+        assert_eq!(frames.free_blocks(3), 1);
+        assert!((0..3).all(|order| frames.free_blocks(order) == 0));
+        assert_eq!(unsafe { frames.alloc_order(3).unwrap() }, memory.frame(0));
+    }
+
+    #[test]
+    fn bad_frees_rejected() {
+        let memory = PhysicalMemory::new(4);
+        let mut state = Vec::new();
+        let mut frames = buddy(&memory, &mut state);
+        let block = unsafe { frames.alloc_order(1).unwrap() };
+        unsafe {
+            assert!(frames.dealloc(block).is_err(), "wrong order");
+            assert!(frames.dealloc(memory.frame(1)).is_err(), "interior");
+            assert!(frames.dealloc(memory.frame(2)).is_err(), "free frame");
+            frames.dealloc_order(block, 1).unwrap();
+            assert!(frames.dealloc_order(block, 1).is_err(), "double free");
+        }
+        assert_eq!(frames.free_blocks(2), 1);
+    }
+
+    #[test]
+    fn blocks_do_not_overlap() {
+        let memory = PhysicalMemory::new(64);
+        let mut state = Vec::new();
+        let mut frames = buddy(&memory, &mut state);
+        let mut blocks = Vec::new();
+        for &order in [0, 2, 1, 0, 3, 0, 1, 2].iter().cycle().take(24) {
+            if let Ok(frame) = unsafe { frames.alloc_order(order) } {
+                // Write to the whole block, to catch blocks which overlap
+                // each other or the allocator's free lists.
+                let ptr = memory.to_virt(frame.base());
+                unsafe { ptr.write_bytes(order as u8, FRAME_SIZE << order) };
+                blocks.push((frame, order));
+            }
+        }
+        for &(frame, order) in &blocks {
+            let ptr = memory.to_virt(frame.base());
+            let bytes =
+                unsafe { slice::from_raw_parts(ptr, FRAME_SIZE << order) };
+            assert!(bytes.iter().all(|&byte| byte == order as u8));
+        }
+        for (frame, order) in blocks {
+            unsafe { frames.dealloc_order(frame, order).unwrap() };
+        }
+        assert_eq!(frames.free_frames(), 64);
+        assert_eq!(frames.free_blocks(6), 1);
+    }
+
+    #[test]
+    fn ranges_carved_from_blocks() {
+        let memory = PhysicalMemory::new(8);
+        let mut state = Vec::new();
+        let mut frames = buddy(&memory, &mut state);
+        let range = unsafe { frames.alloc_range(3).unwrap() };
+        assert_eq!(range, FrameRange::new(memory.frame(0), 3));
+        assert_eq!(frames.free_frames(), 5);
+        // The unused frame of the 4-frame block was returned.
+        assert_eq!(frames.free_blocks(0), 1);
+        assert_eq!(frames.free_blocks(2), 1);
+        assert!(unsafe { frames.alloc_range(6) }.is_err());
+
+        unsafe { frames.dealloc_range(range).unwrap() };
+        assert_eq!(frames.free_blocks(3), 1);
+        assert_eq!(frames.free_frames(), 8);
+    }
+
+    #[test]
+    fn constrained_ranges_aligned_and_bounded() {
+        // 16 frames starting at frame 2: blocks of 2, 4 and 8 frames, and
+        // another 2 frames.
+        let memory = PhysicalMemory::with_base(2 * FRAME_SIZE, 16);
+        let mut state = Vec::new();
+        let mut frames = buddy(&memory, &mut state);
+        let aligned = Constraints::new(3, 8 * FRAME_SIZE, usize::max_value());
+        let range = unsafe { frames.alloc_constrained(aligned).unwrap() };
+        assert_eq!(range.start(), memory.frame(6), "frame number 8");
+        assert!(aligned.is_satisfied_by(&range));
+
+        // Only the first 6 frames are below the limit, and the only free
+        // frames there are in blocks of 2 and 4.
+        let limit = 8 * FRAME_SIZE - 1;
+        let low = Constraints::new(2, FRAME_SIZE, limit);
+        let range = unsafe { frames.alloc_constrained(low).unwrap() };
+        assert!(low.is_satisfied_by(&range));
+        let too_big = Constraints::new(5, FRAME_SIZE, limit);
+        assert!(unsafe { frames.alloc_constrained(too_big) }.is_err());
+        let low_aligned = Constraints::new(1, 8 * FRAME_SIZE, limit);
+        assert!(unsafe { frames.alloc_constrained(low_aligned) }.is_err());
+    }
+
+    #[test]
+    fn huge_pages_from_high_orders() {
+        // Two huge pages, preceded by a frame which is not part of either.
+        let count = HugeFrame::SIZE / FRAME_SIZE;
+        let base = HugeFrame::SIZE - FRAME_SIZE;
+        let memory = PhysicalMemory::with_base(base, 2 * count + 1);
+        let mut state = Vec::new();
+        let mut frames = buddy(&memory, &mut state);
+        let order = HUGE_FRAME_SHIFT - FRAME_SHIFT;
+        assert_eq!(frames.free_blocks(order), 2);
+
+        let page: HugeFrame = unsafe { frames.alloc_huge().unwrap() };
+        let frame = unsafe { frames.alloc().unwrap() };
+        assert_eq!(frame, memory.frame(0), "smaller blocks are used first");
+        let next: HugeFrame = unsafe { frames.alloc_huge().unwrap() };
+        let mut bases = [page.base(), next.base()];
+        bases.sort();
+        assert_eq!(
+            bases,
+            [memory.frame(1).base(), memory.frame(count + 1).base()]
+        );
+        assert!(
+            unsafe { HugeAllocator::<HugeFrame>::alloc_huge(&mut frames) }
+                .is_err()
+        );
+
+        unsafe {
+            assert!(frames.dealloc(memory.frame(1)).is_err(), "wrong order");
+            frames.dealloc_huge(page).unwrap();
+            frames.dealloc_huge(next).unwrap();
+        }
+        // The pages are not buddies, so they are not merged.
+        assert_eq!(frames.free_blocks(order), 2);
+    }
+
+    #[test]
+    fn state_in_managed_region() {
+        let memory = PhysicalMemory::new(16);
+        let mut frames =
+            unsafe { Buddy::from_region(memory.frame(0), 16, memory.offset()) }
+                .unwrap();
+        assert_eq!(frames.free_frames(), 15);
+        assert_eq!(unsafe { frames.alloc().unwrap() }, memory.frame(1));
+        assert!(unsafe { frames.dealloc(memory.frame(0)) }.is_err());
+    }
+
+    #[test]
+    fn initialized_from_memory_map() {
+        let memory = PhysicalMemory::new(sim::SAMPLE_MAP_FRAMES);
+        let regions = sim::sample_memory_map(&memory);
+        let mut frames = unsafe {
+            Buddy::from_memory_map(regions.clone(), memory.offset())
+        }
+        .unwrap();
+        // Every available frame is free, except the one holding the state,
+        // which is the first frame after the kernel image.
+        let home = memory.frame(sim::SAMPLE_MAP_AFTER_KERNEL);
+        let expected: Vec<Frame> = Available::new(regions)
+            .flatten()
+            .filter(|frame| *frame != home)
+            .collect();
+        assert_eq!(frames.frames(), sim::SAMPLE_MAP_FRAMES);
+        assert_eq!(frames.free_frames(), expected.len());
+        // The two usable regions after the hole are merged into one block.
+        let split = sim::SAMPLE_MAP_SPLIT;
+        let order = (split.end - split.start).trailing_zeros() as usize;
+        assert_eq!(frames.free_blocks(order), 1);
+
+        assert_eq!(sim::drain(&mut frames), expected);
+        assert!(unsafe { frames.alloc() }.is_err());
+    }
+}
@@ -0,0 +1,28 @@
+// ••• ALARM: the SOS memory allocator
+// --- by Eliza Weisman (eliza@elizas.website)
+// ••• and the SOS contributors
+//
+//  Copyright (c) 2018 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Physical frame allocators.
+//!
+//! The allocators in this crate implement `alarm_base::frame::Allocator`,
+//! and manage regions of physical memory without requiring a heap, so that
+//! they may be used to bootstrap the rest of the kernel's memory management.
+// Use `no_std` attribute unless we are running tests.
+#![cfg_attr(not(test), no_std)]
+#![deny(missing_docs)]
+#![feature(allocator_api)]
+
+extern crate alarm_base;
+#[cfg(test)]
+extern crate core;
+extern crate hal9000;
+extern crate intruder_alarm;
+
+pub mod bitmap;
+pub mod buddy;
+
+pub use self::{bitmap::BitmapAllocator, buddy::BuddyAllocator};
@@ -17,6 +17,7 @@ use core::{
     marker::PhantomData,
     mem,
     ops::DerefMut,
+    ptr::NonNull,
 };
 
 #[cfg(test)]
@@ -320,6 +321,36 @@ where
             })
         }
     }
+
+    /// Remove an arbitrary node from the list.
+    ///
+    /// Unlike removing a node with a [`CursorMut`], this does not need to
+    /// search the list for the node, so it completes in constant time.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that `node`
+    /// is currently an element of _this_ list.
+    ///
+    /// [`CursorMut`]: struct.CursorMut.html
+    pub unsafe fn remove_node(&mut self, node: NonNull<Node>) -> Ref {
+        let node = node.as_ptr();
+        let links = (*node).take_links();
+        let mut next = links.next;
+        let mut prev = links.prev;
+
+        match next.as_mut() {
+            None => self.tail = prev,
+            Some(next) => next.links_mut().prev = prev,
+        }
+
+        match prev.as_mut() {
+            None => self.head = next,
+            Some(prev) => prev.links_mut().next = next,
+        }
+
+        self.len -= 1;
+        Ref::from_ptr(node as *const Node)
+    }
 }
 
 impl<T, Node, R> List<T, Node, R>
@@ -497,7 +528,7 @@ where
 impl<T> Links<T> {
     /// Returns a new unlinked set of `Links`.
     #[inline]
-    const fn new() -> Self {
+    pub const fn new() -> Self {
         Links {
             next: Link::none(),
             prev: Link::none(),
@@ -854,6 +854,33 @@ mod unsafe_ref {
     // }
 
     quickcheck! {
+        fn remove_arbitrary_node(xs: Vec<usize>, i: usize) -> TestResult {
+            if i >= xs.len() {
+                return TestResult::discard();
+            }
+
+            let mut list = UnsafeList::new();
+            let mut nodes = Vec::new();
+            for x in xs.clone() {
+                let node = UnsafeRef::boxed(NumberedNode::new(x));
+                nodes.push(NonNull::from(&*node));
+                list.push_back_node(node);
+            }
+
+            let mut xs = xs;
+            let removed_item = xs.remove(i);
+            let removed = unsafe { list.remove_node(nodes[i]) };
+            assert_eq!(removed.number, removed_item, "which item was removed?");
+            assert_eq!(list.len(), xs.len());
+
+            let list_contents = list.cursor().map(|&x| x).collect::<Vec<usize>>();
+            assert_eq!(xs, list_contents, "post-removal check");
+            assert_eq!(list.head().map(|n| n.number), xs.first().cloned());
+            assert_eq!(list.tail().map(|n| n.number), xs.last().cloned());
+
+            TestResult::passed()
+        }
+
         fn push_front_node_order(x: usize, xs: Vec<usize>) -> TestResult {
             let mut list = UnsafeList::new();
             list.push_front_node(UnsafeRef::boxed(NumberedNode::new(x)));
@@ -54,6 +54,19 @@ pub struct Stack<T, N, R> {
     _ref_ty: PhantomData<R>,
 }
 
+//  Iter
+/// An iterator over the items in a [`Stack`], from top to bottom.
+///
+/// [`Stack`]: struct.Stack.html
+#[derive(Debug)]
+pub struct Iter<'a, T: 'a, N: 'a> {
+    /// The next node to yield.
+    current: Option<&'a N>,
+
+    /// Type marker for items stored in the stack.
+    _elem_ty: PhantomData<&'a T>,
+}
+
 //  Linked
 /// Trait that must be implemented in order to be a member of an intrusive
 /// linked list.
@@ -173,6 +186,21 @@ where
     }
 }
 
+impl<T, Node, R> Stack<T, Node, R>
+where
+    Node: Linked + AsRef<T>,
+{
+    /// Returns an iterator over the items of this `Stack`, from top to
+    /// bottom.
+    #[inline]
+    pub fn iter<'a>(&'a self) -> Iter<'a, T, Node> {
+        Iter {
+            current: self.top.as_ref(),
+            _elem_ty: PhantomData,
+        }
+    }
+}
+
 impl<T, Node, R> Stack<T, Node, R>
 where
     Node: AsMut<T>,
@@ -270,3 +298,20 @@ where
         stack
     }
 }
+
+// ===== impl Iter =====
+
+impl<'a, T, Node> Iterator for Iter<'a, T, Node>
+where
+    Node: Linked + AsRef<T>,
+{
+    type Item = &'a T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.current.map(|node| {
+            self.current = node.next().as_ref();
+            node.as_ref()
+        })
+    }
+}
@@ -137,6 +137,16 @@ mod boxed {
         }
     }
 
+    quickcheck! {
+        fn iter_top_to_bottom(xs: Vec<usize>) -> bool {
+            let mut list = NumberedList::new();
+            for &x in &xs {
+                list.push(x);
+            }
+            list.iter().cloned().eq(xs.into_iter().rev())
+        }
+    }
+
     #[test]
     fn contents_after_push_nodes() {
         let mut list = NumberedList::new();
@@ -0,0 +1,361 @@
+//! Owning handles on slab-allocated objects.
+//!
+//! A [`SlabBox`] owns an object allocated from a slab cache, much like a
+//! `Box` owns an object allocated from the heap, or an `alarm_base`
+//! `Borrowed` handle owns an allocation from an `Alloc`. When a `SlabBox`
+//! is dropped, its object is freed to the cache it was allocated from.
+//!
+//! A `SlabBox` finds its way back to its cache through an [`ObjectCache`]
+//! handle. Handles to statically-allocated caches may be zero-sized types
+//! implementing `Default`, in which case `SlabBox` also implements
+//! `OwningRef`, and slab-allocated objects may be placed directly into
+//! intrusive collections.
+//!
+//! [`SlabBox`]: struct.SlabBox.html
+//! [`ObjectCache`]: trait.ObjectCache.html
+use super::{cache::Cache, off_slab::OffSlabCache, SlabRef};
+use alarm_base::{FrameAllocator, LockedAlloc};
+use core::{
+    alloc::AllocErr,
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+use intruder_alarm::OwningRef;
+
+/// A handle to a cache of objects of type `T`.
+///
+/// # Unsafety
+/// This trait is unsafe to implement because every clone of a handle (and,
+/// if the handle implements `Default`, every default handle) must refer to
+/// the same cache, so that objects are always freed to the cache they were
+/// allocated from.
+pub unsafe trait ObjectCache<T> {
+    /// Allocate an object from the cache, and move `value` into it.
+    fn alloc(&self, value: T) -> Result<SlabRef<T>, AllocErr>;
+
+    /// Free an object to the cache.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that
+    /// `slab_ref` was allocated from this cache.
+    unsafe fn free(&self, slab_ref: SlabRef<T>);
+}
+
+/// An owning handle on an object allocated from a slab cache.
+///
+/// When a `SlabBox` is dropped, its object is freed to its cache. As when
+/// freeing an object directly, this runs `T`'s destructor, unless the cache
+/// keeps objects in their constructed state.
+///
+/// # Type Parameters
+/// - `T`: the type of the allocated object.
+/// - `C`: the type of the handle to the cache from which the object was
+///   allocated.
+pub struct SlabBox<T, C>
+where
+    C: ObjectCache<T>,
+{
+    /// The object this `SlabBox` owns.
+    slab_ref: Option<SlabRef<T>>,
+
+    /// A handle to the cache which provided the object.
+    cache: C,
+}
+
+// ===== impl ObjectCache =====
+
+unsafe impl<T, A> ObjectCache<T> for &LockedAlloc<Cache<T, A>>
+where
+    A: FrameAllocator,
+{
+    #[inline]
+    fn alloc(&self, value: T) -> Result<SlabRef<T>, AllocErr> {
+        self.lock().alloc(value)
+    }
+
+    #[inline]
+    unsafe fn free(&self, slab_ref: SlabRef<T>) {
+        self.lock().free(slab_ref)
+    }
+}
+
+unsafe impl<T, A> ObjectCache<T> for &LockedAlloc<OffSlabCache<T, A>>
+where
+    A: FrameAllocator + Clone,
+{
+    #[inline]
+    fn alloc(&self, value: T) -> Result<SlabRef<T>, AllocErr> {
+        self.lock().alloc(value)
+    }
+
+    #[inline]
+    unsafe fn free(&self, slab_ref: SlabRef<T>) {
+        self.lock().free(slab_ref)
+    }
+}
+
+// ===== impl SlabBox =====
+
+impl<T, C> SlabBox<T, C>
+where
+    C: ObjectCache<T>,
+{
+    /// Allocate an object from `cache` and move `value` into it.
+    ///
+    /// # Returns
+    /// - `Ok(SlabBox)` owning the allocated object.
+    /// - `Err(AllocErr)` if the cache could not allocate an object.
+    pub fn new(value: T, cache: C) -> Result<Self, AllocErr> {
+        let slab_ref = cache.alloc(value)?;
+        Ok(SlabBox {
+            slab_ref: Some(slab_ref),
+            cache,
+        })
+    }
+
+    /// Returns a new `SlabBox` owning an object referenced by `slab_ref`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that
+    /// `slab_ref` was allocated from `cache`.
+    pub unsafe fn from_slab_ref(slab_ref: SlabRef<T>, cache: C) -> Self {
+        SlabBox {
+            slab_ref: Some(slab_ref),
+            cache,
+        }
+    }
+
+    /// Consume this `SlabBox`, returning a reference to its object _without_
+    /// freeing it.
+    pub fn into_slab_ref(mut self) -> SlabRef<T> {
+        self.slab_ref.take().expect("SlabBox must own an object")
+    }
+
+    /// Returns a raw pointer to the owned object.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut T {
+        self.slab_ref().as_ptr()
+    }
+
+    /// Borrow the handle to the cache which provided this `SlabBox`'s
+    /// object.
+    #[inline]
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    #[inline]
+    fn slab_ref(&self) -> &SlabRef<T> {
+        self.slab_ref.as_ref().expect("SlabBox must own an object")
+    }
+}
+
+impl<T, C> Deref for SlabBox<T, C>
+where
+    C: ObjectCache<T>,
+{
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.slab_ref()
+    }
+}
+
+impl<T, C> DerefMut for SlabBox<T, C>
+where
+    C: ObjectCache<T>,
+{
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.slab_ref.as_mut().expect("SlabBox must own an object")
+    }
+}
+
+impl<T, C> Drop for SlabBox<T, C>
+where
+    C: ObjectCache<T>,
+{
+    fn drop(&mut self) {
+        if let Some(slab_ref) = self.slab_ref.take() {
+            unsafe { self.cache.free(slab_ref) }
+        }
+    }
+}
+
+unsafe impl<T, C> OwningRef<T> for SlabBox<T, C>
+where
+    C: ObjectCache<T> + Default,
+{
+    #[inline]
+    fn into_ptr(self) -> *const T {
+        // The `SlabBox` will be reconstructed by `from_ptr`, so don't free
+        // the object.
+        self.into_slab_ref().as_ptr()
+    }
+
+    #[inline]
+    unsafe fn from_ptr(p: *const T) -> Self {
+        let ptr = NonNull::new(p as *mut T)
+            .expect("attempted to create OwningRef from null pointer!");
+        SlabBox::from_slab_ref(SlabRef { ptr }, C::default())
+    }
+}
+
+impl<T, C> fmt::Debug for SlabBox<T, C>
+where
+    T: fmt::Debug,
+    C: ObjectCache<T>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestFrames;
+    use intruder_alarm::{
+        list::{Linked, Links, List},
+        stack::{self, Stack},
+        Link,
+    };
+    use std::{rc::Rc, vec::Vec};
+
+    type TestCache<T> = LockedAlloc<Cache<T, TestFrames>>;
+
+    #[test]
+    fn drop_returns_object_to_cache() {
+        let cache: TestCache<u64> =
+            LockedAlloc::new(unsafe { Cache::new(TestFrames::new(1)) });
+        let a = SlabBox::new(1u64, &cache).unwrap();
+        let b = SlabBox::new(2u64, &cache).unwrap();
+        assert_eq!(*a + *b, 3);
+        assert_eq!(cache.lock().partial_pages(), 1);
+
+        drop(a);
+        drop(b);
+        assert_eq!(cache.lock().partial_pages(), 0);
+        assert_eq!(cache.lock().empty_pages(), 1);
+    }
+
+    #[test]
+    fn drop_runs_destructor() {
+        let cache: TestCache<Rc<()>> =
+            LockedAlloc::new(unsafe { Cache::new(TestFrames::new(1)) });
+        let value = Rc::new(());
+        let mut boxed = SlabBox::new(value.clone(), &cache).unwrap();
+        assert_eq!(Rc::strong_count(&value), 2);
+
+        *boxed = Rc::new(());
+        assert_eq!(Rc::strong_count(&value), 1);
+        let inner = (*boxed).clone();
+        drop(boxed);
+        assert_eq!(Rc::strong_count(&inner), 1);
+    }
+
+    #[test]
+    fn into_slab_ref_does_not_free() {
+        let cache: TestCache<u64> =
+            LockedAlloc::new(unsafe { Cache::new(TestFrames::new(1)) });
+        let a = SlabBox::new(1u64, &cache).unwrap();
+        let slab_ref = a.into_slab_ref();
+        assert_eq!(cache.lock().partial_pages(), 1);
+        unsafe { cache.lock().free(slab_ref) };
+    }
+
+    #[derive(Debug, Default)]
+    struct Node {
+        value: usize,
+        links: Links<Node>,
+        next: Link<Node>,
+    }
+
+    impl Linked for Node {
+        fn links(&self) -> &Links<Self> {
+            &self.links
+        }
+
+        fn links_mut(&mut self) -> &mut Links<Self> {
+            &mut self.links
+        }
+    }
+
+    impl stack::Linked for Node {
+        fn next(&self) -> &Link<Self> {
+            &self.next
+        }
+
+        fn next_mut(&mut self) -> &mut Link<Self> {
+            &mut self.next
+        }
+    }
+
+    thread_local! {
+        static NODES: TestCache<Node> =
+            LockedAlloc::new(unsafe { Cache::new(TestFrames::new(1)) });
+    }
+
+    /// A handle to this thread's cache of `Node`s.
+    #[derive(Clone, Copy, Debug, Default)]
+    struct Nodes;
+
+    unsafe impl ObjectCache<Node> for Nodes {
+        fn alloc(&self, value: Node) -> Result<SlabRef<Node>, AllocErr> {
+            NODES.with(|cache| cache.lock().alloc(value))
+        }
+
+        unsafe fn free(&self, slab_ref: SlabRef<Node>) {
+            NODES.with(|cache| cache.lock().free(slab_ref))
+        }
+    }
+
+    fn node(value: usize) -> SlabBox<Node, Nodes> {
+        let node = Node {
+            value,
+            ..Node::default()
+        };
+        SlabBox::new(node, Nodes).unwrap()
+    }
+
+    /// Returns the number of pages in this thread's `Node` cache which hold
+    /// allocated objects.
+    fn pages_in_use() -> usize {
+        NODES.with(|cache| {
+            let cache = cache.lock();
+            cache.partial_pages() + cache.full_pages()
+        })
+    }
+
+    #[test]
+    fn slab_boxes_in_lists() {
+        let mut list: List<Node, Node, SlabBox<Node, Nodes>> = List::new();
+        for i in 0..4 {
+            list.push_back_node(node(i));
+        }
+        assert_eq!(list.len(), 4);
+        assert_eq!(pages_in_use(), 1);
+
+        let values: Vec<_> = (0..4)
+            .map(|_| list.pop_front_node().unwrap().value)
+            .collect();
+        assert_eq!(values, vec![0, 1, 2, 3]);
+        assert_eq!(pages_in_use(), 0);
+    }
+
+    #[test]
+    fn slab_boxes_in_stacks() {
+        let mut stack: Stack<Node, Node, SlabBox<Node, Nodes>> = Stack::new();
+        for i in 0..4 {
+            stack.push_node(node(i));
+        }
+        assert_eq!(stack.len(), 4);
+        assert_eq!(pages_in_use(), 1);
+
+        assert_eq!(stack.pop_node().unwrap().value, 3);
+        while stack.pop_node().is_some() {}
+        assert_eq!(pages_in_use(), 0);
+    }
+}
@@ -0,0 +1,267 @@
+//! Slab caches backed by a caller-supplied buffer.
+//!
+//! Early-boot code may need to allocate objects before the kernel's frame
+//! allocator has been initialized, and embedded systems may have no frame
+//! allocator at all. A [`BufferFrames`] is a frame allocator which carves a
+//! fixed buffer (such as a `static` array) into frames, so that slab caches
+//! may be used in either situation.
+//!
+//! A cache may be constructed over a buffer directly, using
+//! [`Cache::from_buffer`].
+//!
+//! [`BufferFrames`]: struct.BufferFrames.html
+//! [`Cache::from_buffer`]: ../cache/struct.Cache.html#method.from_buffer
+use super::cache::Cache;
+use alarm_base::FrameAllocator;
+use core::{alloc::AllocErr, fmt, marker::PhantomData, ptr::NonNull};
+use hal9000::mem::Page as MemPage;
+use intruder_alarm::{
+    stack::{self, Stack},
+    Link,
+    OwningRef,
+    UnsafeRef,
+};
+
+/// A frame allocator which allocates frames from a fixed buffer.
+///
+/// Frames are aligned to their size, so up to one frame's worth of the
+/// buffer may be unused if the buffer is not itself frame-aligned. Frames
+/// which have never been allocated are handed out in address order; frames
+/// which are returned to the allocator are kept on an intrusive free list
+/// and reused first.
+///
+/// # Type Parameters
+/// - `F`: the type of frames handed out by this allocator.
+pub struct BufferFrames<F> {
+    /// The base address of the first frame in the buffer.
+    start: usize,
+
+    /// The base address of the first frame which has never been allocated.
+    next: usize,
+
+    /// The end address of the last whole frame in the buffer.
+    end: usize,
+
+    /// Frames which have been returned to this allocator.
+    free: Stack<FreeFrame, FreeFrame, UnsafeRef<FreeFrame>>,
+
+    _frame: PhantomData<fn() -> F>,
+}
+
+/// A free frame, linked into a `BufferFrames`' free list.
+struct FreeFrame {
+    next: Link<FreeFrame>,
+}
+
+// ===== impl BufferFrames =====
+
+impl<F> BufferFrames<F>
+where
+    F: MemPage,
+{
+    /// Returns a new `BufferFrames` which will allocate frames from
+    /// `buffer`.
+    pub fn new(buffer: &'static mut [u8]) -> Self {
+        unsafe {
+            let start = NonNull::new_unchecked(buffer.as_mut_ptr());
+            Self::from_raw_parts(start, buffer.len())
+        }
+    }
+
+    /// Returns a new `BufferFrames` which will allocate frames from the
+    /// `size` bytes of memory starting at `start`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// region of memory starting at `start` is valid for `size` bytes, and
+    /// that nothing else will access that memory for as long as any frames
+    /// allocated from it are in use.
+    pub unsafe fn from_raw_parts(start: NonNull<u8>, size: usize) -> Self {
+        let addr = start.as_ptr() as usize;
+        let first = addr
+            .checked_add(F::SIZE - 1)
+            .map(|a| a & !(F::SIZE - 1))
+            .unwrap_or(usize::max_value());
+        let frames = (addr + size).saturating_sub(first) / F::SIZE;
+        BufferFrames {
+            start: first,
+            next: first,
+            end: first + frames * F::SIZE,
+            free: Stack::new(),
+            _frame: PhantomData,
+        }
+    }
+
+    /// Returns the total number of frames in the buffer.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        (self.end - self.start) / F::SIZE
+    }
+
+    /// Returns the number of frames which may currently be allocated.
+    #[inline]
+    pub fn free_frames(&self) -> usize {
+        (self.end - self.next) / F::SIZE + self.free.len()
+    }
+
+    /// Returns `true` if `frame` may have been allocated from this
+    /// allocator's buffer.
+    fn contains(&self, frame: &F) -> bool {
+        let base: usize = frame.base().into();
+        base >= self.start && base < self.next && base % F::SIZE == 0
+    }
+}
+
+unsafe impl<F> FrameAllocator for BufferFrames<F>
+where
+    F: MemPage,
+{
+    type Frame = F;
+
+    unsafe fn alloc(&mut self) -> Result<F, AllocErr> {
+        let base = if let Some(frame) = self.free.pop_node() {
+            OwningRef::into_ptr(frame) as usize
+        } else if self.next < self.end {
+            let base = self.next;
+            self.next += F::SIZE;
+            base
+        } else {
+            return Err(AllocErr);
+        };
+        Ok(F::containing(base.into()))
+    }
+
+    unsafe fn dealloc(&mut self, frame: F) -> Result<(), AllocErr> {
+        if !self.contains(&frame) {
+            return Err(AllocErr);
+        }
+        let base: usize = frame.base().into();
+        let node = base as *mut FreeFrame;
+        node.write(FreeFrame { next: Link::none() });
+        self.free
+            .push_node(UnsafeRef::from(NonNull::new_unchecked(node)));
+        Ok(())
+    }
+}
+
+// A `BufferFrames` has exclusive access to its buffer, including the free
+// frames its free list points into.
+unsafe impl<F> Send for BufferFrames<F> {}
+
+impl<F> fmt::Debug for BufferFrames<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BufferFrames")
+            .field("start", &(self.start as *const u8))
+            .field("next", &(self.next as *const u8))
+            .field("end", &(self.end as *const u8))
+            .field("free", &self.free.len())
+            .finish()
+    }
+}
+
+// ===== impl FreeFrame =====
+
+impl stack::Linked for FreeFrame {
+    #[inline]
+    fn next(&self) -> &Link<Self> {
+        &self.next
+    }
+
+    #[inline]
+    fn next_mut(&mut self) -> &mut Link<Self> {
+        &mut self.next
+    }
+}
+
+// ===== impl Cache =====
+
+impl<T, F> Cache<T, BufferFrames<F>>
+where
+    F: MemPage,
+{
+    /// Returns a new `Cache` which will allocate pages from `buffer`, rather
+    /// than from a frame allocator.
+    ///
+    /// See [`BufferFrames`] for details on how the buffer is divided into
+    /// pages.
+    ///
+    /// [`BufferFrames`]: ../buffer/struct.BufferFrames.html
+    pub fn from_buffer(buffer: &'static mut [u8]) -> Self {
+        // Safe, since the "frames" handed out by `BufferFrames` are addressed
+        // by their virtual addresses within `buffer`, which we own.
+        unsafe { Cache::new(BufferFrames::new(buffer)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TestFrame, FRAME_SIZE};
+    use std::{boxed::Box, vec::Vec};
+
+    fn buffer(frames: usize) -> &'static mut [u8] {
+        Box::leak(vec![0u8; (frames + 1) * FRAME_SIZE].into_boxed_slice())
+    }
+
+    #[test]
+    fn frames_are_aligned() {
+        let mut frames = BufferFrames::<TestFrame>::new(buffer(4));
+        // An unaligned buffer of 5 frames holds at least 4 aligned frames.
+        assert!(frames.capacity() >= 4);
+        assert_eq!(frames.free_frames(), frames.capacity());
+
+        let a = unsafe { frames.alloc().unwrap() };
+        let b = unsafe { frames.alloc().unwrap() };
+        let (a_base, b_base): (usize, usize) =
+            (a.base().into(), b.base().into());
+        assert_eq!(a_base % FRAME_SIZE, 0);
+        assert_eq!(b_base, a_base + FRAME_SIZE);
+    }
+
+    #[test]
+    fn frames_are_reused() {
+        let mut frames = BufferFrames::<TestFrame>::new(buffer(4));
+        let capacity = frames.capacity();
+        let all: Vec<_> = (0..capacity)
+            .map(|_| unsafe { frames.alloc().unwrap() })
+            .collect();
+        assert!(unsafe { frames.alloc() }.is_err());
+        assert_eq!(frames.free_frames(), 0);
+
+        let mut all = all.into_iter();
+        let freed = all.next().unwrap();
+        let base: usize = freed.base().into();
+        unsafe { frames.dealloc(freed).unwrap() };
+        assert_eq!(frames.free_frames(), 1);
+
+        let again: usize = unsafe { frames.alloc().unwrap() }.base().into();
+        assert_eq!(again, base);
+    }
+
+    #[test]
+    fn foreign_frames_rejected() {
+        let mut frames = BufferFrames::<TestFrame>::new(buffer(1));
+        let frame = unsafe { frames.alloc().unwrap() };
+        let base: usize = frame.base().into();
+        let foreign = TestFrame::containing((base + FRAME_SIZE * 64).into());
+        assert!(unsafe { frames.dealloc(foreign) }.is_err());
+        unsafe { frames.dealloc(frame).unwrap() };
+    }
+
+    #[test]
+    fn cache_from_buffer() {
+        let mut cache =
+            Cache::<u64, BufferFrames<TestFrame>>::from_buffer(buffer(2));
+        let capacity = cache.frame_allocator().capacity();
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..capacity * per_page)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert!(cache.alloc(0).is_err());
+        assert_eq!(cache.frame_allocator().free_frames(), 0);
+
+        unsafe { cache.free_bulk(refs) };
+        cache.reclaim();
+        assert_eq!(cache.frame_allocator().free_frames(), capacity);
+    }
+}
@@ -0,0 +1,2077 @@
+//! Object caches backed by a frame allocator.
+//!
+//! A [`Cache`] manages a set of [`Page`]s obtained from a frame allocator,
+//! in the style of Bonwick's slab allocator. Pages are kept on one of three
+//! intrusive lists, depending on how many of their slots are allocated:
+//!
+//! - _empty_ pages have no allocated objects,
+//! - _partial_ pages have some allocated objects and some free slots,
+//! - _full_ pages have no free slots.
+//!
+//! Allocations are always satisfied from a partial page if one exists, so
+//! that allocated objects are packed into as few pages as possible. Empty
+//! pages are only used when there are no partial pages, and new pages are
+//! only requested from the frame allocator when there are no empty pages.
+//!
+//! Each page occupies a single frame of `A::FRAME_SIZE` bytes. When a page
+//! becomes empty and the cache already holds its maximum number of empty
+//! pages, the page's frame is returned to the frame allocator. When the cache
+//! itself is dropped, the frames of all of its pages are returned.
+//!
+//! A page's header and slots are accessed through the physical address of
+//! its frame, so every frame a cache allocates must be identity-mapped. This
+//! is why the constructors are `unsafe`.
+//!
+//! # Object Caching
+//! A cache may be created with a _constructor_ and an optional _destructor_
+//! for its objects, using [`Cache::with_ctor`]. Every object in a page is
+//! constructed once, when the page is allocated, and freed objects are left
+//! in their constructed state rather than being dropped. Objects are only
+//! destroyed when their page is returned to the frame allocator, including
+//! when the cache is dropped. This avoids repeatedly initializing and tearing
+//! down objects which are expensive to construct, provided that users return
+//! objects to the cache in their constructed state.
+//!
+//! # Colouring
+//! Packing slots into a frame usually leaves some bytes unused at the end
+//! of the frame. Rather than always wasting those bytes at the end, each new
+//! page offsets its first slot by a different multiple of the cache's
+//! _colour alignment_ (by default, [`CACHE_LINE_SIZE`]), cycling through
+//! every offset that fits in the leftover space. This way, objects at the
+//! same index in different pages land on different cache lines, rather than
+//! competing for the same cache sets.
+//!
+//! [`Cache`]: struct.Cache.html
+//! [`CACHE_LINE_SIZE`]: constant.CACHE_LINE_SIZE.html
+//! [`Page`]: ../struct.Page.html
+//! [`Cache::with_ctor`]: struct.Cache.html#method.with_ctor
+#[cfg(feature = "debug")]
+use super::debug;
+use super::{Allocated, Page, SlabRef, SlotLayout};
+use alarm_base::FrameAllocator;
+use core::{
+    alloc::{Alloc, AllocErr, Layout},
+    cmp,
+    fmt,
+    iter,
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    ptr::{self, NonNull},
+};
+use hal9000::mem::Page as MemPage;
+use intruder_alarm::{
+    list::{Cursor, List},
+    UnsafeRef,
+};
+
+/// A slab cache for objects of type `T`.
+///
+/// # Type Parameters
+/// - `T`: the type of object allocated by this cache.
+/// - `A`: the type of the frame allocator that provides pages to this cache.
+pub struct Cache<T, A>
+where
+    A: FrameAllocator,
+{
+    /// The name of this cache, used when reporting errors.
+    name: &'static str,
+
+    /// Pages with no allocated objects.
+    empty: PageList<T>,
+
+    /// Pages with both allocated objects and free slots.
+    partial: PageList<T>,
+
+    /// Pages with no free slots.
+    full: PageList<T>,
+
+    /// The maximum number of empty pages this cache will hold before
+    /// returning them to the frame allocator.
+    max_empty: usize,
+
+    /// Constructor for this cache's objects, if objects are cached in their
+    /// constructed state.
+    ctor: Option<fn() -> T>,
+
+    /// Destructor run on this cache's objects before they are dropped.
+    dtor: Option<fn(&mut T)>,
+
+    /// The distance between colour offsets, in bytes.
+    colour_align: usize,
+
+    /// The colour of the next page allocated by this cache.
+    next_colour: usize,
+
+    /// Counts of this cache's allocations and frees.
+    counters: Counters,
+
+    /// The frame allocator from which new pages are requested.
+    frames: A,
+}
+
+/// The default maximum number of empty pages held by a `Cache`.
+///
+/// Holding on to a single empty page prevents a cache whose usage oscillates
+/// around a page boundary from repeatedly allocating and deallocating frames.
+pub const DEFAULT_MAX_EMPTY: usize = 1;
+
+/// The name of a `Cache` which has not been given one.
+pub(crate) const UNNAMED: &str = "(unnamed)";
+
+/// The default colour alignment of a `Cache`, in bytes.
+pub const CACHE_LINE_SIZE: usize = 64;
+
+/// A snapshot of a `Cache`'s statistics.
+///
+/// A `Stats` is displayed as a single line in the style of Linux's
+/// `/proc/slabinfo`, with the columns described by [`SLABINFO_HEADER`].
+///
+/// [`SLABINFO_HEADER`]: constant.SLABINFO_HEADER.html
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Stats {
+    /// The name of the cache.
+    pub name: &'static str,
+
+    /// The size of each slot in the cache, in bytes.
+    ///
+    /// This includes any space used by the slot's free-list node and red
+    /// zones, so it may be larger than the cache's object type.
+    pub object_size: usize,
+
+    /// The number of objects that fit in each page.
+    pub objects_per_page: usize,
+
+    /// The number of objects currently allocated.
+    pub active_objects: usize,
+
+    /// The number of pages with no allocated objects.
+    pub empty_pages: usize,
+
+    /// The number of pages with both allocated objects and free slots.
+    pub partial_pages: usize,
+
+    /// The number of pages with no free slots.
+    pub full_pages: usize,
+
+    /// The total number of objects allocated over the cache's lifetime.
+    pub allocs: usize,
+
+    /// The total number of objects freed over the cache's lifetime.
+    pub frees: usize,
+
+    /// The number of allocations which failed because a new page could not
+    /// be allocated.
+    pub failed_allocs: usize,
+
+    /// The largest number of objects that have been allocated at once.
+    pub high_water: usize,
+
+    /// The number of distinct colours a page may be assigned.
+    ///
+    /// Page colours range from `0` to `colours - 1`.
+    pub colours: usize,
+
+    /// The colour that will be assigned to the next page.
+    pub next_colour: usize,
+
+    /// The distance between colour offsets, in bytes.
+    pub colour_align: usize,
+}
+
+/// The header describing the columns of a displayed [`Stats`].
+///
+/// [`Stats`]: struct.Stats.html
+pub const SLABINFO_HEADER: &str = "# name            <active_objs> \
+    <num_objs> <objsize> <objperslab> <pagesperslab> : slabdata \
+    <active_slabs> <num_slabs> : stats <allocs> <frees> <failed> \
+    <high_water>";
+
+/// Allocation counters for a slab cache.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Counters {
+    /// The total number of objects allocated.
+    pub(crate) allocs: usize,
+
+    /// The total number of objects freed.
+    pub(crate) frees: usize,
+
+    /// The number of failed allocations.
+    pub(crate) failed: usize,
+
+    /// The largest number of objects allocated at once.
+    pub(crate) high_water: usize,
+}
+
+type PageList<T> = List<Page<T>, Page<T>, UnsafeRef<Page<T>>>;
+
+/// An iterator over the allocated objects in a [`Cache`].
+///
+/// Returned by [`Cache::iter`].
+///
+/// [`Cache`]: struct.Cache.html
+/// [`Cache::iter`]: struct.Cache.html#method.iter
+pub struct Iter<'a, T: 'a> {
+    /// The partial and full pages which have not yet been visited.
+    pages: iter::Chain<PageCursor<'a, T>, PageCursor<'a, T>>,
+
+    /// The allocated objects in the page currently being visited.
+    objects: Option<Allocated<'a, T>>,
+}
+
+/// An iterator over mutable references to the allocated objects in a
+/// [`Cache`].
+///
+/// Returned by [`Cache::iter_mut`].
+///
+/// [`Cache`]: struct.Cache.html
+/// [`Cache::iter_mut`]: struct.Cache.html#method.iter_mut
+pub struct IterMut<'a, T: 'a> {
+    inner: Iter<'a, T>,
+    _ty: PhantomData<&'a mut T>,
+}
+
+type PageCursor<'a, T> = Cursor<'a, Page<T>, Page<T>>;
+
+/// The slots allocated by [`Cache::alloc_bulk_with`] which have not yet been
+/// filled. If the closure filling them panics, they are returned to the cache
+/// when this is dropped.
+///
+/// [`Cache::alloc_bulk_with`]: struct.Cache.html#method.alloc_bulk_with
+struct UnfilledSlots<'a, T: 'a, A: 'a>
+where
+    A: FrameAllocator,
+{
+    cache: &'a mut Cache<T, A>,
+    slots: &'a [MaybeUninit<SlabRef<T>>],
+
+    /// The index of the next slot to be filled.
+    next: usize,
+}
+
+// ===== impl Cache =====
+
+impl<T, A> Cache<T, A>
+where
+    A: FrameAllocator,
+{
+    /// Returns a new `Cache` which will request pages from `frames`.
+    ///
+    /// The cache will not allocate any pages until the first object is
+    /// allocated, and will hold at most [`DEFAULT_MAX_EMPTY`] empty pages.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that every
+    /// frame allocated from `frames` is mapped at the virtual address equal
+    /// to its physical address, and that nothing else will access those
+    /// frames while this cache holds them.
+    ///
+    /// [`DEFAULT_MAX_EMPTY`]: constant.DEFAULT_MAX_EMPTY.html
+    pub const unsafe fn new(frames: A) -> Self {
+        Self::with_max_empty(frames, DEFAULT_MAX_EMPTY)
+    }
+
+    /// Returns a new `Cache` which will request pages from `frames`, and hold
+    /// at most `max_empty` empty pages.
+    ///
+    /// Once the cache holds `max_empty` empty pages, any further pages that
+    /// become empty are returned to the frame allocator.
+    ///
+    /// # Unsafety
+    /// As with [`new`], the caller must guarantee that frames allocated from
+    /// `frames` are identity-mapped and not accessed by anything else.
+    ///
+    /// [`new`]: #method.new
+    pub const unsafe fn with_max_empty(frames: A, max_empty: usize) -> Self {
+        Cache {
+            name: UNNAMED,
+            empty: List::new(),
+            partial: List::new(),
+            full: List::new(),
+            max_empty,
+            ctor: None,
+            dtor: None,
+            colour_align: CACHE_LINE_SIZE,
+            next_colour: 0,
+            counters: Counters::new(),
+            frames,
+        }
+    }
+
+    /// Returns a new `Cache` which will request pages from `frames`, and
+    /// cache its objects in their constructed state.
+    ///
+    /// When a page is allocated, `ctor` is called to construct every object
+    /// in that page. Freed objects are not dropped, and are handed out again
+    /// in whatever state they were freed in. When a page is returned to the
+    /// frame allocator, `dtor` (if there is one) is called on each of its
+    /// objects, and then they are dropped.
+    ///
+    /// # Unsafety
+    /// As with [`new`], the caller must guarantee that frames allocated from
+    /// `frames` are identity-mapped and not accessed by anything else.
+    ///
+    /// [`new`]: #method.new
+    pub const unsafe fn with_ctor(
+        frames: A,
+        ctor: fn() -> T,
+        dtor: Option<fn(&mut T)>,
+    ) -> Self {
+        Cache {
+            name: UNNAMED,
+            empty: List::new(),
+            partial: List::new(),
+            full: List::new(),
+            max_empty: DEFAULT_MAX_EMPTY,
+            ctor: Some(ctor),
+            dtor,
+            colour_align: CACHE_LINE_SIZE,
+            next_colour: 0,
+            counters: Counters::new(),
+            frames,
+        }
+    }
+
+    /// Returns the name of this cache.
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Sets the name of this cache, which is used when reporting errors.
+    #[inline]
+    pub fn set_name(&mut self, name: &'static str) {
+        self.name = name;
+    }
+
+    /// Returns `true` if this cache keeps freed objects in their constructed
+    /// state.
+    #[inline]
+    pub fn caches_objects(&self) -> bool {
+        self.ctor.is_some()
+    }
+
+    /// Returns the destructor run on this cache's objects, if any.
+    #[inline]
+    pub(crate) fn dtor(&self) -> Option<fn(&mut T)> {
+        self.dtor
+    }
+
+    /// Returns the maximum number of empty pages this cache will hold.
+    #[inline]
+    pub fn max_empty(&self) -> usize {
+        self.max_empty
+    }
+
+    /// Sets the maximum number of empty pages this cache will hold.
+    ///
+    /// This does not release any empty pages the cache already holds.
+    #[inline]
+    pub fn set_max_empty(&mut self, max_empty: usize) {
+        self.max_empty = max_empty;
+    }
+
+    /// Returns the distance between colour offsets, in bytes.
+    #[inline]
+    pub fn colour_align(&self) -> usize {
+        self.colour_align
+    }
+
+    /// Sets the distance between colour offsets, in bytes.
+    ///
+    /// This only affects pages allocated after it is called.
+    ///
+    /// # Panics
+    /// If `colour_align` is not a power of two.
+    pub fn set_colour_align(&mut self, colour_align: usize) {
+        assert!(
+            colour_align.is_power_of_two(),
+            "colour alignment must be a power of two"
+        );
+        self.colour_align = colour_align;
+        self.next_colour = 0;
+    }
+
+    /// Borrow the frame allocator that provides pages to this cache.
+    #[inline]
+    pub fn frame_allocator(&self) -> &A {
+        &self.frames
+    }
+
+    /// Returns the number of pages in this cache with no allocated objects.
+    #[inline]
+    pub fn empty_pages(&self) -> usize {
+        self.empty.len()
+    }
+
+    /// Returns the number of pages in this cache with both allocated objects
+    /// and free slots.
+    #[inline]
+    pub fn partial_pages(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Returns the number of pages in this cache with no free slots.
+    #[inline]
+    pub fn full_pages(&self) -> usize {
+        self.full.len()
+    }
+
+    /// Returns an iterator over every allocated object in this cache.
+    ///
+    /// Objects are visited page by page, skipping free slots. This walks each
+    /// page's free list to find its allocated objects, so it is intended for
+    /// debugging and leak checking rather than for hot paths.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        Iter {
+            pages: self.partial.cursor().chain(self.full.cursor()),
+            objects: None,
+        }
+    }
+
+    /// Returns an iterator over mutable references to every allocated object
+    /// in this cache.
+    ///
+    /// Like [`iter`], this is intended for debugging rather than hot paths.
+    ///
+    /// [`iter`]: #method.iter
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a, T> {
+        // Objects live in page slots rather than in the cache itself, so
+        // borrowing the cache mutably for `'a` ensures that nothing else may
+        // access them while they are borrowed.
+        let this: &'a Self = self;
+        IterMut {
+            inner: this.iter(),
+            _ty: PhantomData,
+        }
+    }
+}
+
+impl<T, A> Cache<T, A>
+where
+    A: FrameAllocator,
+{
+    /// Returns the number of objects that will fit in each page of this
+    /// cache.
+    pub fn objects_per_page(&self) -> usize {
+        A::FRAME_SIZE.saturating_sub(self.slots_offset()) / self.layout().size()
+    }
+
+    /// Returns the number of distinct colours a page in this cache may be
+    /// assigned.
+    pub fn colours(&self) -> usize {
+        let used = self.objects_per_page() * self.layout().size();
+        let leftover = A::FRAME_SIZE.saturating_sub(self.slots_offset() + used);
+        leftover / self.colour_step() + 1
+    }
+
+    /// Return up to `pages` empty pages to the frame allocator.
+    ///
+    /// This ignores the cache's `max_empty` threshold, so it may be used to
+    /// give memory back to the frame allocator when frames are scarce.
+    ///
+    /// # Returns
+    /// The number of frames that were returned to the frame allocator. This
+    /// may be less than `pages` if the cache has fewer empty pages, or if the
+    /// frame allocator refuses to take a frame back.
+    pub fn shrink(&mut self, pages: usize) -> usize {
+        let mut freed = 0;
+        while freed < pages {
+            let page = match self.empty.pop_front_node() {
+                Some(page) => page,
+                None => break,
+            };
+            if let Err(page) = unsafe { self.release(page) } {
+                self.empty.push_front_node(page);
+                break;
+            }
+            freed += 1;
+        }
+        freed
+    }
+
+    /// Return every empty page in this cache to the frame allocator.
+    ///
+    /// # Returns
+    /// The number of frames that were returned to the frame allocator.
+    #[inline]
+    pub fn reclaim(&mut self) -> usize {
+        self.shrink(usize::max_value())
+    }
+
+    /// Returns a snapshot of this cache's statistics.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            name: self.name,
+            object_size: self.layout().size(),
+            objects_per_page: self.objects_per_page(),
+            active_objects: self.counters.live(),
+            empty_pages: self.empty.len(),
+            partial_pages: self.partial.len(),
+            full_pages: self.full.len(),
+            allocs: self.counters.allocs,
+            frees: self.counters.frees,
+            failed_allocs: self.counters.failed,
+            high_water: self.counters.high_water,
+            colours: self.colours(),
+            next_colour: self.next_colour,
+            colour_align: self.colour_step(),
+        }
+    }
+
+    /// Allocate a slot in this cache and move `value` into it.
+    ///
+    /// If this cache keeps objects in their constructed state, the cached
+    /// object in the allocated slot is destroyed and replaced with `value`.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object.
+    /// - `Err(AllocErr)` if there were no free slots and a new page could not
+    ///   be allocated.
+    pub fn alloc(&mut self, value: T) -> Result<SlabRef<T>, AllocErr> {
+        let ptr = self.alloc_slot()?;
+        unsafe {
+            if self.caches_objects() {
+                self.destroy(ptr);
+            }
+            ptr::write(ptr.as_ptr(), value);
+        }
+        Ok(SlabRef { ptr })
+    }
+
+    /// Allocate a constructed object from this cache.
+    ///
+    /// The object is in whatever state it was in when it was last freed, or
+    /// the state it was constructed in if it has never been allocated.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object.
+    /// - `Err(AllocErr)` if there were no free slots and a new page could not
+    ///   be allocated.
+    ///
+    /// # Panics
+    /// If this cache was not created with a constructor.
+    pub fn alloc_constructed(&mut self) -> Result<SlabRef<T>, AllocErr> {
+        assert!(
+            self.caches_objects(),
+            "alloc_constructed requires a cache with a constructor"
+        );
+        self.alloc_slot().map(|ptr| SlabRef { ptr })
+    }
+
+    /// Allocate up to `out.len()` slots from this cache at once.
+    ///
+    /// This takes every free slot from a page before moving on to the next,
+    /// so the cost of list management is paid per page rather than per
+    /// object. Callers sharing a cache behind a lock need only take the lock
+    /// once for the whole batch.
+    ///
+    /// As with memory allocated through `Alloc`, each slot is uninitialized,
+    /// unless this cache keeps objects in their constructed state, in which
+    /// case it holds a constructed `T`. Once a slot holds a valid `T`, it may
+    /// be turned into a `SlabRef` with [`SlabRef::from_raw`] and freed with
+    /// [`free`] or [`free_bulk`]. Slots which are never initialized must be
+    /// returned with `Alloc::dealloc`, which doesn't drop them.
+    ///
+    /// # Returns
+    /// The number of slots allocated, `n`. The first `n` elements of `out`
+    /// are initialized with pointers to the allocated slots. `n` is less than
+    /// `out.len()` only if a new page could not be allocated.
+    ///
+    /// [`SlabRef::from_raw`]: ../struct.SlabRef.html#method.from_raw
+    /// [`free`]: #method.free
+    /// [`free_bulk`]: #method.free_bulk
+    pub fn alloc_bulk(&mut self, out: &mut [MaybeUninit<NonNull<T>>]) -> usize {
+        self.alloc_slots(out.len(), |i, ptr| out[i] = MaybeUninit::new(ptr))
+    }
+
+    /// Allocate up to `out.len()` constructed objects from this cache at
+    /// once.
+    ///
+    /// This is equivalent to calling [`alloc_constructed`] once for each
+    /// element of `out`, but batched as in [`alloc_bulk`].
+    ///
+    /// # Returns
+    /// The number of objects allocated, `n`. The first `n` elements of `out`
+    /// are initialized with references to the allocated objects. `n` is less
+    /// than `out.len()` only if a new page could not be allocated.
+    ///
+    /// # Panics
+    /// If this cache was not created with a constructor.
+    ///
+    /// [`alloc_constructed`]: #method.alloc_constructed
+    /// [`alloc_bulk`]: #method.alloc_bulk
+    pub fn alloc_bulk_constructed(
+        &mut self,
+        out: &mut [MaybeUninit<SlabRef<T>>],
+    ) -> usize {
+        assert!(
+            self.caches_objects(),
+            "alloc_bulk_constructed requires a cache with a constructor"
+        );
+        self.alloc_slots(out.len(), |i, ptr| {
+            out[i] = MaybeUninit::new(SlabRef { ptr })
+        })
+    }
+
+    /// Allocate up to `out.len()` objects from this cache at once, moving
+    /// the values returned by `f` into them.
+    ///
+    /// Like [`alloc_bulk`], but hands out initialized objects. If this cache
+    /// keeps objects in their constructed state, each cached object is
+    /// destroyed and replaced, as in [`alloc`].
+    ///
+    /// If `f` panics, the slots which have not yet been filled are returned
+    /// to the cache, and the objects already moved into slots are leaked.
+    ///
+    /// # Returns
+    /// The number of objects allocated, `n`. The first `n` elements of `out`
+    /// are initialized with references to the allocated objects. `n` is less
+    /// than `out.len()` only if a new page could not be allocated.
+    ///
+    /// [`alloc_bulk`]: #method.alloc_bulk
+    /// [`alloc`]: #method.alloc
+    pub fn alloc_bulk_with<F>(
+        &mut self,
+        out: &mut [MaybeUninit<SlabRef<T>>],
+        mut f: F,
+    ) -> usize
+    where
+        F: FnMut() -> T,
+    {
+        let n = self.alloc_slots(out.len(), |i, ptr| {
+            out[i] = MaybeUninit::new(SlabRef { ptr })
+        });
+        let mut guard = UnfilledSlots {
+            cache: self,
+            slots: &out[..n],
+            next: 0,
+        };
+        while guard.next < n {
+            unsafe {
+                let ptr = (*guard.slots[guard.next].as_ptr()).ptr;
+                // Get the new value before destroying the cached object, so
+                // that if `f` panics, the slot still holds a constructed
+                // object when it is freed.
+                let value = f();
+                if guard.cache.caches_objects() {
+                    guard.cache.destroy(ptr);
+                }
+                ptr::write(ptr.as_ptr(), value);
+            }
+            guard.next += 1;
+        }
+        n
+    }
+
+    /// Drop every object yielded by `slab_refs` and return their slots to
+    /// this cache.
+    ///
+    /// This is equivalent to calling [`free`] on each object, but a page is
+    /// only moved between lists once for each run of objects from that page,
+    /// so freeing objects grouped by page (as [`alloc_bulk`] hands them out)
+    /// pays the cost of list management per page rather than per object.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that every
+    /// `SlabRef` yielded by `slab_refs` was allocated by _this_ cache.
+    ///
+    /// [`free`]: #method.free
+    /// [`alloc_bulk`]: #method.alloc_bulk
+    pub unsafe fn free_bulk<I>(&mut self, slab_refs: I)
+    where
+        I: IntoIterator<Item = SlabRef<T>>,
+    {
+        // The page currently being freed into, and whether it was full
+        // before we started.
+        let mut current: Option<(NonNull<Page<T>>, bool)> = None;
+        for slab_ref in slab_refs {
+            let ptr = slab_ref.ptr;
+            if !self.caches_objects() {
+                self.destroy(ptr);
+            }
+
+            #[cfg(feature = "debug")]
+            {
+                debug::check(self.name, ptr, self.layout(), false);
+                if !self.caches_objects() {
+                    debug::poison(ptr);
+                }
+            }
+
+            let mut page = Self::page_of(ptr);
+            match current {
+                Some((p, _)) if p == page => {},
+                _ => {
+                    if let Some((p, was_full)) = current.take() {
+                        self.requeue(p, was_full);
+                    }
+                    current = Some((page, page.as_ref().is_full()));
+                },
+            }
+            page.as_mut().dealloc(ptr);
+            self.counters.free();
+        }
+
+        if let Some((page, was_full)) = current {
+            self.requeue(page, was_full);
+        }
+    }
+
+    /// Allocate a slot from a partial page, moving the page onto the full
+    /// list if the slot was its last free one.
+    ///
+    /// The returned slot is uninitialized, unless this cache keeps objects in
+    /// their constructed state.
+    pub(crate) fn alloc_slot(&mut self) -> Result<NonNull<T>, AllocErr> {
+        let (ptr, is_full) = {
+            let page = match self.partial_page() {
+                Ok(page) => page,
+                Err(e) => {
+                    self.counters.fail();
+                    return Err(e);
+                },
+            };
+            let ptr = page
+                .alloc()
+                .expect("pages on the partial list must have free slots");
+            (ptr, page.is_full())
+        };
+
+        #[cfg(feature = "debug")]
+        unsafe {
+            debug::check(self.name, ptr, self.layout(), !self.caches_objects());
+        }
+
+        if is_full {
+            let page = self
+                .partial
+                .pop_front_node()
+                .expect("partial list cannot be empty after allocating");
+            self.full.push_front_node(page);
+        }
+
+        self.counters.alloc(1);
+        Ok(ptr)
+    }
+
+    /// Drop the object referenced by `slab_ref` and return its slot to this
+    /// cache.
+    ///
+    /// If this cache keeps objects in their constructed state, the object is
+    /// _not_ dropped, and will be handed out in its current state by a later
+    /// allocation.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that
+    /// `slab_ref` was allocated by _this_ cache.
+    pub unsafe fn free(&mut self, slab_ref: SlabRef<T>) {
+        let ptr = slab_ref.ptr;
+        if !self.caches_objects() {
+            self.destroy(ptr);
+        }
+        self.free_slot(ptr);
+    }
+
+    /// Allocate up to `count` slots, emptying each partial page before
+    /// moving it onto the full list, and pass each one to `put` along with
+    /// its index in the batch.
+    ///
+    /// The slots are uninitialized, unless this cache keeps objects in their
+    /// constructed state.
+    ///
+    /// # Returns
+    /// The number of slots allocated.
+    fn alloc_slots<F>(&mut self, count: usize, mut put: F) -> usize
+    where
+        F: FnMut(usize, NonNull<T>),
+    {
+        #[cfg(feature = "debug")]
+        let (name, layout, poisoned) =
+            (self.name, self.layout(), !self.caches_objects());
+
+        let mut n = 0;
+        while n < count {
+            let start = n;
+            let is_full = {
+                let page = match self.partial_page() {
+                    Ok(page) => page,
+                    Err(_) => {
+                        self.counters.fail();
+                        break;
+                    },
+                };
+                while n < count {
+                    let ptr = match page.alloc() {
+                        Some(ptr) => ptr,
+                        None => break,
+                    };
+                    #[cfg(feature = "debug")]
+                    unsafe {
+                        debug::check(name, ptr, layout, poisoned);
+                    }
+                    put(n, ptr);
+                    n += 1;
+                }
+                page.is_full()
+            };
+            self.counters.alloc(n - start);
+
+            if is_full {
+                let page = self
+                    .partial
+                    .pop_front_node()
+                    .expect("partial list cannot be empty after allocating");
+                self.full.push_front_node(page);
+            }
+        }
+        n
+    }
+
+    /// Return the slot at `ptr` to its page, moving the page between lists
+    /// as necessary.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was allocated by _this_ cache, and that the object in the slot has
+    /// already been destroyed, unless this cache keeps objects in their
+    /// constructed state.
+    pub(crate) unsafe fn free_slot(&mut self, ptr: NonNull<T>) {
+        #[cfg(feature = "debug")]
+        {
+            debug::check(self.name, ptr, self.layout(), false);
+            if !self.caches_objects() {
+                debug::poison(ptr);
+            }
+        }
+
+        let mut page = Self::page_of(ptr);
+        let was_full = page.as_ref().is_full();
+        page.as_mut().dealloc(ptr);
+        self.counters.free();
+        self.requeue(page, was_full);
+    }
+
+    /// Move `page` onto the list matching its state after slots have been
+    /// returned to it.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that `page`
+    /// belongs to _this_ cache, and that it is still on the full list if
+    /// `was_full` is true, or on the partial list otherwise.
+    unsafe fn requeue(&mut self, page: NonNull<Page<T>>, was_full: bool) {
+        if was_full {
+            let page = self.full.remove_node(page);
+            if page.is_empty() {
+                self.push_empty(page);
+            } else {
+                self.partial.push_front_node(page);
+            }
+        } else if page.as_ref().is_empty() {
+            let page = self.partial.remove_node(page);
+            self.push_empty(page);
+        }
+    }
+
+    /// Move an empty page onto the empty list, or return its frame to the
+    /// frame allocator if the cache already holds `max_empty` empty pages.
+    unsafe fn push_empty(&mut self, page: UnsafeRef<Page<T>>) {
+        debug_assert!(page.is_empty());
+        if self.empty.len() < self.max_empty {
+            self.empty.push_front_node(page);
+            return;
+        }
+
+        if let Err(page) = self.release(page) {
+            // If the frame allocator won't take the frame back, hang on to
+            // the page rather than leaking it.
+            self.empty.push_front_node(page);
+        }
+    }
+
+    /// Destroy the objects in an empty page and return its frame to the
+    /// frame allocator.
+    ///
+    /// # Returns
+    /// - `Ok(())` if the frame was returned.
+    /// - `Err(page)` if the frame allocator refused the frame. The page's
+    ///   objects are reconstructed, so it may continue to be used.
+    unsafe fn release(
+        &mut self,
+        page: UnsafeRef<Page<T>>,
+    ) -> Result<(), UnsafeRef<Page<T>>> {
+        debug_assert!(page.is_empty());
+        if self.caches_objects() {
+            for i in 0..page.capacity() {
+                self.destroy(page.slot(i));
+            }
+        }
+        if self.dealloc_page(&page).is_err() {
+            self.construct_objects(&*page as *const Page<T> as *mut Page<T>);
+            return Err(page);
+        }
+        Ok(())
+    }
+
+    /// Return the frame holding `page` to the frame allocator.
+    ///
+    /// This does not destroy any of the page's objects.
+    #[inline]
+    unsafe fn dealloc_page(&mut self, page: &Page<T>) -> Result<(), AllocErr> {
+        let base = page as *const Page<T> as usize;
+        self.frames.dealloc(A::Frame::containing(base.into()))
+    }
+
+    /// Run the destructor (if any) on the object at `ptr`, and drop it.
+    #[inline]
+    pub(crate) unsafe fn destroy(&self, ptr: NonNull<T>) {
+        if let Some(dtor) = self.dtor {
+            dtor(&mut *ptr.as_ptr());
+        }
+        ptr::drop_in_place(ptr.as_ptr());
+    }
+
+    /// Construct every object in a newly-created page, if this cache keeps
+    /// objects in their constructed state.
+    unsafe fn construct_objects(&self, page: *mut Page<T>) {
+        if let Some(ctor) = self.ctor {
+            let page = &*page;
+            for i in 0..page.capacity() {
+                ptr::write(page.slot(i).as_ptr(), ctor());
+            }
+        }
+    }
+
+    /// Returns the layout of slots in this cache's pages.
+    ///
+    /// Caches which keep objects in their constructed state store free-list
+    /// nodes after each object, so that freeing an object doesn't clobber it.
+    #[cfg(not(feature = "debug"))]
+    #[inline]
+    pub(crate) fn layout(&self) -> SlotLayout {
+        if self.caches_objects() {
+            SlotLayout::preserving::<T>()
+        } else {
+            SlotLayout::of::<T>()
+        }
+    }
+
+    /// Returns the layout of slots in this cache's pages.
+    ///
+    /// With the `debug` feature enabled, every object is surrounded by red
+    /// zones, and free-list nodes are stored after each object.
+    #[cfg(feature = "debug")]
+    #[inline]
+    pub(crate) fn layout(&self) -> SlotLayout {
+        SlotLayout {
+            preserves_objects: self.caches_objects(),
+            ..SlotLayout::red_zoned::<T>()
+        }
+    }
+
+    /// Returns a page with at least one free slot, moving an empty page onto
+    /// the partial list (or allocating a new one) if there are no partial
+    /// pages.
+    fn partial_page(&mut self) -> Result<&mut Page<T>, AllocErr> {
+        if self.partial.is_empty() {
+            let page = match self.empty.pop_front_node() {
+                Some(page) => page,
+                None => self.grow()?,
+            };
+            self.partial.push_front_node(page);
+        }
+        Ok(self
+            .partial
+            .head_mut()
+            .expect("partial list cannot be empty"))
+    }
+
+    /// Allocate a new page from the frame allocator.
+    ///
+    /// The page's header is written to the start of the frame, followed by
+    /// its slots, so a page spans exactly `A::FRAME_SIZE` bytes.
+    fn grow(&mut self) -> Result<UnsafeRef<Page<T>>, AllocErr> {
+        if self.objects_per_page() == 0 {
+            // Don't waste a frame if we can't fit any objects in it.
+            return Err(AllocErr);
+        }
+
+        unsafe {
+            let frame = self.frames.alloc()?;
+            // Only advance the colour once we have a page to colour, so that
+            // failed allocations don't skip colours.
+            let colour = self.next_colour;
+            self.next_colour = (colour + 1) % self.colours();
+            let base: usize = frame.base().into();
+            let page = base as *mut Page<T>;
+            let offset = self.slots_offset() + colour * self.colour_step();
+            let slots = NonNull::new_unchecked((base + offset) as *mut u8);
+            ptr::write(
+                page,
+                Page::new(slots, A::FRAME_SIZE - offset, self.layout()),
+            );
+            #[cfg(feature = "debug")]
+            for i in 0..(*page).capacity() {
+                debug::init(
+                    (*page).slot(i),
+                    self.layout(),
+                    !self.caches_objects(),
+                );
+            }
+            self.construct_objects(page);
+            Ok(UnsafeRef::from(NonNull::new_unchecked(page)))
+        }
+    }
+
+    /// Returns the offset from the start of a frame to the first slot in the
+    /// page.
+    ///
+    /// Each page's `Page` header is stored at the start of the frame, followed
+    /// by its slots.
+    #[inline]
+    fn slots_offset(&self) -> usize {
+        let align = self.layout().align();
+        (mem::size_of::<Page<T>>() + align - 1) & !(align - 1)
+    }
+
+    /// Returns the distance between colour offsets for this cache's pages.
+    ///
+    /// Offsets must preserve the alignment of the page's slots, so this is
+    /// never less than the slot alignment.
+    #[inline]
+    fn colour_step(&self) -> usize {
+        cmp::max(self.colour_align, self.layout().align())
+    }
+
+    /// Returns the header of the page containing `ptr`.
+    ///
+    /// Since pages are allocated from page-aligned frames, the header is
+    /// found by rounding `ptr` down to the nearest frame boundary.
+    #[inline]
+    unsafe fn page_of(ptr: NonNull<T>) -> NonNull<Page<T>> {
+        let base = ptr.as_ptr() as usize & !(A::FRAME_SIZE - 1);
+        NonNull::new_unchecked(base as *mut Page<T>)
+    }
+}
+
+/// A `Cache` may be used as an allocator for blocks of memory with exactly
+/// the layout of its object type, `T`. Requests with any other layout fail
+/// with `AllocErr`.
+///
+/// Memory allocated through `Alloc` is uninitialized, unless this cache
+/// keeps objects in their constructed state, in which case it holds a
+/// constructed `T` which must be left in its constructed state when it is
+/// deallocated. Deallocating memory never drops the object it holds.
+unsafe impl<T, A> Alloc for Cache<T, A>
+where
+    A: FrameAllocator,
+{
+    unsafe fn alloc(
+        &mut self,
+        layout: Layout,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        if layout != Layout::new::<T>() {
+            return Err(AllocErr);
+        }
+        self.alloc_slot().map(NonNull::cast)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        debug_assert_eq!(
+            layout,
+            Layout::new::<T>(),
+            "layout does not match cache `{}`",
+            self.name
+        );
+        self.free_slot(ptr.cast())
+    }
+}
+
+/// Dropping a `Cache` returns every page it holds to the frame allocator.
+///
+/// If the cache keeps objects in their constructed state, every object in
+/// each page, allocated or not, is destroyed before its frame is returned.
+/// Otherwise, objects which are still allocated from the cache are leaked
+/// rather than dropped. Either way, they must not be used once the cache is
+/// gone.
+impl<T, A> Drop for Cache<T, A>
+where
+    A: FrameAllocator,
+{
+    fn drop(&mut self) {
+        self.reclaim();
+        // If the frame allocator refused to take back an empty page, there
+        // is nothing more to be done with it, so any pages left on the empty
+        // list are released along with the partial and full pages.
+        while let Some(page) = self
+            .empty
+            .pop_front_node()
+            .or_else(|| self.partial.pop_front_node())
+            .or_else(|| self.full.pop_front_node())
+        {
+            unsafe {
+                if self.caches_objects() {
+                    for i in 0..page.capacity() {
+                        self.destroy(page.slot(i));
+                    }
+                }
+                let _ = self.dealloc_page(&page);
+            }
+        }
+    }
+}
+
+// A `Cache` exclusively owns its pages, so it may be sent to another thread
+// if its objects and frame allocator may be.
+unsafe impl<T, A> Send for Cache<T, A>
+where
+    T: Send,
+    A: FrameAllocator + Send,
+{
+}
+
+impl<T, A> fmt::Debug for Cache<T, A>
+where
+    A: FrameAllocator + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("empty", &self.empty.len())
+            .field("partial", &self.partial.len())
+            .field("full", &self.full.len())
+            .field("max_empty", &self.max_empty)
+            .field("name", &self.name)
+            .field("caches_objects", &self.caches_objects())
+            .field("colour_align", &self.colour_align)
+            .field("next_colour", &self.next_colour)
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+// ===== impl Stats =====
+
+impl Stats {
+    /// Returns the number of pages with allocated objects.
+    #[inline]
+    pub fn active_pages(&self) -> usize {
+        self.partial_pages + self.full_pages
+    }
+
+    /// Returns the total number of pages held by the cache.
+    #[inline]
+    pub fn total_pages(&self) -> usize {
+        self.empty_pages + self.active_pages()
+    }
+
+    /// Returns the total number of objects that fit in the cache's pages.
+    #[inline]
+    pub fn total_objects(&self) -> usize {
+        self.total_pages() * self.objects_per_page
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Each slab is exactly one page, so `pagesperslab` is always 1.
+        write!(
+            f,
+            "{:<17} {:>6} {:>6} {:>6} {:>4} {:>4} : slabdata {:>6} {:>6} \
+             : stats {:>8} {:>8} {:>6} {:>6}",
+            self.name,
+            self.active_objects,
+            self.total_objects(),
+            self.object_size,
+            self.objects_per_page,
+            1,
+            self.active_pages(),
+            self.total_pages(),
+            self.allocs,
+            self.frees,
+            self.failed_allocs,
+            self.high_water,
+        )
+    }
+}
+
+// ===== impl Counters =====
+
+impl Counters {
+    pub(crate) const fn new() -> Self {
+        Counters {
+            allocs: 0,
+            frees: 0,
+            failed: 0,
+            high_water: 0,
+        }
+    }
+
+    /// Returns the number of objects currently allocated.
+    #[inline]
+    pub(crate) fn live(&self) -> usize {
+        self.allocs - self.frees
+    }
+
+    /// Record the allocation of `n` objects.
+    #[inline]
+    pub(crate) fn alloc(&mut self, n: usize) {
+        self.allocs += n;
+        self.high_water = cmp::max(self.high_water, self.live());
+    }
+
+    /// Record the freeing of an object.
+    #[inline]
+    pub(crate) fn free(&mut self) {
+        self.frees += 1;
+    }
+
+    /// Record a failed allocation.
+    #[inline]
+    pub(crate) fn fail(&mut self) {
+        self.failed += 1;
+    }
+}
+
+// ===== impl Iter =====
+
+impl<'a, T> Iter<'a, T> {
+    fn next_ptr(&mut self) -> Option<NonNull<T>> {
+        loop {
+            if let Some(ptr) = self.objects.as_mut().and_then(Iterator::next) {
+                return Some(ptr);
+            }
+            self.objects = Some(self.pages.next()?.allocated());
+        }
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_ptr().map(|ptr| unsafe { &*ptr.as_ptr() })
+    }
+}
+
+impl<'a, T> fmt::Debug for Iter<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Iter")
+            .field("objects", &self.objects)
+            .finish()
+    }
+}
+
+// ===== impl IterMut =====
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner
+            .next_ptr()
+            .map(|ptr| unsafe { &mut *ptr.as_ptr() })
+    }
+}
+
+impl<'a, T> fmt::Debug for IterMut<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IterMut")
+            .field("objects", &self.inner.objects)
+            .finish()
+    }
+}
+
+// ===== impl UnfilledSlots =====
+
+impl<'a, T, A> Drop for UnfilledSlots<'a, T, A>
+where
+    A: FrameAllocator,
+{
+    fn drop(&mut self) {
+        // Unfilled slots hold either nothing or a cached object, just like
+        // free slots, so they may go straight back to their pages.
+        for slot in &self.slots[self.next..] {
+            unsafe { self.cache.free_slot((*slot.as_ptr()).ptr) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{SharedFrames, TestFrames, FRAME_SIZE};
+    use std::vec::Vec;
+
+    type TestCache<T> = Cache<T, TestFrames>;
+
+    #[test]
+    fn no_pages_until_first_alloc() {
+        let cache = unsafe { TestCache::<u64>::new(TestFrames::new(4)) };
+        assert_eq!(cache.empty_pages(), 0);
+        assert_eq!(cache.partial_pages(), 0);
+        assert_eq!(cache.full_pages(), 0);
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+    }
+
+    #[test]
+    fn first_alloc_creates_partial_page() {
+        let mut cache = unsafe { TestCache::new(TestFrames::new(4)) };
+        let a = cache.alloc(1u64).unwrap();
+        assert_eq!(*a, 1);
+        assert_eq!(cache.partial_pages(), 1);
+        assert_eq!(cache.frame_allocator().allocated(), 1);
+
+        unsafe { cache.free(a) };
+        assert_eq!(cache.partial_pages(), 0);
+        assert_eq!(cache.empty_pages(), 1);
+    }
+
+    #[test]
+    fn objects_fill_page() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(4)) };
+        let per_page = cache.objects_per_page();
+        assert!(per_page > 0);
+        assert!(per_page < FRAME_SIZE / 8);
+
+        let refs: Vec<_> = (0..per_page)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert_eq!(cache.full_pages(), 1);
+        assert_eq!(cache.partial_pages(), 0);
+        assert_eq!(cache.frame_allocator().allocated(), 1);
+
+        // The next allocation needs a new page.
+        let extra = cache.alloc(0xdead).unwrap();
+        assert_eq!(cache.full_pages(), 1);
+        assert_eq!(cache.partial_pages(), 1);
+        assert_eq!(cache.frame_allocator().allocated(), 2);
+
+        for (i, r) in refs.iter().enumerate() {
+            assert_eq!(**r, i as u64);
+        }
+        assert_eq!(*extra, 0xdead);
+    }
+
+    #[test]
+    fn pages_migrate_between_lists() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(4)) };
+        let per_page = cache.objects_per_page();
+        let mut refs: Vec<_> = (0..per_page)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert_eq!(cache.full_pages(), 1);
+
+        // Freeing one object from a full page makes it partial.
+        unsafe { cache.free(refs.pop().unwrap()) };
+        assert_eq!(cache.full_pages(), 0);
+        assert_eq!(cache.partial_pages(), 1);
+
+        // Freeing the remaining objects makes it empty.
+        for r in refs.drain(..) {
+            unsafe { cache.free(r) };
+        }
+        assert_eq!(cache.partial_pages(), 0);
+        assert_eq!(cache.empty_pages(), 1);
+
+        // Empty pages are reused before new frames are allocated.
+        let a = cache.alloc(1).unwrap();
+        assert_eq!(cache.empty_pages(), 0);
+        assert_eq!(cache.partial_pages(), 1);
+        assert_eq!(cache.frame_allocator().allocated(), 1);
+        unsafe { cache.free(a) };
+    }
+
+    #[test]
+    fn prefers_partial_pages() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(4)) };
+        let per_page = cache.objects_per_page();
+        let mut refs: Vec<_> = (0..per_page * 2)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert_eq!(cache.full_pages(), 2);
+
+        // Empty out the first page entirely, and free one object from the
+        // second page.
+        let second = refs.split_off(per_page);
+        for r in refs {
+            unsafe { cache.free(r) };
+        }
+        let mut second = second.into_iter();
+        unsafe { cache.free(second.next().unwrap()) };
+        assert_eq!(cache.empty_pages(), 1);
+        assert_eq!(cache.partial_pages(), 1);
+
+        // The next allocation should fill the partial page, rather than using
+        // the empty one.
+        let a = cache.alloc(1).unwrap();
+        assert_eq!(cache.empty_pages(), 1);
+        assert_eq!(cache.full_pages(), 1);
+        assert_eq!(cache.partial_pages(), 0);
+
+        unsafe { cache.free(a) };
+        for r in second {
+            unsafe { cache.free(r) };
+        }
+        assert_eq!(cache.empty_pages(), 1);
+        assert_eq!(cache.frame_allocator().allocated(), 1);
+    }
+
+    fn empty_pages_after_freeing(
+        pages: usize,
+        max_empty: usize,
+    ) -> TestCache<u64> {
+        let mut cache = unsafe {
+            TestCache::with_max_empty(TestFrames::new(pages), max_empty)
+        };
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..per_page * pages)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert_eq!(cache.full_pages(), pages);
+        assert_eq!(cache.frame_allocator().allocated(), pages);
+        for r in refs {
+            unsafe { cache.free(r) };
+        }
+        cache
+    }
+
+    #[test]
+    fn empty_pages_returned_past_threshold() {
+        let cache = empty_pages_after_freeing(4, 2);
+        assert_eq!(cache.empty_pages(), 2);
+        assert_eq!(cache.frame_allocator().allocated(), 2);
+    }
+
+    #[test]
+    fn no_empty_pages_held() {
+        let cache = empty_pages_after_freeing(3, 0);
+        assert_eq!(cache.empty_pages(), 0);
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+    }
+
+    #[test]
+    fn default_max_empty() {
+        let cache = empty_pages_after_freeing(3, DEFAULT_MAX_EMPTY);
+        assert_eq!(cache.empty_pages(), DEFAULT_MAX_EMPTY);
+        assert_eq!(cache.frame_allocator().allocated(), DEFAULT_MAX_EMPTY);
+    }
+
+    #[test]
+    fn returned_frames_are_reallocated() {
+        let mut cache = empty_pages_after_freeing(2, 0);
+        let a = cache.alloc(1).unwrap();
+        assert_eq!(cache.frame_allocator().allocated(), 1);
+        unsafe { cache.free(a) };
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+    }
+
+    #[test]
+    fn frame_allocator_exhausted() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..per_page)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert!(cache.alloc(0).is_err());
+        for r in refs {
+            unsafe { cache.free(r) };
+        }
+    }
+
+    #[test]
+    fn stats_count_allocations() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+        cache.set_name("counted");
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..per_page)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert!(cache.alloc(0).is_err());
+
+        let mut refs = refs.into_iter();
+        for r in refs.by_ref().take(3) {
+            unsafe { cache.free(r) };
+        }
+        let a = cache.alloc(0).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!(stats.name, "counted");
+        assert_eq!(stats.object_size, cache.layout().size());
+        assert_eq!(stats.objects_per_page, per_page);
+        assert_eq!(stats.allocs, per_page + 1);
+        assert_eq!(stats.frees, 3);
+        assert_eq!(stats.failed_allocs, 1);
+        assert_eq!(stats.active_objects, per_page - 2);
+        assert_eq!(stats.high_water, per_page);
+        assert_eq!(stats.active_pages(), 1);
+        assert_eq!(stats.total_objects(), per_page);
+
+        unsafe {
+            cache.free(a);
+            cache.free_bulk(refs);
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.active_objects, 0);
+        assert_eq!(stats.high_water, per_page);
+    }
+
+    #[test]
+    fn stats_display_like_slabinfo() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+        cache.set_name("display");
+        let a = cache.alloc(1).unwrap();
+        let line = cache.stats().to_string();
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let per_page = cache.objects_per_page().to_string();
+        let size = cache.layout().size().to_string();
+        assert_eq!(
+            fields,
+            vec![
+                "display", "1", &per_page, &size, &per_page, "1", ":",
+                "slabdata", "1", "1", ":", "stats", "1", "0", "0", "1",
+            ]
+        );
+        let header: Vec<_> = SLABINFO_HEADER.split_whitespace().collect();
+        // The header has an extra leading `#`.
+        assert_eq!(header.len(), fields.len() + 1);
+        unsafe { cache.free(a) };
+    }
+
+    #[test]
+    fn objects_too_large_for_frame() {
+        let mut cache = unsafe { TestCache::new(TestFrames::new(1)) };
+        assert_eq!(cache.objects_per_page(), 0);
+        assert!(cache.alloc([0u8; FRAME_SIZE]).is_err());
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+    }
+
+    #[test]
+    fn reclaim_returns_all_empty_pages() {
+        let mut cache = empty_pages_after_freeing(4, 4);
+        assert_eq!(cache.empty_pages(), 4);
+        assert_eq!(cache.reclaim(), 4);
+        assert_eq!(cache.empty_pages(), 0);
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+        assert_eq!(cache.reclaim(), 0);
+    }
+
+    #[test]
+    fn shrink_returns_some_empty_pages() {
+        let mut cache = empty_pages_after_freeing(4, 4);
+        assert_eq!(cache.shrink(3), 3);
+        assert_eq!(cache.empty_pages(), 1);
+        assert_eq!(cache.frame_allocator().allocated(), 1);
+        assert_eq!(cache.shrink(3), 1);
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+    }
+
+    #[test]
+    fn reclaim_leaves_used_pages() {
+        let mut cache =
+            unsafe { TestCache::with_max_empty(TestFrames::new(4), 4) };
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..per_page * 2)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        let mut refs = refs.into_iter();
+        unsafe { cache.free(refs.next().unwrap()) };
+        assert_eq!(cache.reclaim(), 0);
+        assert_eq!(cache.partial_pages(), 1);
+        assert_eq!(cache.full_pages(), 1);
+
+        for r in refs {
+            unsafe { cache.free(r) };
+        }
+        assert_eq!(cache.reclaim(), 2);
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+    }
+
+    #[test]
+    fn drop_returns_every_page() {
+        let frames = SharedFrames::new(4);
+        let mut cache = unsafe { Cache::<u64, _>::new(frames.clone()) };
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..per_page * 2 + 1)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        for r in refs.into_iter().skip(per_page * 2) {
+            unsafe { cache.free(r) };
+        }
+        assert_eq!(cache.full_pages(), 2);
+        assert_eq!(cache.empty_pages(), 1);
+        assert_eq!(frames.allocated(), 3);
+
+        drop(cache);
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn iter_visits_allocated_objects() {
+        let mut cache = unsafe { TestCache::new(TestFrames::new(4)) };
+        assert_eq!(cache.iter().count(), 0);
+
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..per_page + per_page / 2)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        let mut live = Vec::new();
+        for (i, r) in refs.into_iter().enumerate() {
+            if i % 5 == 0 {
+                unsafe { cache.free(r) };
+            } else {
+                live.push(i as u64);
+            }
+        }
+
+        let mut found: Vec<_> = cache.iter().cloned().collect();
+        found.sort();
+        assert_eq!(found, live);
+
+        for object in cache.iter_mut() {
+            *object += 1000;
+        }
+        assert!(cache.iter().all(|&object| object >= 1000));
+        assert_eq!(cache.iter().count(), live.len());
+    }
+
+    mod alloc {
+        use super::*;
+        use alarm_base::{lend::Lend, LockedAlloc};
+
+        #[test]
+        fn matching_layouts_allocated() {
+            let mut cache =
+                unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+            let layout = Layout::new::<u64>();
+            unsafe {
+                let a = Alloc::alloc(&mut cache, layout).unwrap();
+                let b = Alloc::alloc(&mut cache, layout).unwrap();
+                assert_ne!(a, b);
+                assert_eq!(a.as_ptr() as usize % 8, 0);
+                assert_eq!(cache.stats().active_objects, 2);
+
+                Alloc::dealloc(&mut cache, a, layout);
+                Alloc::dealloc(&mut cache, b, layout);
+            }
+            assert_eq!(cache.empty_pages(), 1);
+        }
+
+        #[test]
+        fn mismatched_layouts_rejected() {
+            let mut cache =
+                unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+            let smaller = Layout::from_size_align(4, 4).unwrap();
+            let larger = Layout::from_size_align(16, 8).unwrap();
+            let aligned = Layout::from_size_align(8, 16).unwrap();
+            unsafe {
+                assert!(Alloc::alloc(&mut cache, smaller).is_err());
+                assert!(Alloc::alloc(&mut cache, larger).is_err());
+                assert!(Alloc::alloc(&mut cache, aligned).is_err());
+            }
+            assert_eq!(cache.frame_allocator().allocated(), 0);
+        }
+
+        #[test]
+        fn lend_from_locked_cache() {
+            let cache = LockedAlloc::new(unsafe {
+                TestCache::<[u64; 4]>::new(TestFrames::new(1))
+            });
+            {
+                let mut borrowed = (&cache).borrow::<[u64; 4]>().unwrap();
+                *borrowed = [1, 2, 3, 4];
+                assert_eq!(borrowed[3], 4);
+                assert_eq!(cache.lock().partial_pages(), 1);
+                assert!((&cache).borrow::<u64>().is_err());
+            }
+            assert_eq!(cache.lock().partial_pages(), 0);
+            assert_eq!(cache.lock().empty_pages(), 1);
+        }
+    }
+
+    fn init_refs<T>(out: &[MaybeUninit<SlabRef<T>>]) -> Vec<SlabRef<T>> {
+        out.iter()
+            .map(|r| unsafe { ptr::read(r.as_ptr()) })
+            .collect()
+    }
+
+    #[test]
+    fn alloc_bulk_hands_out_slots() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+        let mut out: Vec<_> = (0..4).map(|_| MaybeUninit::uninit()).collect();
+        assert_eq!(cache.alloc_bulk(&mut out), 4);
+        assert_eq!(cache.partial_pages(), 1);
+
+        let mut slots: Vec<_> = out
+            .iter()
+            .map(|p| unsafe { ptr::read(p.as_ptr()) })
+            .collect();
+        // Slots which are never initialized go back through `Alloc`.
+        let unused = slots.pop().unwrap();
+        unsafe { cache.dealloc(unused.cast(), Layout::new::<u64>()) };
+
+        let refs: Vec<_> = slots
+            .into_iter()
+            .enumerate()
+            .map(|(i, ptr)| unsafe {
+                ptr::write(ptr.as_ptr(), i as u64);
+                SlabRef::from_raw(ptr)
+            })
+            .collect();
+        assert_eq!(*refs[2], 2);
+        unsafe { cache.free_bulk(refs) };
+        assert_eq!(cache.empty_pages(), 1);
+    }
+
+    #[test]
+    fn alloc_bulk_spans_pages() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(4)) };
+        let per_page = cache.objects_per_page();
+        let mut out: Vec<_> =
+            (0..per_page + 2).map(|_| MaybeUninit::uninit()).collect();
+        let mut next = 0;
+        let n = cache.alloc_bulk_with(&mut out, || {
+            next += 1;
+            next
+        });
+        assert_eq!(n, per_page + 2);
+        assert_eq!(cache.full_pages(), 1);
+        assert_eq!(cache.partial_pages(), 1);
+
+        let refs = init_refs(&out);
+        for (i, r) in refs.iter().enumerate() {
+            assert_eq!(**r, i as u64 + 1);
+        }
+
+        unsafe { cache.free_bulk(refs) };
+        assert_eq!(cache.full_pages(), 0);
+        assert_eq!(cache.partial_pages(), 0);
+        assert_eq!(cache.empty_pages(), 1);
+        assert_eq!(cache.frame_allocator().allocated(), 1);
+    }
+
+    #[test]
+    fn alloc_bulk_stops_when_frames_exhausted() {
+        let mut cache = unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+        let per_page = cache.objects_per_page();
+        let mut out: Vec<_> =
+            (0..per_page * 2).map(|_| MaybeUninit::uninit()).collect();
+        let n = cache.alloc_bulk_with(&mut out, || 0);
+        assert_eq!(n, per_page);
+        assert_eq!(cache.full_pages(), 1);
+
+        unsafe { cache.free_bulk(init_refs(&out[..n])) };
+        assert_eq!(cache.empty_pages(), 1);
+    }
+
+    #[test]
+    fn free_bulk_handles_interleaved_pages() {
+        let mut cache =
+            unsafe { TestCache::<u64>::with_max_empty(TestFrames::new(2), 0) };
+        let per_page = cache.objects_per_page();
+        let mut out: Vec<_> =
+            (0..per_page * 2).map(|_| MaybeUninit::uninit()).collect();
+        assert_eq!(cache.alloc_bulk_with(&mut out, || 0), per_page * 2);
+        assert_eq!(cache.full_pages(), 2);
+
+        // Free alternately from each page, so that no two consecutive
+        // objects share a page.
+        let mut first = init_refs(&out);
+        let second = first.split_off(per_page);
+        let mut refs = Vec::new();
+        for (a, b) in first.into_iter().zip(second) {
+            refs.push(a);
+            refs.push(b);
+        }
+        unsafe { cache.free_bulk(refs) };
+        assert_eq!(cache.full_pages(), 0);
+        assert_eq!(cache.partial_pages(), 0);
+        assert_eq!(cache.frame_allocator().allocated(), 0);
+    }
+
+    mod colour {
+        use super::*;
+
+        /// An object type which leaves plenty of space for colouring.
+        type Object = [u8; 500];
+
+        /// Fills a new page, returning the offset of its first object within
+        /// the frame. The page is left full, so the next call gets a new page.
+        fn first_offset(cache: &mut TestCache<Object>) -> usize {
+            let per_page = cache.objects_per_page();
+            let refs: Vec<_> = (0..per_page)
+                .map(|_| cache.alloc([0; 500]).unwrap())
+                .collect();
+            let offset = refs
+                .iter()
+                .map(|r| r.as_ptr() as usize % FRAME_SIZE)
+                .min()
+                .unwrap();
+            assert_eq!(cache.partial_pages(), 0);
+            offset
+        }
+
+        #[test]
+        fn colour_range() {
+            let cache = unsafe { TestCache::<Object>::new(TestFrames::new(1)) };
+            let stats = cache.stats();
+            let per_page = cache.objects_per_page();
+            let slot = cache.layout().size();
+            let leftover = FRAME_SIZE - cache.slots_offset() - per_page * slot;
+            assert_eq!(stats.colours, leftover / CACHE_LINE_SIZE + 1);
+            assert!(stats.colours > 1);
+            assert_eq!(stats.next_colour, 0);
+            assert_eq!(stats.colour_align, CACHE_LINE_SIZE);
+        }
+
+        #[test]
+        fn pages_are_coloured() {
+            let mut cache =
+                unsafe { TestCache::<Object>::new(TestFrames::new(16)) };
+            let colours = cache.colours();
+            let base = first_offset(&mut cache);
+            for colour in 1..colours {
+                assert_eq!(cache.stats().next_colour, colour);
+                assert_eq!(
+                    first_offset(&mut cache),
+                    base + colour * CACHE_LINE_SIZE
+                );
+            }
+
+            // After using every colour, we wrap back around to the first.
+            assert_eq!(cache.stats().next_colour, 0);
+            assert_eq!(first_offset(&mut cache), base);
+        }
+
+        #[test]
+        fn failed_allocations_keep_colour() {
+            let mut cache =
+                unsafe { TestCache::<Object>::new(TestFrames::new(1)) };
+            first_offset(&mut cache);
+            assert_eq!(cache.stats().next_colour, 1);
+            assert!(cache.alloc([0; 500]).is_err());
+            assert!(cache.alloc([0; 500]).is_err());
+            assert_eq!(cache.stats().next_colour, 1);
+        }
+
+        #[test]
+        fn colour_align_is_configurable() {
+            let mut cache =
+                unsafe { TestCache::<Object>::new(TestFrames::new(16)) };
+            let default_colours = cache.colours();
+            cache.set_colour_align(16);
+            assert!(cache.colours() > default_colours);
+
+            let base = first_offset(&mut cache);
+            assert_eq!(first_offset(&mut cache), base + 16);
+        }
+
+        #[test]
+        fn colour_align_respects_slot_align() {
+            let mut cache =
+                unsafe { TestCache::<u64>::new(TestFrames::new(1)) };
+            cache.set_colour_align(1);
+            assert_eq!(cache.stats().colour_align, 8);
+        }
+    }
+
+    mod ctor {
+        use super::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Debug)]
+        struct Object {
+            constructed: bool,
+            uses: usize,
+        }
+
+        fn new_object() -> Object {
+            Object {
+                constructed: true,
+                uses: 0,
+            }
+        }
+
+        #[test]
+        fn objects_constructed_when_page_allocated() {
+            let mut cache = unsafe {
+                TestCache::with_ctor(TestFrames::new(1), new_object, None)
+            };
+            assert!(cache.caches_objects());
+            assert!(cache.layout().preserves_objects());
+            let plain = unsafe { TestCache::<Object>::new(TestFrames::new(1)) };
+            assert!(!plain.layout().preserves_objects());
+
+            let a = cache.alloc_constructed().unwrap();
+            assert!(a.constructed);
+            assert_eq!(a.uses, 0);
+            unsafe { cache.free(a) };
+        }
+
+        #[test]
+        fn freed_objects_keep_state() {
+            let mut cache = unsafe {
+                TestCache::with_ctor(TestFrames::new(1), new_object, None)
+            };
+            let mut a = cache.alloc_constructed().unwrap();
+            a.uses += 1;
+            let addr = a.as_ptr();
+            unsafe { cache.free(a) };
+
+            let b = cache.alloc_constructed().unwrap();
+            assert_eq!(b.as_ptr(), addr);
+            assert!(b.constructed);
+            assert_eq!(b.uses, 1);
+            unsafe { cache.free(b) };
+        }
+
+        #[test]
+        #[cfg(not(feature = "debug"))]
+        fn constructed_pages_hold_fewer_objects() {
+            let plain = unsafe { TestCache::<Object>::new(TestFrames::new(1)) };
+            let constructed = unsafe {
+                TestCache::with_ctor(TestFrames::new(1), new_object, None)
+            };
+            assert!(constructed.objects_per_page() < plain.objects_per_page());
+        }
+
+        #[test]
+        fn alloc_bulk_returns_constructed_objects() {
+            let mut cache = unsafe {
+                TestCache::with_ctor(TestFrames::new(1), new_object, None)
+            };
+            let mut out: Vec<_> =
+                (0..4).map(|_| MaybeUninit::uninit()).collect();
+            assert_eq!(cache.alloc_bulk_constructed(&mut out), 4);
+
+            let refs = init_refs(&out);
+            assert!(refs.iter().all(|r| r.constructed && r.uses == 0));
+            unsafe { cache.free_bulk(refs) };
+            assert_eq!(cache.empty_pages(), 1);
+        }
+
+        #[test]
+        #[should_panic(expected = "alloc_bulk_constructed requires a cache")]
+        fn alloc_bulk_constructed_requires_ctor() {
+            let mut cache =
+                unsafe { TestCache::<Object>::new(TestFrames::new(1)) };
+            let mut out = [MaybeUninit::uninit()];
+            cache.alloc_bulk_constructed(&mut out);
+        }
+
+        #[test]
+        fn alloc_bulk_with_survives_panic() {
+            static DTORS: AtomicUsize = AtomicUsize::new(0);
+
+            fn dtor(object: &mut Object) {
+                assert!(object.constructed);
+                DTORS.fetch_add(1, Ordering::SeqCst);
+            }
+
+            let frames = SharedFrames::new(1);
+            let mut cache = unsafe {
+                Cache::with_ctor(frames.clone(), new_object, Some(dtor))
+            };
+            let per_page = cache.objects_per_page();
+            let mut out: Vec<_> =
+                (0..4).map(|_| MaybeUninit::uninit()).collect();
+            let mut calls = 0;
+            let result =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    cache.alloc_bulk_with(&mut out, || {
+                        calls += 1;
+                        if calls == 3 {
+                            panic!("failed to make an object");
+                        }
+                        new_object()
+                    })
+                }));
+            assert!(result.is_err());
+            // Only the two cached objects which were replaced have been
+            // destroyed, and the two unfilled slots are free again.
+            assert_eq!(DTORS.load(Ordering::SeqCst), 2);
+            assert_eq!(cache.stats().active_objects, 2);
+
+            drop(cache);
+            assert_eq!(DTORS.load(Ordering::SeqCst), 2 + per_page);
+            assert_eq!(frames.allocated(), 0);
+        }
+
+        #[test]
+        #[should_panic]
+        fn alloc_constructed_requires_ctor() {
+            let mut cache =
+                unsafe { TestCache::<Object>::new(TestFrames::new(1)) };
+            let _ = cache.alloc_constructed();
+        }
+
+        #[test]
+        fn objects_destroyed_when_page_released() {
+            static CTORS: AtomicUsize = AtomicUsize::new(0);
+            static DTORS: AtomicUsize = AtomicUsize::new(0);
+
+            fn ctor() -> Object {
+                CTORS.fetch_add(1, Ordering::SeqCst);
+                new_object()
+            }
+
+            fn dtor(object: &mut Object) {
+                assert!(object.constructed);
+                DTORS.fetch_add(1, Ordering::SeqCst);
+            }
+
+            let mut cache = unsafe {
+                TestCache::with_ctor(TestFrames::new(1), ctor, Some(dtor))
+            };
+            cache.set_max_empty(0);
+            let per_page = cache.objects_per_page();
+
+            let refs: Vec<_> = (0..per_page)
+                .map(|_| cache.alloc_constructed().unwrap())
+                .collect();
+            assert_eq!(CTORS.load(Ordering::SeqCst), per_page);
+
+            // Freeing objects doesn't destroy them...
+            let mut refs = refs.into_iter();
+            unsafe { cache.free(refs.next().unwrap()) };
+            assert_eq!(DTORS.load(Ordering::SeqCst), 0);
+
+            // ...but releasing their page does.
+            for r in refs {
+                unsafe { cache.free(r) };
+            }
+            assert_eq!(cache.frame_allocator().allocated(), 0);
+            assert_eq!(DTORS.load(Ordering::SeqCst), per_page);
+            assert_eq!(CTORS.load(Ordering::SeqCst), per_page);
+        }
+
+        #[test]
+        fn objects_destroyed_when_cache_dropped() {
+            static DTORS: AtomicUsize = AtomicUsize::new(0);
+
+            fn dtor(object: &mut Object) {
+                assert!(object.constructed);
+                DTORS.fetch_add(1, Ordering::SeqCst);
+            }
+
+            let frames = SharedFrames::new(2);
+            let mut cache = unsafe {
+                Cache::with_ctor(frames.clone(), new_object, Some(dtor))
+            };
+            let per_page = cache.objects_per_page();
+
+            // Leave one page full, and one partially allocated.
+            let refs: Vec<_> = (0..per_page + 2)
+                .map(|_| cache.alloc_constructed().unwrap())
+                .collect();
+            unsafe { cache.free(refs.into_iter().last().unwrap()) };
+            assert_eq!(cache.full_pages(), 1);
+            assert_eq!(cache.partial_pages(), 1);
+            assert_eq!(DTORS.load(Ordering::SeqCst), 0);
+
+            drop(cache);
+            assert_eq!(frames.allocated(), 0);
+            assert_eq!(DTORS.load(Ordering::SeqCst), per_page * 2);
+        }
+    }
+}
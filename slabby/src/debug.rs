@@ -0,0 +1,289 @@
+//! Red-zone and poison checking for slab caches.
+//!
+//! When the `debug` feature is enabled, every object in a [`Cache`] is
+//! surrounded by _red zones_ of [`RED_ZONE_SIZE`] bytes, filled with
+//! [`RED_ZONE`]. Objects in caches which don't keep objects in their
+//! constructed state are also filled with [`POISON`] when they are freed.
+//!
+//! Whenever an object is allocated from or freed to a cache, its red zones
+//! are checked, and when an object is allocated, it is checked for poison.
+//! A damaged red zone indicates that something wrote past the bounds of an
+//! object, while damaged poison indicates that something wrote to an object
+//! after it was freed. Either is reported by panicking with the address of
+//! the object and the name of its cache.
+//!
+//! [`Cache`]: ../cache/struct.Cache.html
+//! [`RED_ZONE_SIZE`]: constant.RED_ZONE_SIZE.html
+//! [`RED_ZONE`]: constant.RED_ZONE.html
+//! [`POISON`]: constant.POISON.html
+use super::SlotLayout;
+use core::{mem, ptr::NonNull, slice};
+
+/// The minimum size of the red zone on either side of an object, in bytes.
+pub const RED_ZONE_SIZE: usize = 16;
+
+/// The byte pattern written to red zones.
+pub const RED_ZONE: u8 = 0xbb;
+
+/// The byte pattern written to freed objects.
+pub const POISON: u8 = 0x6b;
+
+/// Fill the red zones around the object at `ptr`, and poison the object if
+/// `poison` is `true`.
+///
+/// # Unsafety
+/// This function is unsafe because the caller must guarantee that `ptr`
+/// points to an object in a slot laid out according to `layout`.
+pub(crate) unsafe fn init<T>(
+    ptr: NonNull<T>,
+    layout: SlotLayout,
+    poison: bool,
+) {
+    before(ptr, layout).iter_mut().for_each(|b| *b = RED_ZONE);
+    after(ptr, layout).iter_mut().for_each(|b| *b = RED_ZONE);
+    if poison {
+        self::poison(ptr);
+    }
+}
+
+/// Fill the object at `ptr` with poison.
+///
+/// # Unsafety
+/// This function is unsafe because the caller must guarantee that `ptr`
+/// points to an object which has already been dropped.
+pub(crate) unsafe fn poison<T>(ptr: NonNull<T>) {
+    object(ptr).iter_mut().for_each(|b| *b = POISON);
+}
+
+/// Check the red zones around the object at `ptr`, and check that the
+/// object is still poisoned if `poisoned` is `true`.
+///
+/// # Panics
+/// If a red zone has been overwritten, or if `poisoned` is `true` and the
+/// object's poison has been overwritten.
+///
+/// # Unsafety
+/// This function is unsafe because the caller must guarantee that `ptr`
+/// points to an object in a slot laid out according to `layout`.
+pub(crate) unsafe fn check<T>(
+    cache: &str,
+    ptr: NonNull<T>,
+    layout: SlotLayout,
+    poisoned: bool,
+) {
+    if let Some(i) = find_damage(before(ptr, layout), RED_ZONE) {
+        panic!(
+            "slab corruption in cache `{}`: red zone before object at {:p} \
+             overwritten at offset -{}",
+            cache,
+            ptr,
+            layout.object_offset - i,
+        );
+    }
+    if let Some(i) = find_damage(after(ptr, layout), RED_ZONE) {
+        panic!(
+            "slab corruption in cache `{}`: red zone after object at {:p} \
+             overwritten at offset {}",
+            cache,
+            ptr,
+            mem::size_of::<T>() + i,
+        );
+    }
+    if poisoned {
+        if let Some(i) = find_damage(object(ptr), POISON) {
+            panic!(
+                "slab corruption in cache `{}`: freed object at {:p} \
+                 modified at offset {}",
+                cache, ptr, i,
+            );
+        }
+    }
+}
+
+#[inline]
+fn find_damage(bytes: &[u8], pattern: u8) -> Option<usize> {
+    bytes.iter().position(|&b| b != pattern)
+}
+
+#[inline]
+unsafe fn object<'a, T>(ptr: NonNull<T>) -> &'a mut [u8] {
+    slice::from_raw_parts_mut(ptr.as_ptr() as *mut u8, mem::size_of::<T>())
+}
+
+#[inline]
+unsafe fn before<'a, T>(ptr: NonNull<T>, layout: SlotLayout) -> &'a mut [u8] {
+    let start = (ptr.as_ptr() as *mut u8).sub(layout.object_offset);
+    slice::from_raw_parts_mut(start, layout.object_offset)
+}
+
+#[inline]
+unsafe fn after<'a, T>(ptr: NonNull<T>, layout: SlotLayout) -> &'a mut [u8] {
+    let start = (ptr.as_ptr() as *mut u8).add(mem::size_of::<T>());
+    slice::from_raw_parts_mut(start, layout.free_offset - mem::size_of::<T>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::Cache,
+        magazine::Depot,
+        off_slab::OffSlabCache,
+        test_util::{SharedFrames, TestFrames},
+    };
+    use alarm_base::LockedAlloc;
+    use core::ptr;
+
+    type TestCache = Cache<[u64; 4], TestFrames>;
+
+    fn cache() -> TestCache {
+        let mut cache = unsafe { Cache::new(TestFrames::new(4)) };
+        cache.set_name("test_objects");
+        cache
+    }
+
+    #[test]
+    fn objects_surrounded_by_red_zones() {
+        let layout = SlotLayout::red_zoned::<[u64; 4]>();
+        assert!(layout.object_offset >= RED_ZONE_SIZE);
+        assert!(layout.free_offset >= 32 + RED_ZONE_SIZE);
+
+        let mut cache = cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        let b = cache.alloc([5, 6, 7, 8]).unwrap();
+        assert!(b.as_ptr() as usize - a.as_ptr() as usize >= layout.size());
+        unsafe {
+            check(
+                "test_objects",
+                NonNull::new_unchecked(a.as_ptr()),
+                layout,
+                false,
+            );
+            cache.free(a);
+            cache.free(b);
+        }
+    }
+
+    #[test]
+    fn freed_objects_are_poisoned() {
+        let mut cache = cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        let ptr = a.as_ptr() as *const u8;
+        unsafe {
+            cache.free(a);
+            for i in 0..32 {
+                assert_eq!(*ptr.add(i), POISON);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cache `test_objects`: red zone after object")]
+    fn overflow_detected_on_free() {
+        let mut cache = cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        unsafe {
+            ptr::write((a.as_ptr() as *mut u8).add(32), 0);
+            cache.free(a);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cache `test_objects`: red zone before object")]
+    fn underflow_detected_on_free() {
+        let mut cache = cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        unsafe {
+            ptr::write((a.as_ptr() as *mut u8).sub(1), 0);
+            cache.free(a);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cache `test_objects`: freed object at")]
+    fn use_after_free_detected_on_alloc() {
+        let mut cache = cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        let ptr = a.as_ptr();
+        unsafe {
+            cache.free(a);
+            (*ptr)[2] = 0xdead;
+        }
+        let _ = cache.alloc([0; 4]);
+    }
+
+    #[test]
+    fn report_includes_address() {
+        let mut cache = cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        let addr = format!("{:p}", a.as_ptr());
+        unsafe { ptr::write((a.as_ptr() as *mut u8).add(40), 0) };
+        let msg =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                unsafe { cache.free(a) };
+            }))
+            .unwrap_err();
+        let msg = msg.downcast_ref::<String>().unwrap();
+        assert!(msg.contains(&addr), "{:?} should contain {}", msg, addr);
+        assert!(msg.contains("offset 40"), "{:?}", msg);
+    }
+
+    fn depot() -> LockedAlloc<Depot<[u64; 4], SharedFrames>> {
+        let mut cache = unsafe { Cache::new(SharedFrames::new(4)) };
+        cache.set_name("test_objects");
+        LockedAlloc::new(Depot::new(cache))
+    }
+
+    #[test]
+    #[should_panic(expected = "cache `test_objects`: red zone after object")]
+    fn overflow_detected_on_magazine_free() {
+        let depot = depot();
+        let mut cpu = depot.lock().cpu_cache();
+        let a = cpu.alloc(&depot, [1, 2, 3, 4]).unwrap();
+        unsafe {
+            ptr::write((a.as_ptr() as *mut u8).add(32), 0);
+            cpu.free(&depot, a);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cache `test_objects`: freed object at")]
+    fn use_after_free_detected_on_magazine_alloc() {
+        let depot = depot();
+        let mut cpu = depot.lock().cpu_cache();
+        let a = cpu.alloc(&depot, [1, 2, 3, 4]).unwrap();
+        let ptr = a.as_ptr();
+        unsafe {
+            cpu.free(&depot, a);
+            (*ptr)[2] = 0xdead;
+        }
+        // The object comes straight back out of the CPU's magazine.
+        let _ = cpu.alloc(&depot, [0; 4]);
+    }
+
+    fn off_slab_cache() -> OffSlabCache<[u64; 4], SharedFrames> {
+        let mut cache = unsafe { OffSlabCache::new(SharedFrames::new(4)) };
+        cache.set_name("test_objects");
+        cache
+    }
+
+    #[test]
+    #[should_panic(expected = "cache `test_objects`: red zone before object")]
+    fn underflow_detected_on_off_slab_free() {
+        let mut cache = off_slab_cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        unsafe { ptr::write((a.as_ptr() as *mut u8).sub(1), 0) };
+        cache.free(a);
+    }
+
+    #[test]
+    #[should_panic(expected = "cache `test_objects`: freed object at")]
+    fn use_after_free_detected_on_off_slab_alloc() {
+        let mut cache = off_slab_cache();
+        let a = cache.alloc([1, 2, 3, 4]).unwrap();
+        let ptr = a.as_ptr();
+        cache.free(a);
+        unsafe { (*ptr)[2] = 0xdead };
+        let _ = cache.alloc([0; 4]);
+    }
+}
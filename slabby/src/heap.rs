@@ -0,0 +1,446 @@
+//! A general-purpose allocator built from size-class slab caches.
+//!
+//! A [`Heap`] routes each allocation to one of a fixed set of power-of-two
+//! _size classes_, from [`MIN_CLASS_SIZE`] to [`MAX_CLASS_SIZE`] bytes, each
+//! of which is served by its own slab [`Cache`]. A request is served by the
+//! smallest size class which can hold an object of the requested size and
+//! alignment. Requests which are too large (or too strictly aligned) for any
+//! size class are served by allocating a contiguous range of whole frames
+//! directly from the frame allocator.
+//!
+//! Requests for more than one frame need a physically contiguous range, and
+//! are only as reliable as the frame allocator's `alloc_range`. The default
+//! `alloc_range` only succeeds when consecutive calls to `alloc` return
+//! consecutive frames, so a `Heap` built on an allocator which hands frames
+//! out in any other order (such as a LIFO free list) will fail every such
+//! request. Use a frame allocator which overrides `alloc_range`, such as
+//! `frame_alarm`'s `BitmapAllocator` or `BuddyAllocator`, if large
+//! allocations are needed.
+//!
+//! Since `Heap` implements `Alloc`, it may be wrapped in an
+//! `alarm_base::LockedAlloc` and used as a kernel's global allocator.
+//!
+//! [`Heap`]: struct.Heap.html
+//! [`Cache`]: ../cache/struct.Cache.html
+//! [`MIN_CLASS_SIZE`]: constant.MIN_CLASS_SIZE.html
+//! [`MAX_CLASS_SIZE`]: constant.MAX_CLASS_SIZE.html
+use super::{cache::SLABINFO_HEADER, Cache, SlabRef};
+use alarm_base::{FrameAllocator, FrameRange};
+use core::{
+    alloc::{Alloc, AllocErr, Layout},
+    cmp,
+    fmt,
+    ptr::NonNull,
+};
+use hal9000::mem::Page as MemPage;
+
+/// The size of the smallest size class, in bytes.
+pub const MIN_CLASS_SIZE: usize = 8;
+
+/// The size of the largest size class, in bytes.
+///
+/// Allocations larger than this are served by whole frames.
+pub const MAX_CLASS_SIZE: usize = 1024;
+
+/// The largest alignment guaranteed by any size class, in bytes.
+///
+/// Each size class is aligned to its size, up to this alignment. Allocations
+/// with stricter alignment requirements are served by whole frames.
+pub const MAX_CLASS_ALIGN: usize = 64;
+
+macro_rules! size_classes {
+    ($($block:ident, $field:ident: $size:expr, align $align:tt;)+) => {
+        $(
+            /// A block of memory handed out by a size class.
+            #[repr(C, align($align))]
+            struct $block([u8; $size]);
+        )+
+
+        /// A general-purpose allocator built from size-class slab caches.
+        ///
+        /// Allocations of more than one frame are served by the frame
+        /// allocator's `alloc_range`. Unless `A` overrides it to search for
+        /// contiguous frames, these fail whenever `A` does not hand out
+        /// frames in ascending address order. See the [module-level
+        /// documentation](index.html) for details.
+        ///
+        /// # Type Parameters
+        /// - `A`: the type of the frame allocator that provides pages to
+        ///   this heap's caches. Each size class holds its own clone of the
+        ///   frame allocator, so `A` should be a handle to a shared
+        ///   allocator, such as `&LockedAlloc`.
+        pub struct Heap<A>
+        where
+            A: FrameAllocator,
+        {
+            $($field: Cache<$block, A>,)+
+            frames: A,
+        }
+
+        impl<A> Heap<A>
+        where
+            A: FrameAllocator + Clone,
+        {
+            /// Returns a new `Heap` which allocates pages from `frames`.
+            ///
+            /// Each size class's cache is named `kmalloc-<size>`.
+            ///
+            /// # Unsafety
+            /// This function is unsafe because the caller must guarantee
+            /// that every frame allocated from `frames` is mapped at the
+            /// virtual address equal to its physical address, as for
+            /// `Cache::new`. Large allocations hand out pointers to the
+            /// frames directly.
+            pub unsafe fn new(frames: A) -> Self {
+                let mut heap = Heap {
+                    $($field: Cache::new(frames.clone()),)+
+                    frames,
+                };
+                $(
+                    heap.$field.set_name(
+                        concat!("kmalloc-", stringify!($size))
+                    );
+                )+
+                heap
+            }
+
+            /// Returns the size of the size class that would serve `layout`,
+            /// or `None` if `layout` would be served by a whole frame.
+            pub fn class_size(layout: &Layout) -> Option<usize> {
+                if layout.align() > MAX_CLASS_ALIGN {
+                    return None;
+                }
+                let size = cmp::max(layout.size(), layout.align());
+                $(
+                    if size <= $size {
+                        return Some($size);
+                    }
+                )+
+                None
+            }
+
+            /// Return every empty page in this heap's size classes to the
+            /// frame allocator.
+            ///
+            /// # Returns
+            /// The number of frames that were returned to the frame
+            /// allocator.
+            pub fn reclaim(&mut self) -> usize {
+                0 $(+ self.$field.reclaim())+
+            }
+
+            fn alloc_small(
+                &mut self,
+                size: usize,
+            ) -> Result<NonNull<u8>, AllocErr> {
+                $(
+                    if size == $size {
+                        return self.$field.alloc_slot().map(NonNull::cast);
+                    }
+                )+
+                unreachable!("not a size class: {}", size)
+            }
+
+            unsafe fn dealloc_small(&mut self, ptr: NonNull<u8>, size: usize) {
+                $(
+                    if size == $size {
+                        let slab_ref = SlabRef { ptr: ptr.cast() };
+                        return self.$field.free(slab_ref);
+                    }
+                )+
+                unreachable!("not a size class: {}", size)
+            }
+        }
+
+        /// Displays the statistics of every size class, one per line,
+        /// in the style of `/proc/slabinfo`.
+        impl<A> fmt::Display for Heap<A>
+        where
+            A: FrameAllocator,
+        {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", SLABINFO_HEADER)?;
+                $(write!(f, "\n{}", self.$field.stats())?;)+
+                Ok(())
+            }
+        }
+
+        impl<A> fmt::Debug for Heap<A>
+        where
+            A: FrameAllocator + fmt::Debug,
+        {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_struct("Heap")
+                    $(.field(stringify!($field), &self.$field))+
+                    .field("frames", &self.frames)
+                    .finish()
+            }
+        }
+    };
+}
+
+size_classes! {
+    Block8, size_8: 8, align 8;
+    Block16, size_16: 16, align 16;
+    Block32, size_32: 32, align 32;
+    Block64, size_64: 64, align 64;
+    Block128, size_128: 128, align 64;
+    Block256, size_256: 256, align 64;
+    Block512, size_512: 512, align 64;
+    Block1024, size_1024: 1024, align 64;
+}
+
+// ===== impl Heap =====
+
+impl<A> Heap<A>
+where
+    A: FrameAllocator + Clone,
+{
+    /// Borrow the frame allocator that provides pages to this heap.
+    #[inline]
+    pub fn frame_allocator(&self) -> &A {
+        &self.frames
+    }
+
+    /// Returns the number of frames needed to hold `layout`.
+    ///
+    /// # Returns
+    /// - `Some(usize)` if `layout` may be served by a range of frames.
+    /// - `None` if `layout` is more strictly aligned than a frame.
+    #[inline]
+    fn frames_for(layout: &Layout) -> Option<usize> {
+        if layout.align() > A::FRAME_SIZE {
+            return None;
+        }
+        Some(cmp::max(
+            1,
+            (layout.size() + A::FRAME_SIZE - 1) / A::FRAME_SIZE,
+        ))
+    }
+}
+
+unsafe impl<A> Alloc for Heap<A>
+where
+    A: FrameAllocator + Clone,
+{
+    unsafe fn alloc(
+        &mut self,
+        layout: Layout,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        if let Some(size) = Self::class_size(&layout) {
+            return self.alloc_small(size);
+        }
+
+        let count = Self::frames_for(&layout).ok_or(AllocErr)?;
+        let range = self.frames.alloc_range(count)?;
+        let base: usize = range.start_address().into();
+        Ok(NonNull::new_unchecked(base as *mut u8))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if let Some(size) = Self::class_size(&layout) {
+            return self.dealloc_small(ptr, size);
+        }
+
+        let addr = ptr.as_ptr() as usize;
+        let frame = A::Frame::containing(addr.into());
+        let count = Self::frames_for(&layout)
+            .expect("overaligned layouts are never allocated by this heap");
+        self.frames
+            .dealloc_range(FrameRange::new(frame, count))
+            .expect("frame allocator rejected frames allocated by this heap");
+    }
+
+    fn usable_size(&self, layout: &Layout) -> (usize, usize) {
+        match Self::class_size(layout) {
+            Some(size) => (layout.size(), size),
+            None => {
+                let count = Self::frames_for(layout).unwrap_or(1);
+                (layout.size(), count * A::FRAME_SIZE)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::BufferFrames,
+        test_util::{SharedFrames, TestFrame, FRAME_SIZE},
+    };
+    use alarm_base::LockedAlloc;
+    use std::{boxed::Box, vec::Vec};
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn requests_rounded_up_to_size_classes() {
+        type H = Heap<SharedFrames>;
+        assert_eq!(H::class_size(&layout(1, 1)), Some(8));
+        assert_eq!(H::class_size(&layout(8, 8)), Some(8));
+        assert_eq!(H::class_size(&layout(9, 1)), Some(16));
+        assert_eq!(H::class_size(&layout(100, 4)), Some(128));
+        assert_eq!(H::class_size(&layout(1024, 8)), Some(1024));
+        assert_eq!(H::class_size(&layout(1025, 8)), None);
+    }
+
+    #[test]
+    fn alignment_selects_larger_classes() {
+        type H = Heap<SharedFrames>;
+        assert_eq!(H::class_size(&layout(8, 32)), Some(32));
+        assert_eq!(H::class_size(&layout(8, 64)), Some(64));
+        assert_eq!(H::class_size(&layout(8, 128)), None);
+    }
+
+    #[test]
+    fn small_allocations_are_aligned() {
+        let frames = SharedFrames::new(16);
+        let mut heap = unsafe { Heap::new(frames.clone()) };
+        let mut ptrs = Vec::new();
+        for &(size, align) in &[(1, 1), (8, 8), (24, 16), (40, 64), (600, 8)] {
+            let layout = layout(size, align);
+            let ptr = unsafe { heap.alloc(layout) }.unwrap();
+            assert_eq!(ptr.as_ptr() as usize % align, 0);
+            ptrs.push((ptr, layout));
+        }
+        for (ptr, layout) in ptrs {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn size_classes_share_frames() {
+        let frames = SharedFrames::new(16);
+        let mut heap = unsafe { Heap::new(frames.clone()) };
+        let small = layout(16, 8);
+        let large = layout(512, 8);
+        let a = unsafe { heap.alloc(small) }.unwrap();
+        let b = unsafe { heap.alloc(small) }.unwrap();
+        let c = unsafe { heap.alloc(large) }.unwrap();
+
+        // Each size class gets its own page.
+        assert_eq!(frames.allocated(), 2);
+        assert_eq!(
+            a.as_ptr() as usize & !(FRAME_SIZE - 1),
+            b.as_ptr() as usize & !(FRAME_SIZE - 1)
+        );
+        assert_ne!(
+            a.as_ptr() as usize & !(FRAME_SIZE - 1),
+            c.as_ptr() as usize & !(FRAME_SIZE - 1)
+        );
+
+        unsafe {
+            heap.dealloc(a, small);
+            heap.dealloc(b, small);
+            heap.dealloc(c, large);
+        }
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let mut heap = unsafe { Heap::new(SharedFrames::new(4)) };
+        let layout = layout(32, 8);
+        let a = unsafe { heap.alloc(layout) }.unwrap();
+        unsafe { heap.dealloc(a, layout) };
+        let b = unsafe { heap.alloc(layout) }.unwrap();
+        assert_eq!(a, b);
+        unsafe { heap.dealloc(b, layout) };
+    }
+
+    #[test]
+    fn reclaim_returns_empty_pages() {
+        let frames = SharedFrames::new(4);
+        let mut heap = unsafe { Heap::new(frames.clone()) };
+        let ptrs: Vec<_> = [8, 64, 512]
+            .iter()
+            .map(|&size| {
+                let layout = layout(size, 8);
+                (unsafe { heap.alloc(layout) }.unwrap(), layout)
+            })
+            .collect();
+        assert_eq!(frames.allocated(), 3);
+        assert_eq!(heap.reclaim(), 0);
+
+        for (ptr, layout) in ptrs {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(frames.allocated(), 3);
+        assert_eq!(heap.reclaim(), 3);
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn large_allocations_use_whole_frames() {
+        let frames = SharedFrames::new(4);
+        let mut heap = unsafe { Heap::new(frames.clone()) };
+        let layout = layout(MAX_CLASS_SIZE + 1, 8);
+        let ptr = unsafe { heap.alloc(layout) }.unwrap();
+        assert_eq!(ptr.as_ptr() as usize % FRAME_SIZE, 0);
+        assert_eq!(frames.allocated(), 1);
+        assert_eq!(heap.usable_size(&layout), (layout.size(), FRAME_SIZE));
+
+        unsafe { heap.dealloc(ptr, layout) };
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn overaligned_allocations_use_whole_frames() {
+        let frames = SharedFrames::new(4);
+        let mut heap = unsafe { Heap::new(frames.clone()) };
+        let layout = layout(8, 256);
+        let ptr = unsafe { heap.alloc(layout) }.unwrap();
+        assert_eq!(ptr.as_ptr() as usize % FRAME_SIZE, 0);
+        unsafe { heap.dealloc(ptr, layout) };
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn allocations_larger_than_a_frame_use_ranges() {
+        // Frames from the host heap are rarely contiguous, so allocate them
+        // from a buffer instead.
+        let buffer = Box::leak(vec![0u8; 5 * FRAME_SIZE].into_boxed_slice());
+        let frames = LockedAlloc::new(BufferFrames::<TestFrame>::new(buffer));
+        let capacity = frames.lock().capacity();
+        let mut heap = unsafe { Heap::new(&frames) };
+        let layout = layout(2 * FRAME_SIZE + 1, 8);
+        let ptr = unsafe { heap.alloc(layout) }.unwrap();
+        assert_eq!(ptr.as_ptr() as usize % FRAME_SIZE, 0);
+        assert_eq!(frames.lock().free_frames(), capacity - 3);
+        assert_eq!(heap.usable_size(&layout), (layout.size(), 3 * FRAME_SIZE));
+        unsafe { ptr.as_ptr().write_bytes(0xaa, layout.size()) };
+
+        unsafe { heap.dealloc(ptr, layout) };
+        assert_eq!(frames.lock().free_frames(), capacity);
+    }
+
+    #[test]
+    fn allocations_aligned_past_a_frame_fail() {
+        let frames = SharedFrames::new(4);
+        let mut heap = unsafe { Heap::new(frames.clone()) };
+        let layout = layout(8, 2 * FRAME_SIZE);
+        assert!(unsafe { heap.alloc(layout) }.is_err());
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn display_lists_size_classes() {
+        let frames = SharedFrames::new(16);
+        let mut heap = unsafe { Heap::new(frames.clone()) };
+        let ptr = unsafe { heap.alloc(layout(24, 8)).unwrap() };
+
+        let report = heap.to_string();
+        let mut lines = report.lines();
+        assert_eq!(lines.next(), Some(SLABINFO_HEADER));
+        let classes: Vec<_> = lines
+            .map(|line| line.split_whitespace().take(2).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(classes.len(), 8);
+        assert_eq!(classes[0], vec!["kmalloc-8", "0"]);
+        assert_eq!(classes[2], vec!["kmalloc-32", "1"]);
+        assert_eq!(classes[7], vec!["kmalloc-1024", "0"]);
+        unsafe { heap.dealloc(ptr, layout(24, 8)) };
+    }
+}
@@ -0,0 +1,503 @@
+//! Index-addressed slab storage.
+//!
+//! Unlike the pointer-handing [`Slab`] in the crate root, a keyed [`Slab`]
+//! hands out [`Key`]s, which identify an entry by its index in the slab
+//! together with a _generation_ counter. Each time an entry is freed, its
+//! generation is incremented, so a key to a removed value will never refer to
+//! a value inserted into the same entry later. Looking up a stale key simply
+//! returns `None`.
+//!
+//! This makes keyed slabs well-suited to tables of kernel objects (such as
+//! processes or threads) which are referred to by ID, where an ID may outlive
+//! the object it refers to.
+//!
+//! A keyed slab stores its [`Entry`]s in a region of memory provided by the
+//! caller, such as a frame, and never allocates.
+//!
+//! [`Slab`]: struct.Slab.html
+//! [`Key`]: struct.Key.html
+//! [`Entry`]: ../enum.Entry.html
+use super::{round_up, Entry};
+use core::{
+    alloc::AllocErr,
+    fmt,
+    iter,
+    mem,
+    ptr::{self, NonNull, Unique},
+    slice,
+};
+use hal9000::mem::Page as MemPage;
+
+/// A key identifying a value in a keyed [`Slab`].
+///
+/// [`Slab`]: struct.Slab.html
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Key {
+    /// The index of the key's entry in the slab.
+    pub index: usize,
+
+    /// The generation of the key's entry when the key was issued.
+    pub generation: usize,
+}
+
+/// A slab of values of type `T`, addressed by [`Key`].
+///
+/// [`Key`]: struct.Key.html
+pub struct Slab<T> {
+    /// Pointer to the first entry in the slab.
+    entries: Unique<Entry<T>>,
+
+    /// The number of entries that fit in the slab's memory.
+    capacity: usize,
+
+    /// The number of entries that have been initialized.
+    ///
+    /// Entries past this index have never been filled, so they are not
+    /// linked into the free list.
+    initialized: usize,
+
+    /// The index of the first free entry.
+    ///
+    /// If this is equal to `initialized`, the free list is empty, and the
+    /// next value is inserted into the first uninitialized entry.
+    next_free: usize,
+
+    /// The number of values currently stored in the slab.
+    len: usize,
+}
+
+/// An iterator over the values in a keyed [`Slab`].
+///
+/// Returned by [`Slab::iter`].
+///
+/// [`Slab`]: struct.Slab.html
+/// [`Slab::iter`]: struct.Slab.html#method.iter
+pub struct Iter<'a, T: 'a> {
+    entries: iter::Enumerate<slice::Iter<'a, Entry<T>>>,
+}
+
+/// An iterator over mutable references to the values in a keyed [`Slab`].
+///
+/// Returned by [`Slab::iter_mut`].
+///
+/// [`Slab`]: struct.Slab.html
+/// [`Slab::iter_mut`]: struct.Slab.html#method.iter_mut
+pub struct IterMut<'a, T: 'a> {
+    entries: iter::Enumerate<slice::IterMut<'a, Entry<T>>>,
+}
+
+// ===== impl Slab =====
+
+impl<T> Slab<T> {
+    /// Construct a new `Slab` in the `size` bytes of memory starting at
+    /// `start`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// region of memory starting at `start` is valid for `size` bytes, and
+    /// that nothing else will access that memory for as long as the slab
+    /// exists.
+    pub unsafe fn from_raw_parts(start: NonNull<u8>, size: usize) -> Self {
+        let addr = start.as_ptr() as usize;
+        let offset = round_up(addr, mem::align_of::<Entry<T>>()) - addr;
+        let capacity = size.saturating_sub(offset) / mem::size_of::<Entry<T>>();
+        Slab {
+            entries: Unique::new_unchecked((addr + offset) as *mut Entry<T>),
+            capacity,
+            initialized: 0,
+            next_free: 0,
+            len: 0,
+        }
+    }
+
+    /// Construct a new `Slab` spanning the frame `frame`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that
+    /// `frame` is mapped, and that nothing else will access the frame for
+    /// as long as the slab exists.
+    pub unsafe fn from_frame<F: MemPage>(frame: &F) -> Self {
+        let base: usize = frame.base().into();
+        Self::from_raw_parts(NonNull::new_unchecked(base as *mut u8), F::SIZE)
+    }
+
+    /// Returns the number of values this slab can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of values currently stored in this slab.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if no values are currently stored in this slab.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns `true` if this slab cannot hold any more values.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.len == self.capacity
+    }
+
+    /// Insert `value` into a free entry in this slab.
+    ///
+    /// # Returns
+    /// - `Ok(Key)` identifying the inserted value, if the slab has free
+    ///   entries.
+    /// - `Err(AllocErr)` if the slab is full.
+    pub fn insert(&mut self, value: T) -> Result<Key, AllocErr> {
+        let index = self.next_free;
+        let generation = if index < self.initialized {
+            match *self.entry(index) {
+                Entry::Free { next, generation } => {
+                    self.next_free = next;
+                    generation
+                },
+                Entry::Present { .. } => {
+                    unreachable!("free list points to a present entry")
+                },
+            }
+        } else if index < self.capacity {
+            self.initialized += 1;
+            self.next_free = self.initialized;
+            0
+        } else {
+            return Err(AllocErr);
+        };
+
+        unsafe {
+            ptr::write(
+                self.entry_ptr(index),
+                Entry::Present { generation, value },
+            );
+        }
+        self.len += 1;
+        Ok(Key { index, generation })
+    }
+
+    /// Returns `true` if `key` refers to a value in this slab.
+    #[inline]
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns a reference to the value identified by `key`, or `None` if
+    /// that value has been removed.
+    pub fn get(&self, key: Key) -> Option<&T> {
+        if key.index >= self.initialized {
+            return None;
+        }
+        match *self.entry(key.index) {
+            Entry::Present {
+                generation,
+                ref value,
+            } if generation == key.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns a mutable reference to the value identified by `key`, or
+    /// `None` if that value has been removed.
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        if key.index >= self.initialized {
+            return None;
+        }
+        match *self.entry_mut(key.index) {
+            Entry::Present {
+                generation,
+                ref mut value,
+            } if generation == key.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Remove the value identified by `key` from this slab.
+    ///
+    /// # Returns
+    /// - `Some(T)` if `key` referred to a value in this slab.
+    /// - `None` if that value has already been removed.
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        if !self.contains(key) {
+            return None;
+        }
+        let free = Entry::Free {
+            next: self.next_free,
+            generation: key.generation.wrapping_add(1),
+        };
+        let entry = mem::replace(self.entry_mut(key.index), free);
+        self.next_free = key.index;
+        self.len -= 1;
+        match entry {
+            Entry::Present { value, .. } => Some(value),
+            Entry::Free { .. } => unreachable!("removed a free entry"),
+        }
+    }
+
+    /// Returns an iterator over the keys and values in this slab, in index
+    /// order.
+    pub fn iter<'a>(&'a self) -> Iter<'a, T> {
+        let entries = unsafe {
+            slice::from_raw_parts(self.entries.as_ptr(), self.initialized)
+        };
+        Iter {
+            entries: entries.iter().enumerate(),
+        }
+    }
+
+    /// Returns an iterator over the keys and mutable references to the values
+    /// in this slab, in index order.
+    pub fn iter_mut<'a>(&'a mut self) -> IterMut<'a, T> {
+        let entries = unsafe {
+            slice::from_raw_parts_mut(self.entries.as_ptr(), self.initialized)
+        };
+        IterMut {
+            entries: entries.iter_mut().enumerate(),
+        }
+    }
+
+    #[inline]
+    fn entry_ptr(&self, index: usize) -> *mut Entry<T> {
+        debug_assert!(index < self.capacity);
+        unsafe { self.entries.as_ptr().add(index) }
+    }
+
+    #[inline]
+    fn entry(&self, index: usize) -> &Entry<T> {
+        debug_assert!(index < self.initialized);
+        unsafe { &*self.entry_ptr(index) }
+    }
+
+    #[inline]
+    fn entry_mut(&mut self, index: usize) -> &mut Entry<T> {
+        debug_assert!(index < self.initialized);
+        unsafe { &mut *self.entry_ptr(index) }
+    }
+}
+
+impl<T> Drop for Slab<T> {
+    fn drop(&mut self) {
+        for i in 0..self.initialized {
+            unsafe { ptr::drop_in_place(self.entry_ptr(i)) }
+        }
+    }
+}
+
+impl<T> fmt::Debug for Slab<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Slab")
+            .field("entries", &self.entries.as_ptr())
+            .field("capacity", &self.capacity)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+// ===== impl Iter =====
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Key, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (index, entry) in &mut self.entries {
+            if let Entry::Present {
+                generation,
+                ref value,
+            } = *entry
+            {
+                return Some((Key { index, generation }, value));
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T> fmt::Debug for Iter<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Iter").finish()
+    }
+}
+
+// ===== impl IterMut =====
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = (Key, &'a mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (index, entry) in &mut self.entries {
+            if let Entry::Present {
+                generation,
+                ref mut value,
+            } = *entry
+            {
+                return Some((Key { index, generation }, value));
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T> fmt::Debug for IterMut<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IterMut").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{rc::Rc, vec::Vec};
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn with_slab<T, F>(f: F)
+    where
+        F: FnOnce(&mut Slab<T>),
+    {
+        // Aligned for `Entry<T>`, so that capacities are exact.
+        let mut memory = vec![0u64; PAGE_SIZE / 8];
+        let start = NonNull::new(memory.as_mut_ptr() as *mut u8).unwrap();
+        let mut slab = unsafe { Slab::from_raw_parts(start, PAGE_SIZE) };
+        f(&mut slab);
+    }
+
+    #[test]
+    fn capacity_fills_page() {
+        with_slab::<u64, _>(|slab| {
+            let entry = mem::size_of::<Entry<u64>>();
+            assert_eq!(slab.capacity(), PAGE_SIZE / entry);
+            assert!(slab.is_empty());
+        });
+    }
+
+    #[test]
+    fn insert_and_get() {
+        with_slab(|slab| {
+            let a = slab.insert(1usize).unwrap();
+            let b = slab.insert(2usize).unwrap();
+            assert_ne!(a, b);
+            assert_eq!(slab.get(a), Some(&1));
+            assert_eq!(slab.get(b), Some(&2));
+            assert_eq!(slab.len(), 2);
+
+            *slab.get_mut(a).unwrap() += 10;
+            assert_eq!(slab.get(a), Some(&11));
+        });
+    }
+
+    #[test]
+    fn remove_returns_value() {
+        with_slab(|slab| {
+            let a = slab.insert(1usize).unwrap();
+            assert_eq!(slab.remove(a), Some(1));
+            assert_eq!(slab.remove(a), None);
+            assert!(slab.is_empty());
+        });
+    }
+
+    #[test]
+    fn stale_keys_do_not_alias() {
+        with_slab(|slab| {
+            let a = slab.insert(1usize).unwrap();
+            slab.remove(a).unwrap();
+
+            // The freed entry is reused, with a new generation.
+            let b = slab.insert(2usize).unwrap();
+            assert_eq!(a.index, b.index);
+            assert_ne!(a.generation, b.generation);
+
+            assert!(!slab.contains(a));
+            assert_eq!(slab.get(a), None);
+            assert_eq!(slab.get_mut(a), None);
+            assert_eq!(slab.remove(a), None);
+            assert_eq!(slab.get(b), Some(&2));
+        });
+    }
+
+    #[test]
+    fn keys_out_of_range() {
+        with_slab::<usize, _>(|slab| {
+            let key = Key {
+                index: slab.capacity() + 1,
+                generation: 0,
+            };
+            assert_eq!(slab.get(key), None);
+            assert_eq!(slab.remove(key), None);
+        });
+    }
+
+    #[test]
+    fn insert_until_full() {
+        with_slab(|slab| {
+            let keys: Vec<_> = (0..slab.capacity())
+                .map(|i| slab.insert(i).expect("slab should not be full"))
+                .collect();
+            assert!(slab.is_full());
+            assert!(slab.insert(0).is_err());
+
+            for (i, &key) in keys.iter().enumerate() {
+                assert_eq!(slab.get(key), Some(&i));
+            }
+
+            // Free entries are reused in LIFO order.
+            slab.remove(keys[3]).unwrap();
+            slab.remove(keys[7]).unwrap();
+            assert_eq!(slab.insert(0).unwrap().index, 7);
+            assert_eq!(slab.insert(0).unwrap().index, 3);
+            assert!(slab.is_full());
+        });
+    }
+
+    #[test]
+    fn unaligned_memory() {
+        let mut memory = vec![0u64; PAGE_SIZE / 8];
+        let start = NonNull::new(memory.as_mut_ptr() as *mut u8).unwrap();
+        let start = unsafe { NonNull::new_unchecked(start.as_ptr().add(1)) };
+        let mut slab: Slab<u64> =
+            unsafe { Slab::from_raw_parts(start, PAGE_SIZE - 1) };
+        let entry = mem::size_of::<Entry<u64>>();
+        assert_eq!(slab.capacity(), (PAGE_SIZE - 8) / entry);
+
+        let key = slab.insert(0xfeed).unwrap();
+        assert_eq!(slab.get(key), Some(&0xfeed));
+    }
+
+    #[test]
+    fn iter_skips_free_entries() {
+        with_slab(|slab| {
+            let keys: Vec<_> =
+                (0..6usize).map(|i| slab.insert(i).unwrap()).collect();
+            slab.remove(keys[1]).unwrap();
+            slab.remove(keys[4]).unwrap();
+
+            let live: Vec<_> = slab.iter().map(|(k, &v)| (k, v)).collect();
+            assert_eq!(
+                live,
+                vec![(keys[0], 0), (keys[2], 2), (keys[3], 3), (keys[5], 5)]
+            );
+
+            for (_, value) in slab.iter_mut() {
+                *value *= 10;
+            }
+            assert_eq!(slab.get(keys[3]), Some(&30));
+            assert_eq!(slab.iter().count(), slab.len());
+        });
+    }
+
+    #[test]
+    fn drop_drops_values() {
+        let value = Rc::new(());
+        with_slab(|slab| {
+            let a = slab.insert(value.clone()).unwrap();
+            slab.insert(value.clone()).unwrap();
+            slab.remove(a).unwrap();
+            assert_eq!(Rc::strong_count(&value), 2);
+        });
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
@@ -1,31 +1,844 @@
+// ••• ALARM: the SOS memory allocator
+// --- by Eliza Weisman (eliza@elizas.website)
+// ••• and the SOS contributors
+//
+//  Copyright (c) 2018 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
 //! A typed slab allocator suitable for use with `#![no_std]`.
-#![no_std]
-#![feature(ptr_internals)]
+//!
+//! A slab allocator carves a region of memory into equally-sized slots, each
+//! large enough to hold a single `T`. Unallocated slots are threaded into an
+//! intrusive free list stored _in the slots themselves_, so allocating and
+//! freeing an object are both O(1) and never require a heap.
+// Use `no_std` attribute unless we are running tests.
+#![cfg_attr(not(test), no_std)]
+#![feature(allocator_api, const_fn, ptr_internals)]
 
-use core::ptr::Unique;
+extern crate alarm_base;
+#[cfg(test)]
+extern crate core;
+extern crate hal9000;
+extern crate intruder_alarm;
+
+use core::{
+    alloc::AllocErr,
+    cmp,
+    fmt,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull, Unique},
+};
+use intruder_alarm::{
+    list::{self, Links},
+    stack::{self, Stack},
+    Link,
+    OwningRef,
+    UnsafeRef,
+};
+
+pub mod boxed;
+pub mod buffer;
+pub mod cache;
+#[cfg(feature = "debug")]
+pub mod debug;
+pub mod heap;
+pub mod keyed;
+pub mod lock_free;
+pub mod magazine;
+pub mod off_slab;
+#[cfg(test)]
+mod test_util;
+
+pub use self::{
+    boxed::SlabBox,
+    cache::Cache,
+    heap::Heap,
+    off_slab::OffSlabCache,
+};
 
-#[derive(Clone)]
+/// An entry in a [`keyed::Slab`].
+///
+/// [`keyed::Slab`]: keyed/struct.Slab.html
+#[derive(Clone, Debug)]
 pub enum Entry<T> {
     /// A free entry.
-    Free,
+    Free {
+        /// The index of the next free entry in the slab.
+        next: usize,
+        /// The generation that will be assigned to this entry when it is
+        /// next filled.
+        generation: usize,
+    },
     /// A filled entry.
-    Present(T),
+    Present {
+        /// The generation of the key that refers to this entry.
+        generation: usize,
+        /// The value stored in this entry.
+        value: T,
+    },
 }
 
+/// A page of memory carved into slots for objects of type `T`.
+///
+/// Each slot holds a `T` while it is allocated, and a free-list node while it
+/// is free, so unallocated slots are linked together into an intrusive
+/// [`Stack`] without requiring any additional memory. Where the free-list
+/// node is stored within the slot is described by the page's
+/// [`SlotLayout`].
+///
+/// [`Stack`]: ../intruder_alarm/stack/struct.Stack.html
+/// [`SlotLayout`]: struct.SlotLayout.html
 pub struct Page<T: Sized> {
     /// Pointer to the head of the page.
     head: Unique<T>,
 
-    /// Length of the page.
+    /// Length of the page, in slots.
     len: usize,
-    /*    next: NonNull<Page<T>>,
-     *    prev: NonNull<Page<T>>, */
+
+    /// The layout of each slot in the page.
+    layout: SlotLayout,
+
+    /// Stack of free slots in this page.
+    free: FreeList,
+
+    /// Links to the next and previous pages in a cache's page list.
+    links: Links<Page<T>>,
+}
+
+/// Describes the layout of the slots in a [`Page`].
+///
+/// [`Page`]: struct.Page.html
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SlotLayout {
+    /// The size of each slot, in bytes.
+    size: usize,
+
+    /// The alignment of each slot, in bytes.
+    align: usize,
+
+    /// The offset of the free-list node from the start of the object in a
+    /// free slot, in bytes.
+    free_offset: usize,
+
+    /// The offset of the object from the start of its slot, in bytes.
+    object_offset: usize,
+
+    /// Whether objects keep their constructed state while their slot is
+    /// free.
+    preserves_objects: bool,
+}
+
+/// A slab allocator for objects of type `T`.
+///
+/// A `Slab` manages a single [`Page`] of memory, which is provided by the
+/// caller when the slab is constructed.
+///
+/// [`Page`]: struct.Page.html
+pub struct Slab<T: Sized> {
+    page: Page<T>,
+}
+
+/// An iterator over the allocated slots in a [`Page`].
+///
+/// Returned by [`Page::allocated`].
+///
+/// [`Page`]: struct.Page.html
+/// [`Page::allocated`]: struct.Page.html#method.allocated
+pub struct Allocated<'a, T: 'a> {
+    page: &'a Page<T>,
+
+    /// The index of the first slot in the current window of slots.
+    window: usize,
+
+    /// A bitmap of the allocated slots in the current window which have not
+    /// yet been yielded.
+    allocated: usize,
+}
+
+/// A reference to an object allocated in a [`Slab`].
+///
+/// Dropping a `SlabRef` does _not_ free the referenced object; it must be
+/// returned to the slab it was allocated from using [`Slab::free`].
+///
+/// [`Slab`]: struct.Slab.html
+/// [`Slab::free`]: struct.Slab.html#method.free
+#[must_use]
+pub struct SlabRef<T> {
+    ptr: NonNull<T>,
+}
+
+/// A free slot in a `Page`.
+///
+/// Free slots are linked together into a stack, with the list node stored
+/// in the free slot's memory.
+struct FreeSlot {
+    next: Link<FreeSlot>,
+}
+
+type FreeList = Stack<FreeSlot, FreeSlot, UnsafeRef<FreeSlot>>;
+
+/// The number of slots whose allocation state fits in one `usize` bitmap.
+const WINDOW_SLOTS: usize = mem::size_of::<usize>() * 8;
+
+// ===== impl Entry =====
+
+impl<T> Entry<T> {
+    /// Returns the generation of this entry.
+    #[inline]
+    pub fn generation(&self) -> usize {
+        match *self {
+            Entry::Free { generation, .. } => generation,
+            Entry::Present { generation, .. } => generation,
+        }
+    }
+
+    /// Returns `true` if this entry holds a value.
+    #[inline]
+    pub fn is_present(&self) -> bool {
+        match *self {
+            Entry::Present { .. } => true,
+            Entry::Free { .. } => false,
+        }
+    }
+}
+
+// ===== impl SlotLayout =====
+
+impl SlotLayout {
+    /// Returns the most compact slot layout for objects of type `T`.
+    ///
+    /// The free-list node is stored in the first bytes of each free slot,
+    /// overwriting whatever object previously occupied that slot.
+    pub fn of<T>() -> Self {
+        let align = cmp::max(mem::align_of::<T>(), mem::align_of::<FreeSlot>());
+        let size = cmp::max(mem::size_of::<T>(), mem::size_of::<FreeSlot>());
+        SlotLayout {
+            size: round_up(size, align),
+            align,
+            free_offset: 0,
+            object_offset: 0,
+            preserves_objects: false,
+        }
+    }
+
+    /// Returns a slot layout for objects of type `T` which preserves the
+    /// contents of free slots.
+    ///
+    /// The free-list node is stored _after_ the object in each slot, so an
+    /// object's state is preserved while its slot is free. This makes each
+    /// slot larger than the layout returned by [`SlotLayout::of`].
+    ///
+    /// [`SlotLayout::of`]: #method.of
+    pub fn preserving<T>() -> Self {
+        let align = cmp::max(mem::align_of::<T>(), mem::align_of::<FreeSlot>());
+        let free_offset =
+            round_up(mem::size_of::<T>(), mem::align_of::<FreeSlot>());
+        SlotLayout {
+            size: round_up(free_offset + mem::size_of::<FreeSlot>(), align),
+            align,
+            free_offset,
+            object_offset: 0,
+            preserves_objects: true,
+        }
+    }
+
+    /// Returns a slot layout for objects of type `T` with a red zone on
+    /// either side of each object.
+    ///
+    /// Like [`SlotLayout::preserving`], the free-list node is stored after
+    /// the object, so that freed objects may be poisoned. The returned layout
+    /// does not preserve objects; caches which keep objects in their
+    /// constructed state mark their red-zoned layouts as preserving them.
+    ///
+    /// [`SlotLayout::preserving`]: #method.preserving
+    #[cfg(feature = "debug")]
+    pub fn red_zoned<T>() -> Self {
+        let align = cmp::max(mem::align_of::<T>(), mem::align_of::<FreeSlot>());
+        let object_offset = round_up(debug::RED_ZONE_SIZE, align);
+        let free_offset = round_up(
+            mem::size_of::<T>() + debug::RED_ZONE_SIZE,
+            mem::align_of::<FreeSlot>(),
+        );
+        SlotLayout {
+            size: round_up(
+                object_offset + free_offset + mem::size_of::<FreeSlot>(),
+                align,
+            ),
+            align,
+            free_offset,
+            object_offset,
+            preserves_objects: false,
+        }
+    }
+
+    /// Returns the size of each slot, in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the alignment of each slot, in bytes.
+    #[inline]
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    /// Returns `true` if objects keep their state while their slot is free.
+    #[inline]
+    pub fn preserves_objects(&self) -> bool {
+        self.preserves_objects
+    }
+}
+
+// ===== impl Page =====
+
+impl<T> Page<T> {
+    /// Construct a new `Page` in the `size` bytes of memory starting at
+    /// `start`, with slots laid out according to `layout`.
+    ///
+    /// All of the page's slots are initially free.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// region of memory starting at `start` is valid for `size` bytes, and
+    /// that nothing else will access that memory for as long as the page
+    /// exists.
+    pub unsafe fn new(
+        start: NonNull<u8>,
+        size: usize,
+        layout: SlotLayout,
+    ) -> Self {
+        let offset = start.as_ptr().align_offset(layout.align);
+        let len = size.saturating_sub(offset) / layout.size;
+        let head = start.as_ptr().add(offset) as *mut T;
+        let mut page = Page {
+            head: Unique::new_unchecked(head),
+            len,
+            layout,
+            free: Stack::new(),
+            links: Links::new(),
+        };
+        // Push the slots in reverse order, so that the first slot in the page
+        // will be the first one allocated.
+        for i in (0..len).rev() {
+            let slot = page.slot(i);
+            page.push_free(slot);
+        }
+        page
+    }
+
+    /// Returns the number of slots in this page.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the layout of this page's slots.
+    #[inline]
+    pub fn layout(&self) -> SlotLayout {
+        self.layout
+    }
+
+    /// Returns the number of allocated objects in this page.
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.len - self.free.len()
+    }
+
+    /// Returns `true` if no objects are allocated in this page.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.free.len() == self.len
+    }
+
+    /// Returns `true` if every slot in this page is allocated.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.free.is_empty()
+    }
+
+    /// Returns `true` if `ptr` points to a slot in this page.
+    pub fn contains(&self, ptr: *const T) -> bool {
+        let start = self.head.as_ptr() as usize + self.layout.object_offset;
+        let end = start + self.len * self.layout.size;
+        let addr = ptr as usize;
+        addr >= start && addr < end && (addr - start) % self.layout.size == 0
+    }
+
+    /// Allocate an uninitialized slot from this page.
+    ///
+    /// # Returns
+    /// - `Some(NonNull<T>)` pointing to the allocated slot, if the page has
+    ///   free slots.
+    /// - `None` if the page is full.
+    pub fn alloc(&mut self) -> Option<NonNull<T>> {
+        let free_offset = self.layout.free_offset;
+        self.free.pop_node().map(|node| unsafe {
+            let slot = (node.into_ptr() as *mut u8).sub(free_offset);
+            NonNull::new_unchecked(slot as *mut T)
+        })
+    }
+
+    /// Return a slot to this page's free list.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was allocated from this page, that it has not already been freed, and
+    /// that any value in the slot has already been dropped (unless the page's
+    /// layout preserves objects in free slots).
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<T>) {
+        debug_assert!(
+            self.contains(ptr.as_ptr()),
+            "pointer {:p} was not allocated from this page",
+            ptr
+        );
+        self.push_free(ptr);
+    }
+
+    /// Returns an iterator over pointers to the allocated objects in this
+    /// page, in address order.
+    ///
+    /// Allocated slots are found by walking the page's free list once for
+    /// each word-sized window of slots in the page, so this is relatively
+    /// slow; it is intended for debugging rather than allocation paths.
+    pub fn allocated<'a>(&'a self) -> Allocated<'a, T> {
+        Allocated {
+            page: self,
+            window: 0,
+            allocated: self.allocated_in(0),
+        }
+    }
+
+    /// Returns a bitmap of the allocated slots among the `WINDOW_SLOTS`
+    /// slots starting at `start`.
+    fn allocated_in(&self, start: usize) -> usize {
+        let len = cmp::min(self.len.saturating_sub(start), WINDOW_SLOTS);
+        if len == 0 || self.is_empty() {
+            return 0;
+        }
+        let mut allocated = if len == WINDOW_SLOTS {
+            !0
+        } else {
+            (1 << len) - 1
+        };
+
+        let first = self.head.as_ptr() as usize
+            + self.layout.object_offset
+            + self.layout.free_offset;
+        for slot in self.free.iter() {
+            let index =
+                (slot as *const FreeSlot as usize - first) / self.layout.size;
+            if index >= start && index < start + len {
+                allocated &= !(1 << (index - start));
+            }
+        }
+        allocated
+    }
+
+    /// Returns a pointer to the object in the slot at `index`.
+    #[inline]
+    unsafe fn slot(&self, index: usize) -> NonNull<T> {
+        let offset = index * self.layout.size + self.layout.object_offset;
+        let slot = (self.head.as_ptr() as *mut u8).add(offset);
+        NonNull::new_unchecked(slot as *mut T)
+    }
+
+    #[inline]
+    unsafe fn push_free(&mut self, ptr: NonNull<T>) {
+        let node = (ptr.as_ptr() as *mut u8).add(self.layout.free_offset)
+            as *mut FreeSlot;
+        ptr::write(node, FreeSlot { next: Link::none() });
+        self.free
+            .push_node(UnsafeRef::from(NonNull::new_unchecked(node)));
+    }
+}
+
+impl<T> fmt::Debug for Page<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Page")
+            .field("head", &self.head.as_ptr())
+            .field("len", &self.len)
+            .field("layout", &self.layout)
+            .field("free", &self.free.len())
+            .finish()
+    }
+}
+
+impl<T> AsRef<Page<T>> for Page<T> {
+    #[inline]
+    fn as_ref(&self) -> &Page<T> {
+        self
+    }
+}
+
+impl<T> list::Linked for Page<T> {
+    #[inline]
+    fn links(&self) -> &Links<Self> {
+        &self.links
+    }
+
+    #[inline]
+    fn links_mut(&mut self) -> &mut Links<Self> {
+        &mut self.links
+    }
+}
+
+// ===== impl Allocated =====
+
+impl<'a, T> Iterator for Allocated<'a, T> {
+    type Item = NonNull<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.allocated == 0 {
+            self.window += WINDOW_SLOTS;
+            if self.window >= self.page.len {
+                return None;
+            }
+            self.allocated = self.page.allocated_in(self.window);
+        }
+        let bit = self.allocated.trailing_zeros() as usize;
+        self.allocated &= self.allocated - 1;
+        Some(unsafe { self.page.slot(self.window + bit) })
+    }
+}
+
+impl<'a, T> fmt::Debug for Allocated<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Allocated")
+            .field("page", &self.page)
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+// ===== impl Slab =====
+
+impl<T> Slab<T> {
+    /// Construct a new `Slab` in the `size` bytes of memory starting at
+    /// `start`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// region of memory starting at `start` is valid for `size` bytes, and
+    /// that nothing else will access that memory for as long as the slab
+    /// exists.
+    pub unsafe fn from_raw_parts(start: NonNull<u8>, size: usize) -> Self {
+        Slab {
+            page: Page::new(start, size, SlotLayout::of::<T>()),
+        }
+    }
+
+    /// Returns the number of objects this slab can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.page.capacity()
+    }
+
+    /// Returns the number of objects currently allocated in this slab.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.page.used()
+    }
+
+    /// Returns `true` if no objects are currently allocated in this slab.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.page.is_empty()
+    }
+
+    /// Returns `true` if this slab cannot allocate any more objects.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.page.is_full()
+    }
+
+    /// Allocate a slot in this slab and move `value` into it.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object, if the slab has
+    ///   free slots.
+    /// - `Err(AllocErr)` if the slab is full.
+    pub fn alloc(&mut self, value: T) -> Result<SlabRef<T>, AllocErr> {
+        let ptr = self.page.alloc().ok_or(AllocErr)?;
+        unsafe {
+            ptr::write(ptr.as_ptr(), value);
+        }
+        Ok(SlabRef { ptr })
+    }
+
+    /// Drop the object referenced by `slab_ref` and return its slot to this
+    /// slab.
+    ///
+    /// # Panics
+    /// If `slab_ref` was not allocated from this slab.
+    pub fn free(&mut self, slab_ref: SlabRef<T>) {
+        assert!(
+            self.page.contains(slab_ref.ptr.as_ptr()),
+            "object at {:p} was not allocated from this slab",
+            slab_ref.ptr
+        );
+        unsafe {
+            ptr::drop_in_place(slab_ref.ptr.as_ptr());
+            self.page.dealloc(slab_ref.ptr);
+        }
+    }
+}
+
+impl<T> fmt::Debug for Slab<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Slab").field("page", &self.page).finish()
+    }
+}
+
+// ===== impl SlabRef =====
+
+impl<T> SlabRef<T> {
+    /// Returns a `SlabRef` referencing the object at `ptr`.
+    ///
+    /// This is used to take ownership of slots allocated in bulk by
+    /// [`Cache::alloc_bulk`].
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// was allocated by a slab cache, that it holds a valid `T`, and that no
+    /// other `SlabRef` references it.
+    ///
+    /// [`Cache::alloc_bulk`]: cache/struct.Cache.html#method.alloc_bulk
+    #[inline]
+    pub unsafe fn from_raw(ptr: NonNull<T>) -> Self {
+        SlabRef { ptr }
+    }
+
+    /// Returns a raw pointer to the referenced object.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+}
+
+impl<T> Deref for SlabRef<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabRef<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+#[inline]
+fn round_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+// ===== impl FreeSlot =====
+
+impl stack::Linked for FreeSlot {
+    #[inline]
+    fn next(&self) -> &Link<Self> {
+        &self.next
+    }
+
+    #[inline]
+    fn next_mut(&mut self) -> &mut Link<Self> {
+        &mut self.next
+    }
+}
+
+impl AsRef<FreeSlot> for FreeSlot {
+    #[inline]
+    fn as_ref(&self) -> &FreeSlot {
+        self
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::{cell::Cell, vec::Vec};
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn with_slab<T, F>(f: F)
+    where
+        F: FnOnce(&mut Slab<T>),
+    {
+        // Back the page with `u64`s so that it is aligned for every object
+        // type the tests use, and no bytes are skipped at its start.
+        let mut memory = vec![0u64; PAGE_SIZE / 8];
+        let start = NonNull::new(memory.as_mut_ptr() as *mut u8).unwrap();
+        let mut slab = unsafe { Slab::from_raw_parts(start, PAGE_SIZE) };
+        f(&mut slab);
+    }
+
+    #[test]
+    fn capacity_fills_page() {
+        with_slab::<u64, _>(|slab| {
+            assert_eq!(slab.capacity(), PAGE_SIZE / 8);
+            assert!(slab.is_empty());
+            assert!(!slab.is_full());
+        });
+        with_slab::<[u8; 100], _>(|slab| {
+            assert_eq!(slab.capacity(), PAGE_SIZE / 104);
+        });
+    }
+
+    #[test]
+    fn small_types_get_pointer_sized_slots() {
+        assert_eq!(SlotLayout::of::<u8>().size(), mem::size_of::<usize>());
+        assert_eq!(SlotLayout::of::<()>().size(), mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn preserving_layout_stores_free_list_after_object() {
+        let word = mem::size_of::<usize>();
+        let layout = SlotLayout::preserving::<u64>();
+        assert!(layout.preserves_objects());
+        assert_eq!(layout.size(), 8 + word);
+
+        let layout = SlotLayout::preserving::<[u8; 3]>();
+        assert_eq!(layout.size(), 2 * word);
+        assert!(!SlotLayout::of::<[u8; 3]>().preserves_objects());
+
+        // Zero-sized objects are preserved, even though the free-list node
+        // is at the start of the slot.
+        assert!(SlotLayout::preserving::<()>().preserves_objects());
+        assert!(!SlotLayout::of::<()>().preserves_objects());
+    }
+
+    #[test]
+    fn preserving_layout_keeps_free_objects() {
+        let mut memory = vec![0u8; PAGE_SIZE];
+        let start = NonNull::new(memory.as_mut_ptr()).unwrap();
+        let mut page = unsafe {
+            Page::<u64>::new(start, PAGE_SIZE, SlotLayout::preserving::<u64>())
+        };
+        let slot = page.alloc().unwrap();
+        unsafe {
+            ptr::write(slot.as_ptr(), 0xfeed_face);
+            page.dealloc(slot);
+            assert_eq!(*slot.as_ptr(), 0xfeed_face);
+        }
+        assert_eq!(page.alloc(), Some(slot));
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        with_slab(|slab| {
+            let mut a = slab.alloc(1usize).unwrap();
+            let b = slab.alloc(2usize).unwrap();
+            assert_eq!(*a, 1);
+            assert_eq!(*b, 2);
+            assert_eq!(slab.len(), 2);
+
+            *a += 10;
+            assert_eq!(*a, 11);
+
+            slab.free(a);
+            assert_eq!(slab.len(), 1);
+            slab.free(b);
+            assert!(slab.is_empty());
+        });
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        with_slab(|slab| {
+            let a = slab.alloc(1usize).unwrap();
+            let addr = a.as_ptr();
+            slab.free(a);
+            let b = slab.alloc(2usize).unwrap();
+            assert_eq!(b.as_ptr(), addr);
+            slab.free(b);
+        });
+    }
+
+    #[test]
+    fn alloc_until_full() {
+        with_slab(|slab| {
+            let refs: Vec<_> = (0..slab.capacity())
+                .map(|i| slab.alloc(i as u64).expect("slab should not be full"))
+                .collect();
+            assert!(slab.is_full());
+            assert!(slab.alloc(0xdead).is_err());
+
+            for (i, r) in refs.iter().enumerate() {
+                assert_eq!(**r, i as u64);
+            }
+
+            for r in refs {
+                slab.free(r);
+            }
+            assert!(slab.is_empty());
+        });
+    }
+
+    #[test]
+    fn free_drops_object() {
+        struct Dropper<'a>(&'a Cell<usize>);
+        impl<'a> Drop for Dropper<'a> {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let drops = Cell::new(0);
+        with_slab(|slab| {
+            let a = slab.alloc(Dropper(&drops)).unwrap();
+            assert_eq!(drops.get(), 0);
+            slab.free(a);
+            assert_eq!(drops.get(), 1);
+        });
+    }
+
+    #[test]
+    fn page_allocated_skips_free_slots() {
+        with_slab(|slab| {
+            assert_eq!(slab.page.allocated().count(), 0);
+            let refs: Vec<_> = (0..slab.capacity())
+                .map(|i| slab.alloc(i).unwrap())
+                .collect();
+            assert!(slab.capacity() > WINDOW_SLOTS * 2);
+
+            let mut kept = Vec::new();
+            for (i, r) in refs.into_iter().enumerate() {
+                if i % 3 == 0 {
+                    slab.free(r);
+                } else {
+                    kept.push(i);
+                }
+            }
+            let live: Vec<usize> = slab
+                .page
+                .allocated()
+                .map(|ptr| unsafe { *ptr.as_ptr() })
+                .collect();
+            assert_eq!(live, kept);
+        });
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    #[should_panic]
+    fn free_from_wrong_slab() {
+        with_slab(|slab1| {
+            with_slab(|slab2| {
+                let a = slab1.alloc(1usize).unwrap();
+                slab2.free(a);
+            })
+        });
     }
 }
@@ -0,0 +1,428 @@
+//! A slab which may be shared between threads without locking.
+//!
+//! The free list of a lock-free [`Slab`] is a _Treiber stack_: allocating
+//! pops a slot from the top of the stack, and freeing pushes a slot back on,
+//! each with a single compare-and-swap on the stack's head. This allows
+//! `alloc` and `free` to take `&self`, so a slab may be shared between CPUs
+//! without wrapping it in a `LockedAlloc`.
+//!
+//! A naive Treiber stack suffers from the _ABA problem_: if, between a thread
+//! reading the head of the stack and swapping it for the head's successor,
+//! other threads pop the head, pop its successor, and push the head back,
+//! the swap succeeds and installs a successor which is no longer free. To
+//! prevent this, the head of the stack is a _tagged_ slot index. The upper
+//! half of the head word holds a tag which is incremented by every push and
+//! pop, so a stale head never compares equal to the current one.
+//!
+//! Each free slot stores the index of the next free slot _after_ the space
+//! for its object, as in [`SlotLayout::preserving`], so that a thread reading
+//! a slot's link never races with another thread writing an object into the
+//! same slot.
+//!
+//! [`Slab`]: struct.Slab.html
+//! [`SlotLayout::preserving`]: ../struct.SlotLayout.html#method.preserving
+use super::{round_up, SlabRef, SlotLayout};
+use core::{
+    alloc::AllocErr,
+    cmp,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull, Unique},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use hal9000::mem::Page as MemPage;
+
+/// The number of low bits of a tagged head which hold a slot index.
+const INDEX_BITS: usize = mem::size_of::<usize>() * 4;
+
+/// Mask selecting the slot index from a tagged head.
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+/// The slot index representing the end of the free list.
+const NIL: usize = INDEX_MASK;
+
+/// The maximum number of slots in a lock-free `Slab`.
+///
+/// Half of the bits of the free stack's head hold a slot index, so on 32-bit
+/// targets, a slab may hold at most 65535 objects.
+pub const MAX_SLOTS: usize = NIL;
+
+/// A slab allocator for objects of type `T` whose `alloc` and `free`
+/// operations are lock-free.
+///
+/// Like the crate root's [`Slab`], a lock-free `Slab` manages a single
+/// region of memory provided by the caller.
+///
+/// [`Slab`]: ../struct.Slab.html
+pub struct Slab<T> {
+    /// Pointer to the first slot in the slab.
+    head: Unique<u8>,
+
+    /// The number of slots in the slab.
+    len: usize,
+
+    /// The layout of each slot in the slab.
+    layout: SlotLayout,
+
+    /// The tagged index of the top of the free stack.
+    free: AtomicUsize,
+
+    /// The number of objects currently allocated.
+    used: AtomicUsize,
+
+    _ty: PhantomData<T>,
+}
+
+// ===== impl Slab =====
+
+impl<T> Slab<T> {
+    /// Construct a new `Slab` in the `size` bytes of memory starting at
+    /// `start`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that the
+    /// region of memory starting at `start` is valid for `size` bytes, and
+    /// that nothing else will access that memory for as long as the slab
+    /// exists.
+    pub unsafe fn from_raw_parts(start: NonNull<u8>, size: usize) -> Self {
+        let layout = Self::layout();
+        let offset = start.as_ptr().align_offset(layout.align);
+        let len =
+            cmp::min(size.saturating_sub(offset) / layout.size, MAX_SLOTS);
+        let slab = Slab {
+            head: Unique::new_unchecked(start.as_ptr().add(offset)),
+            len,
+            layout,
+            free: AtomicUsize::new(if len == 0 { NIL } else { 0 }),
+            used: AtomicUsize::new(0),
+            _ty: PhantomData,
+        };
+        // Link every slot to its successor, so that the first slot in the
+        // slab will be the first one allocated.
+        for i in 0..len {
+            let next = if i + 1 < len { i + 1 } else { NIL };
+            ptr::write(slab.next_ptr(i), AtomicUsize::new(next));
+        }
+        slab
+    }
+
+    /// Construct a new `Slab` spanning the frame `frame`.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that
+    /// `frame` is mapped, and that nothing else will access the frame for
+    /// as long as the slab exists.
+    pub unsafe fn from_frame<F: MemPage>(frame: &F) -> Self {
+        let base: usize = frame.base().into();
+        Self::from_raw_parts(NonNull::new_unchecked(base as *mut u8), F::SIZE)
+    }
+
+    /// Returns the number of objects this slab can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the number of objects currently allocated in this slab.
+    ///
+    /// If other threads are concurrently allocating from this slab, the
+    /// returned value may already be out of date.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` if no objects are currently allocated in this slab.
+    ///
+    /// If other threads are concurrently allocating from this slab, the
+    /// returned value may already be out of date.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Allocate a slot in this slab and move `value` into it.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object, if the slab has
+    ///   free slots.
+    /// - `Err(AllocErr)` if the slab is full.
+    pub fn alloc(&self, value: T) -> Result<SlabRef<T>, AllocErr> {
+        let index = self.pop().ok_or(AllocErr)?;
+        self.used.fetch_add(1, Ordering::Relaxed);
+        let ptr = self.slot(index);
+        unsafe {
+            ptr::write(ptr.as_ptr(), value);
+        }
+        Ok(SlabRef { ptr })
+    }
+
+    /// Drop the object referenced by `slab_ref` and return its slot to this
+    /// slab.
+    ///
+    /// # Panics
+    /// If `slab_ref` was not allocated from this slab.
+    pub fn free(&self, slab_ref: SlabRef<T>) {
+        let index = self.index_of(slab_ref.ptr).unwrap_or_else(|| {
+            panic!(
+                "object at {:p} was not allocated from this slab",
+                slab_ref.ptr
+            )
+        });
+        unsafe {
+            ptr::drop_in_place(slab_ref.ptr.as_ptr());
+        }
+        self.used.fetch_sub(1, Ordering::Relaxed);
+        self.push(index);
+    }
+
+    /// Pop the index of a free slot from the free stack.
+    fn pop(&self) -> Option<usize> {
+        let mut head = self.free.load(Ordering::Acquire);
+        loop {
+            let index = head & INDEX_MASK;
+            if index == NIL {
+                return None;
+            }
+            // If another thread pops `index` before we do, `next` may be
+            // garbage, but the tag ensures the swap below will then fail.
+            let next = self.next(index).load(Ordering::Relaxed);
+            let new = tagged(next, head);
+            match self.free.compare_exchange_weak(
+                head,
+                new,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(index),
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Push the index of a newly-freed slot onto the free stack.
+    fn push(&self, index: usize) {
+        let mut head = self.free.load(Ordering::Relaxed);
+        loop {
+            self.next(index).store(head & INDEX_MASK, Ordering::Relaxed);
+            let new = tagged(index, head);
+            match self.free.compare_exchange_weak(
+                head,
+                new,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Returns the index of the slot containing the object at `ptr`, if it is
+    /// in this slab.
+    fn index_of(&self, ptr: NonNull<T>) -> Option<usize> {
+        let start = self.head.as_ptr() as usize;
+        let addr = ptr.as_ptr() as usize;
+        let offset = addr.checked_sub(start)?;
+        let index = offset / self.layout.size;
+        if offset % self.layout.size == 0 && index < self.len {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a pointer to the object in the slot at `index`.
+    #[inline]
+    fn slot(&self, index: usize) -> NonNull<T> {
+        debug_assert!(index < self.len);
+        unsafe {
+            let slot = self.head.as_ptr().add(index * self.layout.size);
+            NonNull::new_unchecked(slot as *mut T)
+        }
+    }
+
+    /// Returns the free-list link in the slot at `index`.
+    #[inline]
+    fn next(&self, index: usize) -> &AtomicUsize {
+        unsafe { &*self.next_ptr(index) }
+    }
+
+    #[inline]
+    fn next_ptr(&self, index: usize) -> *mut AtomicUsize {
+        let slot = self.slot(index).as_ptr() as *mut u8;
+        unsafe { slot.add(self.layout.free_offset) as *mut AtomicUsize }
+    }
+
+    /// Returns the slot layout for objects of type `T`, with an atomic
+    /// free-list link stored after the object.
+    fn layout() -> SlotLayout {
+        let align =
+            cmp::max(mem::align_of::<T>(), mem::align_of::<AtomicUsize>());
+        let free_offset =
+            round_up(mem::size_of::<T>(), mem::align_of::<AtomicUsize>());
+        SlotLayout {
+            size: round_up(free_offset + mem::size_of::<AtomicUsize>(), align),
+            align,
+            free_offset,
+            object_offset: 0,
+            preserves_objects: false,
+        }
+    }
+}
+
+// A `Slab` hands out its objects to whichever thread allocates them, so it
+// is only `Send` and `Sync` if the objects are `Send`.
+unsafe impl<T: Send> Send for Slab<T> {}
+unsafe impl<T: Send> Sync for Slab<T> {}
+
+impl<T> fmt::Debug for Slab<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Slab")
+            .field("head", &self.head.as_ptr())
+            .field("len", &self.len)
+            .field("layout", &self.layout)
+            .field("used", &self.len())
+            .finish()
+    }
+}
+
+/// Returns a head for the free stack pointing to `index`, tagged with the
+/// successor of the tag in `old`.
+#[inline]
+fn tagged(index: usize, old: usize) -> usize {
+    let tag = (old >> INDEX_BITS).wrapping_add(1);
+    (tag << INDEX_BITS) | index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread, vec::Vec};
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn with_slab<T, F>(f: F)
+    where
+        F: FnOnce(&Slab<T>),
+    {
+        let mut memory = vec![0u8; PAGE_SIZE];
+        let start = NonNull::new(memory.as_mut_ptr()).unwrap();
+        let slab = unsafe { Slab::from_raw_parts(start, PAGE_SIZE) };
+        f(&slab);
+    }
+
+    #[test]
+    fn link_stored_after_object() {
+        let layout = Slab::<[u8; 12]>::layout();
+        assert!(layout.free_offset >= 12);
+        assert_eq!(layout.size() % mem::align_of::<AtomicUsize>(), 0);
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        with_slab(|slab| {
+            let a = slab.alloc(1usize).unwrap();
+            let b = slab.alloc(2usize).unwrap();
+            assert_eq!(*a, 1);
+            assert_eq!(*b, 2);
+            assert_eq!(slab.len(), 2);
+
+            let addr = a.as_ptr();
+            slab.free(a);
+            assert_eq!(slab.len(), 1);
+            // Freed slots are reused in LIFO order.
+            assert_eq!(slab.alloc(3usize).unwrap().as_ptr(), addr);
+            slab.free(b);
+        });
+    }
+
+    #[test]
+    fn alloc_until_full() {
+        with_slab(|slab| {
+            let refs: Vec<_> = (0..slab.capacity())
+                .map(|i| slab.alloc(i).expect("slab should not be full"))
+                .collect();
+            assert!(slab.alloc(0).is_err());
+            for (i, r) in refs.iter().enumerate() {
+                assert_eq!(**r, i);
+            }
+            for r in refs {
+                slab.free(r);
+            }
+            assert!(slab.is_empty());
+        });
+    }
+
+    #[test]
+    fn tags_change_on_every_operation() {
+        with_slab(|slab| {
+            let head = slab.free.load(Ordering::Relaxed);
+            let a = slab.alloc(1usize).unwrap();
+            slab.free(a);
+            let after = slab.free.load(Ordering::Relaxed);
+            // The same slot is on top of the stack, but the head differs.
+            assert_eq!(head & INDEX_MASK, after & INDEX_MASK);
+            assert_ne!(head, after);
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn free_from_wrong_slab() {
+        with_slab(|slab1| {
+            with_slab(|slab2| {
+                let a = slab1.alloc(1usize).unwrap();
+                slab2.free(a);
+            })
+        });
+    }
+
+    #[test]
+    fn concurrent_alloc_and_free() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 2_000;
+        const BATCH: usize = 8;
+
+        let mut memory = vec![0u8; PAGE_SIZE];
+        let start = NonNull::new(memory.as_mut_ptr()).unwrap();
+        let slab: Arc<Slab<(usize, usize)>> =
+            Arc::new(unsafe { Slab::from_raw_parts(start, PAGE_SIZE) });
+        assert!(slab.capacity() >= THREADS * BATCH);
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let slab = slab.clone();
+                thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        let refs: Vec<_> = (0..BATCH)
+                            .map(|i| slab.alloc((thread, round + i)).unwrap())
+                            .collect();
+                        // If two threads were ever handed the same slot,
+                        // one would see the other's values.
+                        for (i, r) in refs.iter().enumerate() {
+                            assert_eq!(**r, (thread, round + i));
+                        }
+                        for r in refs {
+                            slab.free(r);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(slab.is_empty());
+        // Every slot made it back onto the free list.
+        let refs: Vec<_> = (0..slab.capacity())
+            .map(|i| slab.alloc((0, i)).unwrap())
+            .collect();
+        assert!(slab.alloc((0, 0)).is_err());
+        for r in refs {
+            slab.free(r);
+        }
+    }
+}
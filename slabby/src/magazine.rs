@@ -0,0 +1,761 @@
+//! A per-CPU magazine layer in front of a slab cache.
+//!
+//! This is the magazine and depot design described by Bonwick and Adams in
+//! "Magazines and Vmem" (USENIX 2001). A _magazine_ is a small stack of free
+//! objects. Each CPU keeps a [`CpuCache`] holding up to two magazines, which
+//! satisfy allocations and absorb frees _without_ taking any locks.
+//!
+//! Only when both of a CPU's magazines are empty (when allocating) or full
+//! (when freeing) does the CPU need to lock the shared [`Depot`], where it
+//! exchanges a magazine for a full or empty one. If the depot has no suitable
+//! magazine, the CPU falls back to the depot's slab [`Cache`]. Since each
+//! trip to the depot moves a whole magazine's worth of objects, most
+//! allocations and frees never touch the lock at all.
+//!
+//! Like the objects in a cache's free slots, the objects in a magazine are
+//! linked together by free-list nodes stored in the objects' own memory, so
+//! magazines may hold objects in their constructed state. The magazine
+//! headers themselves are allocated from a slab cache owned by the depot.
+//!
+//! With the `debug` feature enabled, objects are checked for red-zone and
+//! poison damage as they enter and leave a CPU's magazines, just as they are
+//! when they are allocated from or freed to a cache.
+//!
+//! [`CpuCache`]: struct.CpuCache.html
+//! [`Depot`]: struct.Depot.html
+//! [`Cache`]: ../cache/struct.Cache.html
+#[cfg(feature = "debug")]
+use super::debug;
+use super::{cache::Cache, FreeList, FreeSlot, SlabRef, SlotLayout};
+use alarm_base::{FrameAllocator, LockedAlloc};
+use core::{
+    alloc::AllocErr,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ptr::{self, NonNull},
+};
+use intruder_alarm::{
+    stack::{self, Stack},
+    Link,
+    OwningRef,
+    UnsafeRef,
+};
+
+/// The default number of objects held by a magazine.
+pub const DEFAULT_MAGAZINE_SIZE: usize = 16;
+
+/// The shared depot of magazines behind a slab cache.
+///
+/// A `Depot` is intended to be shared between CPUs behind a
+/// [`LockedAlloc`], with each CPU allocating and freeing through its own
+/// [`CpuCache`].
+///
+/// # Type Parameters
+/// - `T`: the type of object allocated by the depot's cache.
+/// - `A`: the type of the frame allocator that provides pages to the depot. The
+///   depot's object cache and magazine cache each hold a clone of the frame
+///   allocator, so `A` should be a handle to a shared allocator.
+///
+/// [`LockedAlloc`]: ../../alarm_base/struct.LockedAlloc.html
+/// [`CpuCache`]: struct.CpuCache.html
+pub struct Depot<T, A>
+where
+    A: FrameAllocator,
+{
+    /// The cache from which objects are allocated.
+    cache: Cache<T, A>,
+
+    /// The cache from which magazine headers are allocated.
+    magazines: Cache<Magazine, A>,
+
+    /// Stack of full magazines.
+    full: MagazineStack,
+
+    /// Stack of empty magazines.
+    empty: MagazineStack,
+
+    /// The number of objects held by each magazine.
+    magazine_size: usize,
+}
+
+/// A CPU's private view of a [`Depot`].
+///
+/// A `CpuCache` holds up to two magazines: the _loaded_ magazine, which
+/// allocations and frees are served from, and the _previous_ magazine, which
+/// is swapped with the loaded one when the loaded magazine runs out of
+/// objects or space.
+///
+/// A `CpuCache` must only be used by a single CPU, and must only be used with
+/// the depot which created it. Before it is dropped, its magazines should be
+/// returned to the depot with [`flush`]; otherwise, the objects they hold are
+/// leaked.
+///
+/// [`Depot`]: struct.Depot.html
+/// [`flush`]: #method.flush
+pub struct CpuCache<T> {
+    /// The name of the depot's cache, used when reporting errors.
+    #[cfg(feature = "debug")]
+    name: &'static str,
+
+    /// The magazine allocations and frees are served from.
+    loaded: Option<UnsafeRef<Magazine>>,
+
+    /// The previously loaded magazine, which is always either full or empty.
+    previous: Option<UnsafeRef<Magazine>>,
+
+    /// The layout of the depot's slots.
+    layout: SlotLayout,
+
+    /// Whether the depot's cache keeps objects in their constructed state.
+    caches_objects: bool,
+
+    /// Destructor run on the depot's objects before they are dropped.
+    dtor: Option<fn(&mut T)>,
+
+    /// Type marker for the objects held in this CPU's magazines.
+    _ty: PhantomData<*mut T>,
+}
+
+/// A stack of free objects.
+struct Magazine {
+    /// The free objects in this magazine.
+    rounds: FreeList,
+
+    /// The number of objects this magazine can hold.
+    capacity: usize,
+
+    /// Link to the next magazine in a depot's magazine stack.
+    next: Link<Magazine>,
+}
+
+type MagazineStack = Stack<Magazine, Magazine, UnsafeRef<Magazine>>;
+
+// ===== impl Depot =====
+
+impl<T, A> Depot<T, A>
+where
+    A: FrameAllocator + Clone,
+{
+    /// Returns a new `Depot` in front of `cache`, with magazines of the
+    /// default size.
+    pub fn new(cache: Cache<T, A>) -> Self {
+        Self::with_magazine_size(cache, DEFAULT_MAGAZINE_SIZE)
+    }
+
+    /// Returns a new `Depot` in front of `cache`, with magazines holding
+    /// `magazine_size` objects.
+    ///
+    /// # Panics
+    /// If `magazine_size` is zero.
+    pub fn with_magazine_size(
+        cache: Cache<T, A>,
+        magazine_size: usize,
+    ) -> Self {
+        assert!(magazine_size > 0, "magazines must hold at least one object");
+        let frames = cache.frame_allocator().clone();
+        Depot {
+            cache,
+            // Safe, since `cache` was created with the same frame allocator.
+            magazines: unsafe { Cache::new(frames) },
+            full: Stack::new(),
+            empty: Stack::new(),
+            magazine_size,
+        }
+    }
+
+    /// Returns a new `CpuCache` for allocating from this depot.
+    pub fn cpu_cache(&self) -> CpuCache<T> {
+        CpuCache {
+            #[cfg(feature = "debug")]
+            name: self.cache.name(),
+            loaded: None,
+            previous: None,
+            layout: self.cache.layout(),
+            caches_objects: self.cache.caches_objects(),
+            dtor: self.cache.dtor(),
+            _ty: PhantomData,
+        }
+    }
+
+    /// Borrow the cache from which this depot's objects are allocated.
+    #[inline]
+    pub fn cache(&self) -> &Cache<T, A> {
+        &self.cache
+    }
+
+    /// Mutably borrow the cache from which this depot's objects are
+    /// allocated.
+    #[inline]
+    pub fn cache_mut(&mut self) -> &mut Cache<T, A> {
+        &mut self.cache
+    }
+
+    /// Returns the number of objects held by each magazine.
+    #[inline]
+    pub fn magazine_size(&self) -> usize {
+        self.magazine_size
+    }
+
+    /// Returns the number of full magazines in this depot.
+    #[inline]
+    pub fn full_magazines(&self) -> usize {
+        self.full.len()
+    }
+
+    /// Returns the number of empty magazines in this depot.
+    #[inline]
+    pub fn empty_magazines(&self) -> usize {
+        self.empty.len()
+    }
+
+    /// Return every object in this depot's magazines to the cache, free the
+    /// magazines, and return every empty page to the frame allocator.
+    ///
+    /// Magazines held by `CpuCache`s are not affected; they must first be
+    /// returned to the depot with [`CpuCache::flush`].
+    ///
+    /// # Returns
+    /// The number of frames that were returned to the frame allocator.
+    ///
+    /// [`CpuCache::flush`]: struct.CpuCache.html#method.flush
+    pub fn reclaim(&mut self) -> usize {
+        while let Some(magazine) = self.full.pop_node() {
+            unsafe {
+                self.drain(&magazine);
+                self.free_magazine(magazine);
+            }
+        }
+        while let Some(magazine) = self.empty.pop_node() {
+            unsafe { self.free_magazine(magazine) };
+        }
+        self.cache.reclaim() + self.magazines.reclaim()
+    }
+
+    /// Take a full magazine from the depot, in exchange for an empty one.
+    ///
+    /// If the depot has no full magazines, `empty` is left untouched.
+    fn exchange_full(
+        &mut self,
+        empty: &mut Option<UnsafeRef<Magazine>>,
+    ) -> Option<UnsafeRef<Magazine>> {
+        let full = self.full.pop_node()?;
+        if let Some(empty) = empty.take() {
+            debug_assert!(empty.is_empty());
+            self.empty.push_node(empty);
+        }
+        Some(full)
+    }
+
+    /// Take an empty magazine from the depot (or allocate a new one), in
+    /// exchange for a full one.
+    ///
+    /// If no empty magazine is available, `full` is left untouched.
+    fn exchange_empty(
+        &mut self,
+        full: &mut Option<UnsafeRef<Magazine>>,
+    ) -> Option<UnsafeRef<Magazine>> {
+        let empty = match self.empty.pop_node() {
+            Some(empty) => empty,
+            None => {
+                let magazine = Magazine::new(self.magazine_size);
+                let slab_ref = self.magazines.alloc(magazine).ok()?;
+                UnsafeRef::from(slab_ref.ptr)
+            },
+        };
+        if let Some(full) = full.take() {
+            debug_assert!(full.is_full());
+            self.full.push_node(full);
+        }
+        Some(empty)
+    }
+
+    /// Return every object in `magazine` to the cache.
+    unsafe fn drain(&mut self, magazine: &UnsafeRef<Magazine>) {
+        let mut magazine = magazine.clone();
+        let free_offset = self.cache.layout().free_offset;
+        while let Some(ptr) = magazine.pop(free_offset) {
+            self.cache.free_slot(ptr.cast());
+        }
+    }
+
+    /// Return an empty magazine's memory to the magazine cache.
+    unsafe fn free_magazine(&mut self, magazine: UnsafeRef<Magazine>) {
+        let ptr = NonNull::new_unchecked(magazine.into_ptr() as *mut Magazine);
+        self.magazines.free(SlabRef { ptr });
+    }
+}
+
+impl<T, A> fmt::Debug for Depot<T, A>
+where
+    A: FrameAllocator + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Depot")
+            .field("cache", &self.cache)
+            .field("full", &self.full.len())
+            .field("empty", &self.empty.len())
+            .field("magazine_size", &self.magazine_size)
+            .finish()
+    }
+}
+
+// ===== impl CpuCache =====
+
+impl<T> CpuCache<T> {
+    /// Allocate an object through this CPU's magazines and move `value`
+    /// into it.
+    ///
+    /// If the depot's cache keeps objects in their constructed state, the
+    /// cached object is destroyed and replaced with `value`.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object.
+    /// - `Err(AllocErr)` if no objects are available and the depot's cache
+    ///   could not allocate a new page.
+    pub fn alloc<A>(
+        &mut self,
+        depot: &LockedAlloc<Depot<T, A>>,
+        value: T,
+    ) -> Result<SlabRef<T>, AllocErr>
+    where
+        A: FrameAllocator + Clone,
+    {
+        let ptr = self.alloc_slot(depot)?;
+        unsafe {
+            if self.caches_objects {
+                self.destroy(ptr);
+            }
+            ptr::write(ptr.as_ptr(), value);
+        }
+        Ok(SlabRef { ptr })
+    }
+
+    /// Allocate a constructed object through this CPU's magazines.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object.
+    /// - `Err(AllocErr)` if no objects are available and the depot's cache
+    ///   could not allocate a new page.
+    ///
+    /// # Panics
+    /// If the depot's cache was not created with a constructor.
+    pub fn alloc_constructed<A>(
+        &mut self,
+        depot: &LockedAlloc<Depot<T, A>>,
+    ) -> Result<SlabRef<T>, AllocErr>
+    where
+        A: FrameAllocator + Clone,
+    {
+        assert!(
+            self.caches_objects,
+            "alloc_constructed requires a cache with a constructor"
+        );
+        self.alloc_slot(depot).map(|ptr| SlabRef { ptr })
+    }
+
+    /// Free an object into this CPU's magazines.
+    ///
+    /// If the depot's cache keeps objects in their constructed state, the
+    /// object is _not_ dropped.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that
+    /// `slab_ref` was allocated from `depot`.
+    pub unsafe fn free<A>(
+        &mut self,
+        depot: &LockedAlloc<Depot<T, A>>,
+        slab_ref: SlabRef<T>,
+    ) where
+        A: FrameAllocator + Clone,
+    {
+        let ptr = slab_ref.ptr;
+        if !self.caches_objects {
+            self.destroy(ptr);
+        }
+
+        #[cfg(feature = "debug")]
+        {
+            debug::check(self.name, ptr, self.layout, false);
+            if !self.caches_objects {
+                debug::poison(ptr);
+            }
+        }
+
+        let ptr = ptr.cast();
+        let free_offset = self.layout.free_offset;
+
+        // Fast path: there's room in the loaded magazine.
+        if let Some(ref mut loaded) = self.loaded {
+            if !loaded.is_full() {
+                return loaded.push(ptr, free_offset);
+            }
+        }
+
+        // If the previous magazine is empty, it becomes the loaded magazine.
+        if self
+            .previous
+            .as_ref()
+            .map(|m| m.is_empty())
+            .unwrap_or(false)
+        {
+            mem::swap(&mut self.loaded, &mut self.previous);
+            if let Some(ref mut loaded) = self.loaded {
+                return loaded.push(ptr, free_offset);
+            }
+        }
+
+        // Otherwise, exchange the (full) previous magazine for an empty one.
+        let mut depot = depot.lock();
+        match depot.exchange_empty(&mut self.previous) {
+            Some(mut empty) => {
+                empty.push(ptr, free_offset);
+                self.previous = self.loaded.take();
+                self.loaded = Some(empty);
+            },
+            None => depot.cache.free_slot(ptr.cast()),
+        }
+    }
+
+    /// Return this CPU's magazines to the depot.
+    ///
+    /// Full magazines are returned to the depot intact. The objects in
+    /// partially-full magazines are returned to the depot's cache.
+    pub fn flush<A>(&mut self, depot: &LockedAlloc<Depot<T, A>>)
+    where
+        A: FrameAllocator + Clone,
+    {
+        let mut depot = depot.lock();
+        let magazines =
+            self.loaded.take().into_iter().chain(self.previous.take());
+        for magazine in magazines {
+            if magazine.is_full() {
+                depot.full.push_node(magazine);
+            } else {
+                unsafe { depot.drain(&magazine) };
+                depot.empty.push_node(magazine);
+            }
+        }
+    }
+
+    /// Returns the number of objects held in this CPU's magazines.
+    pub fn len(&self) -> usize {
+        self.loaded
+            .iter()
+            .chain(self.previous.iter())
+            .map(|m| m.rounds.len())
+            .sum()
+    }
+
+    /// Returns `true` if this CPU's magazines hold no objects.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Allocate a slot from this CPU's magazines, refilling them from the
+    /// depot if necessary.
+    fn alloc_slot<A>(
+        &mut self,
+        depot: &LockedAlloc<Depot<T, A>>,
+    ) -> Result<NonNull<T>, AllocErr>
+    where
+        A: FrameAllocator + Clone,
+    {
+        let ptr = self.take_slot(depot)?;
+        #[cfg(feature = "debug")]
+        unsafe {
+            debug::check(self.name, ptr, self.layout, !self.caches_objects);
+        }
+        Ok(ptr)
+    }
+
+    /// Take a slot from this CPU's magazines, or from the depot if they are
+    /// both empty.
+    fn take_slot<A>(
+        &mut self,
+        depot: &LockedAlloc<Depot<T, A>>,
+    ) -> Result<NonNull<T>, AllocErr>
+    where
+        A: FrameAllocator + Clone,
+    {
+        let free_offset = self.layout.free_offset;
+
+        // Fast path: the loaded magazine has objects.
+        if let Some(ptr) = self.loaded.as_mut().and_then(|m| m.pop(free_offset))
+        {
+            return Ok(ptr.cast());
+        }
+
+        // If the previous magazine is full, it becomes the loaded magazine.
+        if self.previous.as_ref().map(|m| m.is_full()).unwrap_or(false) {
+            mem::swap(&mut self.loaded, &mut self.previous);
+        } else {
+            // Otherwise, exchange the (empty) previous magazine for a full
+            // one.
+            let mut depot = depot.lock();
+            match depot.exchange_full(&mut self.previous) {
+                Some(full) => {
+                    self.previous = self.loaded.take();
+                    self.loaded = Some(full);
+                },
+                None => return depot.cache.alloc_slot(),
+            }
+        }
+
+        let ptr = self
+            .loaded
+            .as_mut()
+            .and_then(|m| m.pop(free_offset))
+            .expect("newly loaded magazine must be full");
+        Ok(ptr.cast())
+    }
+
+    /// Run the destructor (if any) on the object at `ptr`, and drop it.
+    #[inline]
+    unsafe fn destroy(&self, ptr: NonNull<T>) {
+        if let Some(dtor) = self.dtor {
+            dtor(&mut *ptr.as_ptr());
+        }
+        ptr::drop_in_place(ptr.as_ptr());
+    }
+}
+
+impl<T> fmt::Debug for CpuCache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rounds = |m: &Option<UnsafeRef<Magazine>>| {
+            m.as_ref().map(|m| m.rounds.len())
+        };
+        f.debug_struct("CpuCache")
+            .field("loaded", &rounds(&self.loaded))
+            .field("previous", &rounds(&self.previous))
+            .field("caches_objects", &self.caches_objects)
+            .finish()
+    }
+}
+
+// ===== impl Magazine =====
+
+impl Magazine {
+    fn new(capacity: usize) -> Self {
+        Magazine {
+            rounds: Stack::new(),
+            capacity,
+            next: Link::none(),
+        }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.rounds.is_empty()
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        self.rounds.len() >= self.capacity
+    }
+
+    /// Push the object at `ptr` onto this magazine, writing its free-list
+    /// node `free_offset` bytes into the object's slot.
+    unsafe fn push(&mut self, ptr: NonNull<u8>, free_offset: usize) {
+        debug_assert!(!self.is_full());
+        let node = ptr.as_ptr().add(free_offset) as *mut FreeSlot;
+        ptr::write(node, FreeSlot { next: Link::none() });
+        self.rounds
+            .push_node(UnsafeRef::from(NonNull::new_unchecked(node)));
+    }
+
+    /// Pop an object from this magazine.
+    fn pop(&mut self, free_offset: usize) -> Option<NonNull<u8>> {
+        self.rounds.pop_node().map(|node| unsafe {
+            let slot = (node.into_ptr() as *mut u8).sub(free_offset);
+            NonNull::new_unchecked(slot)
+        })
+    }
+}
+
+impl stack::Linked for Magazine {
+    #[inline]
+    fn next(&self) -> &Link<Self> {
+        &self.next
+    }
+
+    #[inline]
+    fn next_mut(&mut self) -> &mut Link<Self> {
+        &mut self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::SharedFrames;
+    use std::vec::Vec;
+
+    type TestDepot<T> = LockedAlloc<Depot<T, SharedFrames>>;
+
+    fn depot<T>(frames: &SharedFrames, magazine_size: usize) -> TestDepot<T> {
+        let cache = unsafe { Cache::new(frames.clone()) };
+        LockedAlloc::new(Depot::with_magazine_size(cache, magazine_size))
+    }
+
+    #[test]
+    fn first_allocs_come_from_cache() {
+        let frames = SharedFrames::new(8);
+        let depot = depot(&frames, 4);
+        let mut cpu = depot.lock().cpu_cache();
+
+        let a = cpu.alloc(&depot, 1u64).unwrap();
+        assert_eq!(*a, 1);
+        assert!(cpu.is_empty());
+        assert_eq!(depot.lock().cache().partial_pages(), 1);
+
+        unsafe { cpu.free(&depot, a) };
+        // The object is held in the CPU's magazine, not the cache.
+        assert_eq!(cpu.len(), 1);
+        assert_eq!(depot.lock().cache().partial_pages(), 1);
+
+        cpu.flush(&depot);
+        assert!(cpu.is_empty());
+        assert_eq!(depot.lock().cache().partial_pages(), 0);
+        assert_eq!(depot.lock().empty_magazines(), 1);
+    }
+
+    #[test]
+    fn freed_objects_reallocated_from_magazine() {
+        let frames = SharedFrames::new(8);
+        let depot = depot(&frames, 4);
+        let mut cpu = depot.lock().cpu_cache();
+
+        let a = cpu.alloc(&depot, 1u64).unwrap();
+        let addr = a.as_ptr();
+        unsafe { cpu.free(&depot, a) };
+        let b = cpu.alloc(&depot, 2u64).unwrap();
+        assert_eq!(b.as_ptr(), addr);
+        assert_eq!(*b, 2);
+        assert!(cpu.is_empty());
+        unsafe { cpu.free(&depot, b) };
+        cpu.flush(&depot);
+    }
+
+    #[test]
+    fn full_magazines_exchanged_with_depot() {
+        let frames = SharedFrames::new(8);
+        let depot = depot(&frames, 4);
+        let mut cpu = depot.lock().cpu_cache();
+
+        // Fill both of the CPU's magazines, and one more.
+        let refs: Vec<_> = (0..12)
+            .map(|i| cpu.alloc(&depot, i as u64).unwrap())
+            .collect();
+        for r in refs {
+            unsafe { cpu.free(&depot, r) };
+        }
+        assert_eq!(cpu.len(), 8);
+        assert_eq!(depot.lock().full_magazines(), 1);
+
+        // Another CPU can allocate from the full magazine in the depot.
+        let mut other = depot.lock().cpu_cache();
+        let refs: Vec<_> = (0..4)
+            .map(|i| other.alloc(&depot, i as u64).unwrap())
+            .collect();
+        assert_eq!(depot.lock().full_magazines(), 0);
+        assert_eq!(other.len(), 0);
+        for r in refs {
+            unsafe { other.free(&depot, r) };
+        }
+
+        cpu.flush(&depot);
+        other.flush(&depot);
+        assert_eq!(depot.lock().full_magazines(), 3);
+        assert!(depot.lock().reclaim() > 0);
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn previous_magazine_swapped_without_depot() {
+        let frames = SharedFrames::new(8);
+        let depot = depot(&frames, 2);
+        let mut cpu = depot.lock().cpu_cache();
+
+        let refs: Vec<_> = (0..4)
+            .map(|i| cpu.alloc(&depot, i as u64).unwrap())
+            .collect();
+        for r in refs {
+            unsafe { cpu.free(&depot, r) };
+        }
+        assert_eq!(cpu.len(), 4);
+        assert_eq!(depot.lock().full_magazines(), 0);
+
+        // Both magazines are full; allocating from them never needs a new
+        // page.
+        let refs: Vec<_> = (0..4)
+            .map(|i| cpu.alloc(&depot, i as u64).unwrap())
+            .collect();
+        assert!(cpu.is_empty());
+        assert_eq!(frames.allocated(), 2);
+        for r in refs {
+            unsafe { cpu.free(&depot, r) };
+        }
+        cpu.flush(&depot);
+    }
+
+    #[test]
+    fn frees_fall_back_to_cache() {
+        // With only one frame, there's no room to allocate magazines.
+        let frames = SharedFrames::new(1);
+        let depot = depot(&frames, 4);
+        let mut cpu = depot.lock().cpu_cache();
+
+        let a = cpu.alloc(&depot, 1u64).unwrap();
+        unsafe { cpu.free(&depot, a) };
+        assert!(cpu.is_empty());
+        assert_eq!(depot.lock().empty_magazines(), 0);
+        assert_eq!(depot.lock().cache().empty_pages(), 1);
+    }
+
+    #[test]
+    fn reclaim_returns_all_frames() {
+        let frames = SharedFrames::new(8);
+        let depot = depot(&frames, 4);
+        let mut cpu = depot.lock().cpu_cache();
+        let refs: Vec<_> = (0..32)
+            .map(|i| cpu.alloc(&depot, i as u64).unwrap())
+            .collect();
+        for r in refs {
+            unsafe { cpu.free(&depot, r) };
+        }
+        cpu.flush(&depot);
+        assert!(frames.allocated() > 0);
+        depot.lock().reclaim();
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn constructed_objects_keep_state() {
+        #[derive(Debug)]
+        struct Object {
+            uses: usize,
+        }
+
+        let frames = SharedFrames::new(8);
+        let cache = unsafe {
+            Cache::with_ctor(frames.clone(), || Object { uses: 0 }, None)
+        };
+        let depot = LockedAlloc::new(Depot::with_magazine_size(cache, 4));
+        let mut cpu = depot.lock().cpu_cache();
+
+        let mut a = cpu.alloc_constructed(&depot).unwrap();
+        a.uses += 1;
+        let addr = a.as_ptr();
+        unsafe { cpu.free(&depot, a) };
+
+        let b = cpu.alloc_constructed(&depot).unwrap();
+        assert_eq!(b.as_ptr(), addr);
+        assert_eq!(b.uses, 1);
+        unsafe { cpu.free(&depot, b) };
+        cpu.flush(&depot);
+        depot.lock().reclaim();
+        assert_eq!(frames.allocated(), 0);
+    }
+}
@@ -0,0 +1,969 @@
+//! Slab caches which keep their page metadata off-slab.
+//!
+//! A [`Cache`] stores each page's [`Page`] header at the start of the page's
+//! frame, and links free slots together with nodes stored inside the slots.
+//! For small objects, this costs very little. For large objects, though, the
+//! header alone can cost a whole object slot: a frame of 4 KiB holds only one
+//! 2 KiB object once a header is placed in front of it.
+//!
+//! An [`OffSlabCache`] instead keeps a small _descriptor_ for each page,
+//! holding the base address of the page's frame and a bitmap of its free
+//! slots. Descriptors are allocated from a separate, ordinary [`Cache`], so
+//! a page's frame holds nothing but objects, and large objects pack perfectly
+//! into frames. Since no free-list nodes are stored in free slots, freed
+//! objects are always left intact.
+//!
+//! Because the descriptor isn't stored in the frame, it can't be found by
+//! rounding an object's address down to a frame boundary. Instead, the cache
+//! keeps a small hash table of descriptors, keyed by frame number, which is
+//! consulted when objects are freed.
+//!
+//! Off-slab caches are intended for objects larger than roughly an eighth of
+//! a frame. Since each page's free slots are tracked by a single word-sized
+//! bitmap, a page holds at most [`MAX_OBJECTS_PER_PAGE`] objects.
+//!
+//! With the `debug` feature enabled, each slot is laid out like a red-zoned
+//! [`Cache`] slot, and objects are checked for red-zone and poison damage as
+//! they are allocated and freed. This costs some of the dense packing, but
+//! catches overruns of large buffers.
+//!
+//! [`Cache`]: ../cache/struct.Cache.html
+//! [`Page`]: ../struct.Page.html
+//! [`OffSlabCache`]: struct.OffSlabCache.html
+//! [`MAX_OBJECTS_PER_PAGE`]: constant.MAX_OBJECTS_PER_PAGE.html
+use super::{
+    cache::{self, Cache, Counters, Stats},
+    SlabRef,
+};
+#[cfg(feature = "debug")]
+use super::{debug, SlotLayout};
+use alarm_base::FrameAllocator;
+use core::{
+    alloc::AllocErr,
+    cmp,
+    fmt,
+    mem,
+    ptr::{self, NonNull},
+};
+use hal9000::mem::Page as MemPage;
+use intruder_alarm::{
+    list::{self, Links, List},
+    UnsafeRef,
+};
+
+/// The maximum number of objects in an `OffSlabCache` page.
+pub const MAX_OBJECTS_PER_PAGE: usize = mem::size_of::<usize>() * 8;
+
+/// The number of buckets in an `OffSlabCache`'s descriptor hash table.
+const BUCKETS: usize = 64;
+
+/// A slab cache for large objects of type `T`, with page metadata stored
+/// off-slab.
+///
+/// # Type Parameters
+/// - `T`: the type of object allocated by this cache.
+/// - `A`: the type of the frame allocator that provides pages to this cache.
+///   The cache's descriptor cache holds a clone of the frame allocator, so `A`
+///   should be a handle to a shared allocator.
+pub struct OffSlabCache<T, A>
+where
+    A: FrameAllocator,
+{
+    /// The name of this cache, used when reporting errors.
+    name: &'static str,
+
+    /// Pages with no allocated objects.
+    empty: DescriptorList,
+
+    /// Pages with both allocated objects and free slots.
+    partial: DescriptorList,
+
+    /// Pages with no free slots.
+    full: DescriptorList,
+
+    /// Hash table of every page's descriptor, keyed by frame number.
+    ///
+    /// Each bucket is the head of a chain of descriptors, linked through
+    /// their `next_in_bucket` pointers.
+    buckets: [Option<NonNull<Descriptor>>; BUCKETS],
+
+    /// The maximum number of empty pages this cache will hold before
+    /// returning them to the frame allocator.
+    max_empty: usize,
+
+    /// Constructor for this cache's objects, if objects are cached in their
+    /// constructed state.
+    ctor: Option<fn() -> T>,
+
+    /// Destructor run on this cache's objects before they are dropped.
+    dtor: Option<fn(&mut T)>,
+
+    /// The cache from which page descriptors are allocated.
+    descriptors: Cache<Descriptor, A>,
+
+    /// Counts of this cache's allocations and frees.
+    counters: Counters,
+
+    /// The frame allocator from which new pages are requested.
+    frames: A,
+}
+
+/// Describes a page in an `OffSlabCache`.
+struct Descriptor {
+    /// The base address of the page's frame.
+    base: NonNull<u8>,
+
+    /// The number of slots in the page.
+    capacity: usize,
+
+    /// Bitmap of the page's free slots. Bit `i` is set if slot `i` is free.
+    free: usize,
+
+    /// Links to the next and previous descriptors in a cache's page list.
+    links: Links<Descriptor>,
+
+    /// The next descriptor in this descriptor's hash bucket.
+    next_in_bucket: Option<NonNull<Descriptor>>,
+}
+
+type DescriptorList = List<Descriptor, Descriptor, UnsafeRef<Descriptor>>;
+
+// ===== impl OffSlabCache =====
+
+impl<T, A> OffSlabCache<T, A>
+where
+    A: FrameAllocator + Clone,
+{
+    /// Returns a new `OffSlabCache` which will request pages from `frames`.
+    ///
+    /// The cache will hold at most [`DEFAULT_MAX_EMPTY`] empty pages.
+    ///
+    /// # Unsafety
+    /// This function is unsafe because the caller must guarantee that every
+    /// frame allocated from `frames` is identity-mapped, as for
+    /// [`Cache::new`]. The cache's descriptors are allocated from the same
+    /// frames.
+    ///
+    /// [`DEFAULT_MAX_EMPTY`]: ../cache/constant.DEFAULT_MAX_EMPTY.html
+    /// [`Cache::new`]: ../cache/struct.Cache.html#method.new
+    pub unsafe fn new(frames: A) -> Self {
+        Self::with_max_empty(frames, cache::DEFAULT_MAX_EMPTY)
+    }
+
+    /// Returns a new `OffSlabCache` which will request pages from `frames`,
+    /// and hold at most `max_empty` empty pages.
+    ///
+    /// # Unsafety
+    /// As with [`new`], the caller must guarantee that frames allocated from
+    /// `frames` are identity-mapped.
+    ///
+    /// [`new`]: #method.new
+    pub unsafe fn with_max_empty(frames: A, max_empty: usize) -> Self {
+        OffSlabCache {
+            name: cache::UNNAMED,
+            empty: List::new(),
+            partial: List::new(),
+            full: List::new(),
+            buckets: [None; BUCKETS],
+            max_empty,
+            ctor: None,
+            dtor: None,
+            descriptors: Cache::new(frames.clone()),
+            counters: Counters::new(),
+            frames,
+        }
+    }
+
+    /// Returns a new `OffSlabCache` which will request pages from `frames`,
+    /// and cache its objects in their constructed state.
+    ///
+    /// See [`Cache::with_ctor`] for details.
+    ///
+    /// # Unsafety
+    /// As with [`new`], the caller must guarantee that frames allocated from
+    /// `frames` are identity-mapped.
+    ///
+    /// [`Cache::with_ctor`]: ../cache/struct.Cache.html#method.with_ctor
+    /// [`new`]: #method.new
+    pub unsafe fn with_ctor(
+        frames: A,
+        ctor: fn() -> T,
+        dtor: Option<fn(&mut T)>,
+    ) -> Self {
+        let mut cache = Self::new(frames);
+        cache.ctor = Some(ctor);
+        cache.dtor = dtor;
+        cache
+    }
+}
+
+impl<T, A> OffSlabCache<T, A>
+where
+    A: FrameAllocator,
+{
+    /// Returns the name of this cache.
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Sets the name of this cache, which is used when reporting errors.
+    #[inline]
+    pub fn set_name(&mut self, name: &'static str) {
+        self.name = name;
+    }
+
+    /// Returns `true` if this cache keeps freed objects in their constructed
+    /// state.
+    #[inline]
+    pub fn caches_objects(&self) -> bool {
+        self.ctor.is_some()
+    }
+
+    /// Borrow the frame allocator that provides pages to this cache.
+    #[inline]
+    pub fn frame_allocator(&self) -> &A {
+        &self.frames
+    }
+
+    /// Returns the number of empty pages held by this cache.
+    #[inline]
+    pub fn empty_pages(&self) -> usize {
+        self.empty.len()
+    }
+
+    /// Returns the number of partially-full pages held by this cache.
+    #[inline]
+    pub fn partial_pages(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Returns the number of full pages held by this cache.
+    #[inline]
+    pub fn full_pages(&self) -> usize {
+        self.full.len()
+    }
+
+    /// Returns the number of objects that fit in a single page.
+    pub fn objects_per_page(&self) -> usize {
+        cmp::min(A::FRAME_SIZE / Self::slot_size(), MAX_OBJECTS_PER_PAGE)
+    }
+
+    /// Returns a snapshot of this cache's statistics.
+    ///
+    /// Since pages in an `OffSlabCache` are not coloured, every page has the
+    /// same colour.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            name: self.name,
+            object_size: Self::slot_size(),
+            objects_per_page: self.objects_per_page(),
+            active_objects: self.counters.live(),
+            empty_pages: self.empty.len(),
+            partial_pages: self.partial.len(),
+            full_pages: self.full.len(),
+            allocs: self.counters.allocs,
+            frees: self.counters.frees,
+            failed_allocs: self.counters.failed,
+            high_water: self.counters.high_water,
+            colours: 1,
+            next_colour: 0,
+            colour_align: mem::align_of::<T>(),
+        }
+    }
+
+    /// Allocate a slot in this cache and move `value` into it.
+    ///
+    /// If this cache keeps objects in their constructed state, the cached
+    /// object in the allocated slot is destroyed and replaced with `value`.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object.
+    /// - `Err(AllocErr)` if there were no free slots and a new page (or its
+    ///   descriptor) could not be allocated.
+    pub fn alloc(&mut self, value: T) -> Result<SlabRef<T>, AllocErr> {
+        let ptr = self.alloc_slot()?;
+        unsafe {
+            if self.caches_objects() {
+                self.destroy(ptr);
+            }
+            ptr::write(ptr.as_ptr(), value);
+        }
+        Ok(SlabRef { ptr })
+    }
+
+    /// Allocate a constructed object from this cache.
+    ///
+    /// # Returns
+    /// - `Ok(SlabRef<T>)` referencing the allocated object.
+    /// - `Err(AllocErr)` if there were no free slots and a new page (or its
+    ///   descriptor) could not be allocated.
+    ///
+    /// # Panics
+    /// If this cache was not created with a constructor.
+    pub fn alloc_constructed(&mut self) -> Result<SlabRef<T>, AllocErr> {
+        assert!(
+            self.caches_objects(),
+            "alloc_constructed requires a cache with a constructor"
+        );
+        self.alloc_slot().map(|ptr| SlabRef { ptr })
+    }
+
+    /// Drop the object referenced by `slab_ref` and return its slot to this
+    /// cache.
+    ///
+    /// If this cache keeps objects in their constructed state, the object is
+    /// _not_ dropped.
+    ///
+    /// # Panics
+    /// If `slab_ref` was not allocated from this cache.
+    pub fn free(&mut self, slab_ref: SlabRef<T>) {
+        let ptr = slab_ref.ptr;
+        let addr = ptr.as_ptr() as usize;
+        let base = addr & !(A::FRAME_SIZE - 1);
+        let offset = (addr - base).wrapping_sub(Self::object_offset());
+        let index = offset / Self::slot_size();
+        let mut descriptor = self
+            .lookup(base)
+            .filter(|d| {
+                let d = unsafe { d.as_ref() };
+                index < d.capacity && offset % Self::slot_size() == 0
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "object at {:p} was not allocated from cache `{}`",
+                    ptr, self.name
+                )
+            });
+
+        unsafe {
+            let was_full = descriptor.as_ref().is_full();
+            if !self.caches_objects() {
+                self.destroy(ptr);
+            }
+
+            #[cfg(feature = "debug")]
+            {
+                debug::check(self.name, ptr, Self::layout(), false);
+                if !self.caches_objects() {
+                    debug::poison(ptr);
+                }
+            }
+
+            descriptor.as_mut().dealloc(index);
+            self.counters.free();
+
+            if was_full {
+                let descriptor = self.full.remove_node(descriptor);
+                if descriptor.is_empty() {
+                    self.push_empty(descriptor);
+                } else {
+                    self.partial.push_front_node(descriptor);
+                }
+            } else if descriptor.as_ref().is_empty() {
+                let descriptor = self.partial.remove_node(descriptor);
+                self.push_empty(descriptor);
+            }
+        }
+    }
+
+    /// Return up to `pages` empty pages to the frame allocator.
+    ///
+    /// # Returns
+    /// The number of frames that were returned to the frame allocator,
+    /// including any frames freed by the descriptor cache.
+    pub fn shrink(&mut self, pages: usize) -> usize {
+        let mut freed = 0;
+        while freed < pages {
+            let descriptor = match self.empty.pop_front_node() {
+                Some(descriptor) => descriptor,
+                None => break,
+            };
+            if let Err(descriptor) = unsafe { self.release(descriptor) } {
+                self.empty.push_front_node(descriptor);
+                break;
+            }
+            freed += 1;
+        }
+        freed + self.descriptors.reclaim()
+    }
+
+    /// Return every empty page in this cache to the frame allocator.
+    ///
+    /// # Returns
+    /// The number of frames that were returned to the frame allocator,
+    /// including any frames freed by the descriptor cache.
+    #[inline]
+    pub fn reclaim(&mut self) -> usize {
+        self.shrink(usize::max_value())
+    }
+
+    /// Allocate a slot from a partial page, moving the page onto the full
+    /// list if the slot was its last free one.
+    fn alloc_slot(&mut self) -> Result<NonNull<T>, AllocErr> {
+        if self.partial.is_empty() {
+            let descriptor = match self.empty.pop_front_node() {
+                Some(descriptor) => descriptor,
+                None => match self.grow() {
+                    Ok(descriptor) => descriptor,
+                    Err(e) => {
+                        self.counters.fail();
+                        return Err(e);
+                    },
+                },
+            };
+            self.partial.push_front_node(descriptor);
+        }
+
+        let (ptr, is_full) = {
+            let descriptor = self
+                .partial
+                .head_mut()
+                .expect("partial list cannot be empty");
+            let index = descriptor
+                .alloc()
+                .expect("pages on the partial list must have free slots");
+            (Self::slot(descriptor, index), descriptor.is_full())
+        };
+
+        #[cfg(feature = "debug")]
+        unsafe {
+            debug::check(
+                self.name,
+                ptr,
+                Self::layout(),
+                !self.caches_objects(),
+            );
+        }
+
+        if is_full {
+            let descriptor = self
+                .partial
+                .pop_front_node()
+                .expect("partial list cannot be empty after allocating");
+            self.full.push_front_node(descriptor);
+        }
+
+        self.counters.alloc(1);
+        Ok(ptr)
+    }
+
+    /// Allocate a new page and its descriptor.
+    fn grow(&mut self) -> Result<UnsafeRef<Descriptor>, AllocErr> {
+        let capacity = self.objects_per_page();
+        if capacity == 0 {
+            // Don't waste a frame if we can't fit any objects in it.
+            return Err(AllocErr);
+        }
+
+        let frame = unsafe { self.frames.alloc()? };
+        let base: usize = frame.base().into();
+        let descriptor = Descriptor {
+            base: unsafe { NonNull::new_unchecked(base as *mut u8) },
+            capacity,
+            free: Descriptor::all_free(capacity),
+            links: Links::new(),
+            next_in_bucket: None,
+        };
+        let descriptor = match self.descriptors.alloc(descriptor) {
+            Ok(slab_ref) => UnsafeRef::from(slab_ref.ptr),
+            Err(err) => {
+                // Without a descriptor, we can't use the frame; give it back.
+                let _ = unsafe { self.frames.dealloc(frame) };
+                return Err(err);
+            },
+        };
+
+        self.insert(descriptor.clone());
+        #[cfg(feature = "debug")]
+        for i in 0..capacity {
+            unsafe {
+                debug::init(
+                    Self::slot(&descriptor, i),
+                    Self::layout(),
+                    !self.caches_objects(),
+                );
+            }
+        }
+        if let Some(ctor) = self.ctor {
+            for i in 0..capacity {
+                unsafe {
+                    ptr::write(Self::slot(&descriptor, i).as_ptr(), ctor())
+                };
+            }
+        }
+        Ok(descriptor)
+    }
+
+    /// Move an empty page onto the empty list, or return its frame to the
+    /// frame allocator if the cache already holds `max_empty` empty pages.
+    unsafe fn push_empty(&mut self, descriptor: UnsafeRef<Descriptor>) {
+        if self.empty.len() < self.max_empty {
+            self.empty.push_front_node(descriptor);
+            return;
+        }
+        if let Err(descriptor) = self.release(descriptor) {
+            // If the frame allocator won't take the frame back, hang on to
+            // the page rather than leaking it.
+            self.empty.push_front_node(descriptor);
+        }
+    }
+
+    /// Destroy the objects in an empty page, return its frame to the frame
+    /// allocator, and free its descriptor.
+    unsafe fn release(
+        &mut self,
+        descriptor: UnsafeRef<Descriptor>,
+    ) -> Result<(), UnsafeRef<Descriptor>> {
+        debug_assert!(descriptor.is_empty());
+        let base = descriptor.base.as_ptr() as usize;
+        if self.caches_objects() {
+            for i in 0..descriptor.capacity {
+                self.destroy(Self::slot(&descriptor, i));
+            }
+        }
+
+        if self
+            .frames
+            .dealloc(A::Frame::containing(base.into()))
+            .is_err()
+        {
+            if let Some(ctor) = self.ctor {
+                for i in 0..descriptor.capacity {
+                    ptr::write(Self::slot(&descriptor, i).as_ptr(), ctor());
+                }
+            }
+            return Err(descriptor);
+        }
+
+        self.remove(base);
+        let ptr = NonNull::from(&*descriptor);
+        self.descriptors.free(SlabRef { ptr });
+        Ok(())
+    }
+
+    /// Run the destructor (if any) on the object at `ptr`, and drop it.
+    #[inline]
+    unsafe fn destroy(&self, ptr: NonNull<T>) {
+        if let Some(dtor) = self.dtor {
+            dtor(&mut *ptr.as_ptr());
+        }
+        ptr::drop_in_place(ptr.as_ptr());
+    }
+
+    /// Returns the size of each slot, in bytes.
+    ///
+    /// Since free slots are tracked by their page's descriptor, each slot
+    /// holds nothing but its object.
+    #[cfg(not(feature = "debug"))]
+    #[inline]
+    fn slot_size() -> usize {
+        cmp::max(mem::size_of::<T>(), 1)
+    }
+
+    /// Returns the size of each slot, in bytes.
+    ///
+    /// With the `debug` feature enabled, each slot has room for red zones on
+    /// either side of its object.
+    #[cfg(feature = "debug")]
+    #[inline]
+    fn slot_size() -> usize {
+        Self::layout().size()
+    }
+
+    /// Returns the offset of the object from the start of its slot, in
+    /// bytes.
+    #[cfg(not(feature = "debug"))]
+    #[inline]
+    fn object_offset() -> usize {
+        0
+    }
+
+    /// Returns the offset of the object from the start of its slot, in
+    /// bytes, leaving room for the red zone before it.
+    #[cfg(feature = "debug")]
+    #[inline]
+    fn object_offset() -> usize {
+        Self::layout().object_offset
+    }
+
+    /// Returns the red-zoned layout of this cache's slots.
+    #[cfg(feature = "debug")]
+    #[inline]
+    fn layout() -> SlotLayout {
+        SlotLayout::red_zoned::<T>()
+    }
+
+    /// Returns a pointer to the object in slot `index` of `descriptor`'s
+    /// page.
+    #[inline]
+    fn slot(descriptor: &Descriptor, index: usize) -> NonNull<T> {
+        let offset = index * Self::slot_size() + Self::object_offset();
+        unsafe {
+            NonNull::new_unchecked(
+                descriptor.base.as_ptr().add(offset) as *mut T
+            )
+        }
+    }
+
+    /// Returns the hash bucket for the page whose frame starts at `base`.
+    #[inline]
+    fn bucket(base: usize) -> usize {
+        (base / A::FRAME_SIZE) % BUCKETS
+    }
+
+    /// Returns the descriptor for the page whose frame starts at `base`.
+    fn lookup(&self, base: usize) -> Option<NonNull<Descriptor>> {
+        let mut next = self.buckets[Self::bucket(base)];
+        while let Some(descriptor) = next {
+            let descriptor_ref = unsafe { descriptor.as_ref() };
+            if descriptor_ref.base.as_ptr() as usize == base {
+                return Some(descriptor);
+            }
+            next = descriptor_ref.next_in_bucket;
+        }
+        None
+    }
+
+    /// Add a descriptor to the hash table.
+    fn insert(&mut self, mut descriptor: UnsafeRef<Descriptor>) {
+        let bucket = Self::bucket(descriptor.base.as_ptr() as usize);
+        descriptor.next_in_bucket = self.buckets[bucket];
+        self.buckets[bucket] = Some(NonNull::from(&*descriptor));
+    }
+
+    /// Remove the descriptor for the page whose frame starts at `base` from
+    /// the hash table.
+    unsafe fn remove(&mut self, base: usize) {
+        let mut link = &mut self.buckets[Self::bucket(base)];
+        while let Some(mut descriptor) = *link {
+            if descriptor.as_ref().base.as_ptr() as usize == base {
+                *link = descriptor.as_ref().next_in_bucket;
+                return;
+            }
+            link = &mut descriptor.as_mut().next_in_bucket;
+        }
+        unreachable!("descriptor for frame {:#x} not in hash table", base);
+    }
+}
+
+/// Dropping an `OffSlabCache` returns every page it holds to the frame
+/// allocator, and frees every page descriptor.
+///
+/// As with [`Cache`], if the cache keeps objects in their constructed state,
+/// every object is destroyed before its frame is returned. Otherwise, objects
+/// which are still allocated from the cache are leaked rather than dropped.
+///
+/// [`Cache`]: ../cache/struct.Cache.html
+impl<T, A> Drop for OffSlabCache<T, A>
+where
+    A: FrameAllocator,
+{
+    fn drop(&mut self) {
+        self.reclaim();
+        while let Some(descriptor) = self
+            .empty
+            .pop_front_node()
+            .or_else(|| self.partial.pop_front_node())
+            .or_else(|| self.full.pop_front_node())
+        {
+            unsafe {
+                let base = descriptor.base.as_ptr() as usize;
+                if self.caches_objects() {
+                    for i in 0..descriptor.capacity {
+                        self.destroy(Self::slot(&descriptor, i));
+                    }
+                }
+                let _ = self.frames.dealloc(A::Frame::containing(base.into()));
+                self.remove(base);
+                let ptr = NonNull::from(&*descriptor);
+                self.descriptors.free(SlabRef { ptr });
+            }
+        }
+        // The descriptor cache returns its own frames when it is dropped.
+    }
+}
+
+impl<T, A> fmt::Debug for OffSlabCache<T, A>
+where
+    A: FrameAllocator + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OffSlabCache")
+            .field("name", &self.name)
+            .field("empty", &self.empty.len())
+            .field("partial", &self.partial.len())
+            .field("full", &self.full.len())
+            .field("max_empty", &self.max_empty)
+            .field("caches_objects", &self.ctor.is_some())
+            .field("descriptors", &self.descriptors)
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+// ===== impl Descriptor =====
+
+impl Descriptor {
+    /// Returns a bitmap with the first `capacity` slots free.
+    #[inline]
+    fn all_free(capacity: usize) -> usize {
+        if capacity >= MAX_OBJECTS_PER_PAGE {
+            !0
+        } else {
+            (1 << capacity) - 1
+        }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.free == Self::all_free(self.capacity)
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        self.free == 0
+    }
+
+    /// Allocate the lowest-numbered free slot in this page.
+    fn alloc(&mut self) -> Option<usize> {
+        if self.is_full() {
+            return None;
+        }
+        let index = self.free.trailing_zeros() as usize;
+        self.free &= !(1 << index);
+        Some(index)
+    }
+
+    /// Mark slot `index` as free.
+    fn dealloc(&mut self, index: usize) {
+        debug_assert!(index < self.capacity);
+        debug_assert!(
+            self.free & (1 << index) == 0,
+            "slot {} in page {:p} freed twice",
+            index,
+            self.base
+        );
+        self.free |= 1 << index;
+    }
+}
+
+impl list::Linked for Descriptor {
+    #[inline]
+    fn links(&self) -> &Links<Self> {
+        &self.links
+    }
+
+    #[inline]
+    fn links_mut(&mut self) -> &mut Links<Self> {
+        &mut self.links
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{SharedFrames, FRAME_SIZE};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::vec::Vec;
+
+    type Buffer = [u8; 2048];
+    type TestCache<T> = OffSlabCache<T, SharedFrames>;
+
+    // With the `debug` feature, red zones keep objects from packing exactly.
+    #[test]
+    #[cfg(not(feature = "debug"))]
+    fn large_objects_pack_into_frames() {
+        let frames = SharedFrames::new(8);
+        let mut cache = unsafe { TestCache::<Buffer>::new(frames.clone()) };
+        assert_eq!(cache.objects_per_page(), FRAME_SIZE / 2048);
+
+        let a = cache.alloc([1; 2048]).unwrap();
+        let b = cache.alloc([2; 2048]).unwrap();
+        assert_eq!(cache.full_pages(), 1);
+        assert_eq!(a.as_ptr() as usize % FRAME_SIZE, 0);
+        assert_eq!(b.as_ptr() as usize - a.as_ptr() as usize, 2048);
+
+        // One frame for the objects, and one for the descriptor cache.
+        assert_eq!(frames.allocated(), 2);
+        assert_eq!(a[0], 1);
+        assert_eq!(b[2047], 2);
+        cache.free(a);
+        cache.free(b);
+    }
+
+    // Counts object sizes, which red zones would pad.
+    #[test]
+    #[cfg(not(feature = "debug"))]
+    fn stats_count_allocations() {
+        let mut cache =
+            unsafe { TestCache::<Buffer>::new(SharedFrames::new(2)) };
+        let a = cache.alloc([1; 2048]).unwrap();
+        let b = cache.alloc([2; 2048]).unwrap();
+        assert!(cache.alloc([3; 2048]).is_err());
+        cache.free(a);
+
+        let stats = cache.stats();
+        assert_eq!(stats.object_size, 2048);
+        assert_eq!(stats.objects_per_page, FRAME_SIZE / 2048);
+        assert_eq!(stats.active_objects, 1);
+        assert_eq!(stats.allocs, 2);
+        assert_eq!(stats.frees, 1);
+        assert_eq!(stats.failed_allocs, 1);
+        assert_eq!(stats.high_water, 2);
+        assert_eq!(stats.partial_pages, 1);
+        cache.free(b);
+    }
+
+    #[test]
+    fn objects_freed_across_many_pages() {
+        let frames = SharedFrames::new(BUCKETS + 16);
+        let mut cache =
+            unsafe { TestCache::<Buffer>::with_max_empty(frames.clone(), 0) };
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..(BUCKETS + 8) * per_page)
+            .map(|i| cache.alloc([i as u8; 2048]).unwrap())
+            .collect();
+        assert_eq!(cache.full_pages(), BUCKETS + 8);
+
+        for (i, r) in refs.into_iter().enumerate().rev() {
+            assert_eq!(r[0], i as u8);
+            cache.free(r);
+        }
+        assert_eq!(cache.full_pages(), 0);
+        assert_eq!(cache.partial_pages(), 0);
+        assert_eq!(cache.empty_pages(), 0);
+        cache.reclaim();
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn pages_migrate_between_lists() {
+        let frames = SharedFrames::new(4);
+        let mut cache = unsafe { TestCache::<[u8; 1024]>::new(frames.clone()) };
+        let a = cache.alloc([0; 1024]).unwrap();
+        assert_eq!(cache.partial_pages(), 1);
+        let rest: Vec<_> = (1..cache.objects_per_page())
+            .map(|_| cache.alloc([0; 1024]).unwrap())
+            .collect();
+        assert_eq!(cache.full_pages(), 1);
+
+        cache.free(a);
+        assert_eq!(cache.partial_pages(), 1);
+        for r in rest {
+            cache.free(r);
+        }
+        assert_eq!(cache.empty_pages(), 1);
+        assert_eq!(cache.reclaim(), 2);
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn descriptor_alloc_failure_returns_frame() {
+        // There's room for the object frame, but not the descriptor cache.
+        let frames = SharedFrames::new(1);
+        let mut cache = unsafe { TestCache::<Buffer>::new(frames.clone()) };
+        assert!(cache.alloc([0; 2048]).is_err());
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn small_objects_limited_by_bitmap() {
+        let cache = unsafe { TestCache::<u64>::new(SharedFrames::new(1)) };
+        assert_eq!(cache.objects_per_page(), MAX_OBJECTS_PER_PAGE);
+    }
+
+    #[test]
+    fn constructed_objects_keep_state() {
+        #[derive(Debug)]
+        struct Buf {
+            data: [u8; 1024],
+            uses: usize,
+        }
+
+        fn new_buf() -> Buf {
+            Buf {
+                data: [0; 1024],
+                uses: 0,
+            }
+        }
+
+        let frames = SharedFrames::new(4);
+        let mut cache =
+            unsafe { TestCache::with_ctor(frames.clone(), new_buf, None) };
+        // Off-slab caches don't need any room in the slot for free-list
+        // nodes.
+        assert_eq!(
+            cache.objects_per_page(),
+            FRAME_SIZE / mem::size_of::<Buf>()
+        );
+
+        let mut a = cache.alloc_constructed().unwrap();
+        a.uses += 1;
+        a.data[0] = 0xff;
+        let addr = a.as_ptr();
+        cache.free(a);
+
+        let b = cache.alloc_constructed().unwrap();
+        assert_eq!(b.as_ptr(), addr);
+        assert_eq!(b.uses, 1);
+        assert_eq!(b.data[0], 0xff);
+        cache.free(b);
+    }
+
+    #[test]
+    #[should_panic(expected = "was not allocated from cache `buffers`")]
+    fn free_from_wrong_cache() {
+        let frames = SharedFrames::new(8);
+        let mut a = unsafe { TestCache::<Buffer>::new(frames.clone()) };
+        let mut b = unsafe { TestCache::<Buffer>::new(frames.clone()) };
+        b.set_name("buffers");
+        let r = a.alloc([0; 2048]).unwrap();
+        b.free(r);
+    }
+
+    #[test]
+    fn drop_returns_every_frame() {
+        let frames = SharedFrames::new(8);
+        let mut cache =
+            unsafe { TestCache::<Buffer>::with_max_empty(frames.clone(), 4) };
+        let per_page = cache.objects_per_page();
+        let refs: Vec<_> = (0..per_page * 3)
+            .map(|_| cache.alloc([0; 2048]).unwrap())
+            .collect();
+        // Leave some pages with allocated objects, and one empty.
+        for r in refs.into_iter().skip(per_page + 1) {
+            cache.free(r);
+        }
+        assert!(frames.allocated() > 0);
+        drop(cache);
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn objects_destroyed_when_cache_dropped() {
+        static DTORS: AtomicUsize = AtomicUsize::new(0);
+
+        fn new_buf() -> Buffer {
+            [0; 2048]
+        }
+
+        fn dtor(_: &mut Buffer) {
+            DTORS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let frames = SharedFrames::new(8);
+        let mut cache = unsafe {
+            TestCache::with_ctor(frames.clone(), new_buf, Some(dtor))
+        };
+        let per_page = cache.objects_per_page();
+        let a = cache.alloc_constructed().unwrap();
+        cache.free(a);
+        let _b = cache.alloc_constructed().unwrap();
+        drop(cache);
+        // Every constructed object is destroyed exactly once, whether or not
+        // it was still allocated.
+        assert_eq!(DTORS.load(Ordering::SeqCst), per_page);
+        assert_eq!(frames.allocated(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! Utilities for testing slab caches on the host.
+use alarm_base::FrameAllocator;
+use core::alloc::AllocErr;
+use hal9000::mem::{PAddr, Page};
+use std::{
+    alloc::{alloc, dealloc, Layout},
+    cell::RefCell,
+    rc::Rc,
+    vec::Vec,
+};
+
+pub const FRAME_SIZE: usize = 4096;
+
+/// A 4 KiB frame in host memory.
+#[derive(Debug, Eq, PartialEq)]
+pub struct TestFrame(usize);
+
+/// A frame allocator which allocates frames from the host heap.
+#[derive(Debug)]
+pub struct TestFrames {
+    /// The maximum number of frames that may be allocated at once.
+    limit: usize,
+
+    /// Base addresses of the currently allocated frames.
+    allocated: Vec<usize>,
+}
+
+/// A handle to a `TestFrames` shared between several users.
+#[derive(Clone, Debug)]
+pub struct SharedFrames(Rc<RefCell<TestFrames>>);
+
+impl Page for TestFrame {
+    type Address = PAddr;
+    const SHIFT: usize = 12;
+    const SIZE: usize = FRAME_SIZE;
+
+    fn containing(addr: PAddr) -> Self {
+        let addr: usize = addr.into();
+        TestFrame(addr & !(FRAME_SIZE - 1))
+    }
+
+    fn base(&self) -> PAddr {
+        PAddr::from(self.0)
+    }
+
+    fn end_address(&self) -> PAddr {
+        PAddr::from(self.0 + FRAME_SIZE)
+    }
+
+    fn number(&self) -> usize {
+        self.0 >> Self::SHIFT
+    }
+}
+
+impl TestFrames {
+    /// Returns a new `TestFrames` which will allocate at most `limit` frames.
+    pub fn new(limit: usize) -> Self {
+        TestFrames {
+            limit,
+            allocated: Vec::new(),
+        }
+    }
+
+    /// Returns the number of frames currently allocated.
+    pub fn allocated(&self) -> usize {
+        self.allocated.len()
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(FRAME_SIZE, FRAME_SIZE).unwrap()
+    }
+}
+
+unsafe impl FrameAllocator for TestFrames {
+    type Frame = TestFrame;
+
+    unsafe fn alloc(&mut self) -> Result<TestFrame, AllocErr> {
+        if self.allocated.len() >= self.limit {
+            return Err(AllocErr);
+        }
+        let ptr = alloc(Self::layout());
+        if ptr.is_null() {
+            return Err(AllocErr);
+        }
+        self.allocated.push(ptr as usize);
+        Ok(TestFrame(ptr as usize))
+    }
+
+    unsafe fn dealloc(&mut self, frame: TestFrame) -> Result<(), AllocErr> {
+        let idx = self
+            .allocated
+            .iter()
+            .position(|&base| base == frame.0)
+            .ok_or(AllocErr)?;
+        self.allocated.swap_remove(idx);
+        dealloc(frame.0 as *mut u8, Self::layout());
+        Ok(())
+    }
+}
+
+impl Drop for TestFrames {
+    fn drop(&mut self) {
+        for &base in &self.allocated {
+            unsafe { dealloc(base as *mut u8, Self::layout()) }
+        }
+    }
+}
+
+impl SharedFrames {
+    /// Returns a new `SharedFrames` which will allocate at most `limit`
+    /// frames across all of its clones.
+    pub fn new(limit: usize) -> Self {
+        SharedFrames(Rc::new(RefCell::new(TestFrames::new(limit))))
+    }
+
+    /// Returns the number of frames currently allocated.
+    pub fn allocated(&self) -> usize {
+        self.0.borrow().allocated()
+    }
+}
+
+unsafe impl FrameAllocator for SharedFrames {
+    type Frame = TestFrame;
+
+    unsafe fn alloc(&mut self) -> Result<TestFrame, AllocErr> {
+        self.0.borrow_mut().alloc()
+    }
+
+    unsafe fn dealloc(&mut self, frame: TestFrame) -> Result<(), AllocErr> {
+        self.0.borrow_mut().dealloc(frame)
+    }
+}